@@ -0,0 +1,23 @@
+use std::process::Command;
+
+// Embeds the git commit of the build as ALFIS_GIT_COMMIT, if git and the repo are available
+fn main() {
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .args(args)
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| String::from_utf8(output.stdout).ok())
+            .map(|text| text.trim().to_string())
+    };
+
+    if let Some(commit) = git(&["rev-parse", "--short=12", "HEAD"]) {
+        println!("cargo:rustc-env=ALFIS_GIT_COMMIT={}", commit);
+    }
+    if let Some(git_dir) = git(&["rev-parse", "--git-dir"]) {
+        println!("cargo:rerun-if-changed={}/HEAD", git_dir);
+        println!("cargo:rerun-if-changed={}/refs", git_dir);
+    }
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -1,55 +1,1015 @@
-use jni::objects::{JClass, JString};
-use jni::sys::{jboolean, jstring};
-use jni::JNIEnv;
-use log::{error, info, warn};
-use std::sync::{Arc, Mutex};
+use jni::objects::{GlobalRef, JClass, JObject, JString, JValue};
+use jni::sys::{jboolean, jint, jlong, jstring};
+use jni::{JNIEnv, JavaVM};
+use log::{debug, error, info, warn};
+use std::sync::{Arc, Condvar, Mutex, Once, OnceLock, RwLock};
 use std::thread;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use std::collections::VecDeque;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use lazy_static::lazy_static;
 
 // Import Alfis core components
 use alfis::settings::Settings;
-use alfis::{Context, Keystore, Chain};
+use alfis::{Block, Bytes, Context, Keystore, Chain, Miner, Transaction};
 use alfis::dns::context::ServerContext;
+use alfis::dns::protocol::{DnsPacket, DnsQuestion, DnsRecord, QueryType, ResultCode, TransientTtl};
 use alfis::p2p::network::Network;
 use alfis::eventbus::register;
 use alfis::event::Event;
+use alfis::commons::{is_yggdrasil, CLASS_DOMAIN, DOMAIN_DIFFICULTY, DOMAIN_LIFETIME};
+
+// Version of the Alfis core this library is built with, reported to peers
+const ALFIS_CORE_VERSION: &str = "0.8.6";
 
 // Global state for the DNS server and network
-static mut ALFIS_CONTEXT: Option<Arc<Mutex<Context>>> = None;
-static mut SERVER_CONTEXT: Option<Arc<ServerContext>> = None;
-static mut NETWORK_HANDLE: Option<thread::JoinHandle<()>> = None;
-static mut DNS_UDP_HANDLE: Option<thread::JoinHandle<()>> = None;
-static mut DNS_TCP_HANDLE: Option<thread::JoinHandle<()>> = None;
-static mut NETWORK_PEER_COUNT: usize = 0;
-static mut DNS_RUNNING: bool = false;
-static mut DNS_START_TIME: u64 = 0;
-static mut LOG_BUFFER: Option<Arc<Mutex<VecDeque<String>>>> = None;
+// Contexts and thread handles of the running node, see AlfisState
+static STATE: Mutex<AlfisState> = Mutex::new(AlfisState::new());
+static NETWORK_PEER_COUNT: AtomicUsize = AtomicUsize::new(0);
+static DNS_RUNNING: AtomicBool = AtomicBool::new(false);
+static DNS_START_TIME: AtomicU64 = AtomicU64::new(0);
+// Where the UDP and TCP listeners are bound, the configured port or a fallback if it was taken
+static ACTIVE_DNS_ADDR: Mutex<Option<SocketAddr>> = Mutex::new(None);
+const DNS_FALLBACK_PORTS: [u16; 3] = [5354, 5355, 5356];
+// Why the last startDnsServer failed, empty if it succeeded
+static LAST_START_ERROR: Mutex<String> = Mutex::new(String::new());
+static LOG_BUFFER: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
 static DNS_SHUTDOWN_FLAG: AtomicBool = AtomicBool::new(false);
-static mut SERVICE_PID: Option<u32> = None;
+// Set on stop, so a network thread that hasn't started its loop yet doesn't start it
+static NETWORK_SHUTDOWN: AtomicBool = AtomicBool::new(false);
+const NETWORK_JOIN_TIMEOUT: Duration = Duration::from_secs(10);
+// P2P network stopped by pauseNetwork while DNS keeps running, and the lock for pausing and resuming
+static NETWORK_PAUSED: AtomicBool = AtomicBool::new(false);
+static NETWORK_CONTROL_LOCK: Mutex<()> = Mutex::new(());
+const RECONNECT_REPORT_DELAY: Duration = Duration::from_secs(30);
+// Reconnect threads that haven't reported yet, re-bootstrap waits for them
+static RECONNECTS_IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+// Re-dial the bootstrap peers when no peer is connected for this long (0 is off)
+static REBOOTSTRAP_INTERVAL_SECS: AtomicU64 = AtomicU64::new(0);
+static REBOOTSTRAP_WORKER: AtomicBool = AtomicBool::new(false);
+const REBOOTSTRAP_POLL_INTERVAL: Duration = Duration::from_secs(10);
+static CONFIG_PATH: Mutex<Option<String>> = Mutex::new(None);
+// Held while a new config is written and applied, so two swaps can't interleave
+static CONFIG_APPLY_LOCK: Mutex<()> = Mutex::new(());
+static WORK_DIR: Mutex<Option<String>> = Mutex::new(None);
+// Blocks waiting to be mined (domain registrations, renewals, transfers)
+static PENDING_BLOCKS: Mutex<Vec<PendingBlock>> = Mutex::new(Vec::new());
+// Miner threads asked for by startMining (0 is off), hash attempts and blocks mined since start
+static MINING_THREADS: AtomicUsize = AtomicUsize::new(0);
+static MINING_HASHES: AtomicU64 = AtomicU64::new(0);
+static MINED_BLOCKS: AtomicU64 = AtomicU64::new(0);
+static MINER_REPORTS: Mutex<MinerReports> = Mutex::new(MinerReports::new());
+// Phones throttle or heat up with every core busy, the miner gets half of them at most
+const MAX_MINING_THREADS: usize = 4;
+// File of the chain database, None if it fell back to memory
+static CHAIN_DB_PATH: Mutex<Option<String>> = Mutex::new(None);
+// Whether the running chain is in memory, and if startup may fall back to it when the file fails
+static IN_MEMORY_DB: AtomicBool = AtomicBool::new(false);
+static ALLOW_IN_MEMORY_FALLBACK: AtomicBool = AtomicBool::new(true);
+// Whether the chain database file is in WAL mode, so DNS reads don't wait for sync writes
+static CHAIN_DB_WAL: AtomicBool = AtomicBool::new(false);
+const CHAIN_DB_BUSY_TIMEOUT_MS: usize = 5000;
+// Integrity checks stop after this many problems
+const MAX_INTEGRITY_PROBLEMS: usize = 100;
+// Total size of the messages in LOG_BUFFER and the limit for it
+static LOG_BUFFER_BYTES: AtomicUsize = AtomicUsize::new(0);
+static LOG_BUFFER_MAX_BYTES: AtomicUsize = AtomicUsize::new(DEFAULT_LOG_BUFFER_MAX_BYTES);
+const DEFAULT_LOG_BUFFER_MAX_BYTES: usize = 256 * 1024;
+const MIN_LOG_BUFFER_MAX_BYTES: usize = 4 * 1024;
+// Number of messages kept in LOG_BUFFER
+static LOG_BUFFER_MAX_LINES: AtomicUsize = AtomicUsize::new(DEFAULT_LOG_BUFFER_MAX_LINES);
+const DEFAULT_LOG_BUFFER_MAX_LINES: usize = 100;
+const MIN_LOG_BUFFER_MAX_LINES: usize = 50;
+const MAX_LOG_BUFFER_MAX_LINES: usize = 5000;
+
+// The JVM, saved by initLogging so native threads can call into Java
+static JAVA_VM: OnceLock<JavaVM> = OnceLock::new();
+// Listener getting sync and network status events, see registerStatusCallback
+static STATUS_CALLBACK: Mutex<Option<GlobalRef>> = Mutex::new(None);
+// Events for the app (JSON objects), drained by pollEvents
+static APP_EVENTS: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+const MAX_APP_EVENTS: usize = 100;
+// Index of the last block reported by BlockchainChanged, used to detect reorgs
+static LAST_BLOCK_INDEX: AtomicU64 = AtomicU64::new(0);
+static REORG_COUNT: AtomicU64 = AtomicU64::new(0);
+// Hashes of the last blocks seen by index, compared to the chain to find where a reorg forked
+static RECENT_BLOCK_HASHES: Mutex<VecDeque<(u64, Bytes)>> = Mutex::new(VecDeque::new());
+const MAX_RECENT_BLOCK_HASHES: usize = 64;
+// A Block-Hashes thread is already waiting for the chain
+static RECORDING_BLOCK_HASHES: AtomicBool = AtomicBool::new(false);
+// Last panics recorded by the panic hook
+static RECENT_PANICS: Mutex<VecDeque<serde_json::Value>> = Mutex::new(VecDeque::new());
+const MAX_RECENT_PANICS: usize = 10;
+// Network height and blocks we had from the last Syncing event
+static NETWORK_HEIGHT: AtomicU64 = AtomicU64::new(0);
+static SYNC_HAVE: AtomicU64 = AtomicU64::new(0);
+// Sync state from Syncing/SyncFinished events, one of the SYNC_STATE_* values
+static SYNC_STATE: AtomicU64 = AtomicU64::new(SYNC_STATE_IDLE);
+const SYNC_STATE_IDLE: u64 = 0;
+const SYNC_STATE_SYNCING: u64 = 1;
+const SYNC_STATE_SYNCED: u64 = 2;
+// Unix time of the last block received or added to the chain, 0 if none this session
+static LAST_BLOCK_RECEIVED: AtomicU64 = AtomicU64::new(0);
+static SYNC_DATA: Mutex<SyncDataStats> = Mutex::new(SyncDataStats::new());
+// How many blocks are measured at most per call, to keep the context lock short
+const MAX_BLOCK_SAMPLES_PER_CALL: u64 = 100;
+static DNS_COUNTERS: DnsCounters = DnsCounters::new();
+// Largest DNS message accepted over TCP and Unix sockets
+static TCP_MAX_MESSAGE_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_TCP_MAX_MESSAGE_SIZE);
+const DEFAULT_TCP_MAX_MESSAGE_SIZE: usize = 4096;
+// Unix socket listener thread and the socket path it is bound to
+static DNS_UNIX_HANDLE: Mutex<Option<(String, thread::JoinHandle<()>)>> = Mutex::new(None);
+// DNS-over-HTTPS listener for local apps, started with the DNS server while enabled
+static DOH_ENABLED: AtomicBool = AtomicBool::new(false);
+static DNS_DOH_HANDLE: Mutex<Option<thread::JoinHandle<()>>> = Mutex::new(None);
+#[cfg(feature = "doh-server")]
+const DOH_LISTEN: &str = "127.0.0.1:8443";
+// Self-signed certificate and its key in the work directory, generated on first use
+#[cfg(feature = "doh-server")]
+const DOH_CERT_FILE: &str = "doh-cert.pem";
+#[cfg(feature = "doh-server")]
+const DOH_KEY_FILE: &str = "doh-key.pem";
+// Largest HTTP request head the DoH listener reads
+#[cfg(feature = "doh-server")]
+const DOH_MAX_HEAD_SIZE: usize = 8192;
+// Remove DNSSEC records from responses for clients that get confused by them
+static STRIP_DNSSEC: AtomicBool = AtomicBool::new(false);
+// Address family kept when a name has both, set by setAddressFamilyPreference
+static ADDRESS_FAMILY_PREFERENCE: AtomicU32 = AtomicU32::new(FAMILY_ANY);
+const FAMILY_ANY: u32 = 0;
+const FAMILY_IPV4: u32 = 1;
+const FAMILY_IPV6: u32 = 2;
+
+// DNSSEC record types: DS, RRSIG, NSEC, DNSKEY, NSEC3, NSEC3PARAM
+const DNSSEC_RECORD_TYPES: [u16; 6] = [43, 46, 47, 48, 50, 51];
+// DNSSEC OK flag in the TTL field of an OPT record (RFC 3225)
+const EDNS_FLAG_DO: u32 = 0x8000;
+
+// Network health: how many recent blocks are used for the average interval,
+// the most blocks read per call and the tip ages for "slow" and "stalled"
+const HEALTH_INTERVAL_SAMPLES: usize = 20;
+const HEALTH_MAX_BLOCKS_SCANNED: u64 = 500;
+const HEALTH_SLOW_TIP_AGE_SECS: u64 = 6 * 3600;
+const HEALTH_STALLED_TIP_AGE_SECS: u64 = 24 * 3600;
+
+const PEER_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_DOMAIN_HISTORY: usize = 1000;
+// Full chain verification reads blocks in batches so the context lock is released in between,
+// and can be cancelled between batches
+static VERIFY_CANCELLED: AtomicBool = AtomicBool::new(false);
+static VERIFY_RUNNING: AtomicBool = AtomicBool::new(false);
+const VERIFY_BATCH_BLOCKS: u64 = 100;
+const VERIFY_PROGRESS_EVERY: u64 = 1000;
+// Opt-in renewal of owned domains close to expiry, threshold in blocks (0 is off).
+// Expiry is time-based, blocks are turned into time with the measured network block interval.
+static AUTO_RENEW_THRESHOLD_BLOCKS: AtomicU64 = AtomicU64::new(0);
+static AUTO_RENEW_WORKER: AtomicBool = AtomicBool::new(false);
+const AUTO_RENEW_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+const DEFAULT_BLOCK_INTERVAL_SECS: u64 = 3600;
+
+// Resolve A and AAAA together, the second query of a dual-stack client then hits the cache
+static DUAL_STACK_PREFETCH: AtomicBool = AtomicBool::new(false);
+// Listen on the loopback address of the other family too, `dns.dual_stack` in the config
+static DNS_DUAL_STACK: AtomicBool = AtomicBool::new(false);
+// Answer only with the answer section when possible, to save bytes on slow links
+static MINIMAL_RESPONSES: AtomicBool = AtomicBool::new(false);
+// Local overrides of forwarded answers, for mirrors and split-horizon setups
+static ANSWER_REWRITES: RwLock<Vec<AnswerRewrite>> = RwLock::new(Vec::new());
+const DEFAULT_REWRITE_TTL: u32 = 300;
+
+// Expose query names (console, cache dumps), off by default for privacy
+static QUERY_LOGGING: AtomicBool = AtomicBool::new(false);
+// Name pattern whose resolution is traced stage by stage, None when tracing is off
+static QUERY_TRACE: RwLock<Option<String>> = RwLock::new(None);
+// Longest part of a raw upstream response written to the trace, as hex
+const TRACE_MAX_WIRE_BYTES: usize = 512;
+// Maximum number of cached responses and the longest time any of them is kept
+const MAX_CACHE_ENTRIES: usize = 1000;
+const MAX_CACHE_TTL: u32 = 86400;
+// Blockchain answers are also dropped on every new block, this bounds them while no blocks arrive
+const MAX_ALFIS_CACHE_TTL: u32 = 300;
+// Upper bound for forwarded NXDOMAIN/NODATA answers, lowered further by the SOA minimum (0 is off)
+static NEGATIVE_CACHE_TTL: AtomicU32 = AtomicU32::new(DEFAULT_NEGATIVE_CACHE_TTL);
+const DEFAULT_NEGATIVE_CACHE_TTL: u32 = 60;
+// RFC 2308 suggests not keeping negative answers longer than a few hours
+const MAX_NEGATIVE_CACHE_TTL: u32 = 10800;
+
+// Queries per second allowed from one source address, bursts up to the same number (0 is off)
+static RATE_LIMIT_QPS: AtomicU32 = AtomicU32::new(0);
+// Buckets of sources quiet for this long are dropped
+const RATE_BUCKET_IDLE: Duration = Duration::from_secs(60);
+
+// Zone transfers can't be served by this resolver, refuse them by default
+const DEFAULT_REFUSED_QUERY_TYPES: [u16; 2] = [251, 252];
+
+lazy_static! {
+    static ref RESPONSE_CACHE: Mutex<ResponseCache> = Mutex::new(ResponseCache::default());
+    // Query types answered with REFUSED before any resolution
+    static ref REFUSED_QUERY_TYPES: RwLock<HashSet<u16>> = RwLock::new(DEFAULT_REFUSED_QUERY_TYPES.into_iter().collect());
+    // Names and types resolved ahead by dual-stack prefetch and not asked for yet
+    static ref PREFETCHED: Mutex<HashSet<(String, QueryType)>> = Mutex::new(HashSet::new());
+    // Per forwarder counters, reset whenever the forwarder list changes
+    static ref FORWARDER_STATS: Mutex<HashMap<String, UpstreamStats>> = Mutex::new(HashMap::new());
+    // Names answered with NXDOMAIN, loaded from a hosts file by loadBlocklist
+    static ref BLOCKLIST: RwLock<HashSet<String>> = RwLock::new(HashSet::new());
+    // Names pinned to an address by setLocalOverride, answered before anything else
+    static ref LOCAL_OVERRIDES: RwLock<HashMap<String, IpAddr>> = RwLock::new(HashMap::new());
+    // Token buckets of the rate limiter by source address
+    static ref RATE_BUCKETS: Mutex<RateBuckets> = Mutex::new(RateBuckets::default());
+    // Counters of the previous sessions, loaded from STATS_FILE on start
+    static ref STATS_TOTALS: Mutex<serde_json::Map<String, serde_json::Value>> = Mutex::new(serde_json::Map::new());
+}
+
+const LOCAL_OVERRIDE_TTL: u32 = 60;
+
+// Lifetime totals of the DNS counters in the work directory, written on stop
+const STATS_FILE: &str = "dns_stats.json";
+
+/// Token buckets per source address, pruned of idle sources now and then
+#[derive(Default)]
+struct RateBuckets {
+    buckets: HashMap<IpAddr, (f64, Instant)>,
+    pruned: Option<Instant>,
+}
+
+/// Where a cached response came from
+#[derive(Clone, Copy, PartialEq)]
+enum CacheSource {
+    Blockchain,
+    Forwarded,
+}
+
+impl CacheSource {
+    fn name(&self) -> &'static str {
+        match self {
+            CacheSource::Blockchain => "blockchain",
+            CacheSource::Forwarded => "forwarded",
+        }
+    }
+}
+
+/// Cached response for one name and query type
+struct CacheEntry {
+    response: DnsPacket,
+    source: CacheSource,
+    expires: Instant,
+    // Resolved with the DO bit, so DNSSEC records were kept
+    dnssec_ok: bool,
+}
+
+impl CacheEntry {
+    fn is_negative(&self) -> bool {
+        is_negative_response(&self.response)
+    }
+
+    /// Rough heap and inline size, good enough to see what dominates memory use
+    fn approx_size(&self, name: &str) -> usize {
+        let records = self.response.answers.len() + self.response.authorities.len() + self.response.resources.len();
+        std::mem::size_of::<CacheEntry>() + name.len() + records * std::mem::size_of::<DnsRecord>()
+    }
+}
+
+/// Response cache with entry counts by category kept up to date on every change
+#[derive(Default)]
+struct ResponseCache {
+    entries: HashMap<(String, QueryType), CacheEntry>,
+    alfis: usize,
+    forwarded: usize,
+    negative: usize,
+    bytes: usize,
+}
+
+impl ResponseCache {
+    fn get(&self, key: &(String, QueryType)) -> Option<&CacheEntry> {
+        self.entries.get(key)
+    }
+
+    fn contains_key(&self, key: &(String, QueryType)) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&(String, QueryType), &CacheEntry)> {
+        self.entries.iter()
+    }
+
+    fn insert(&mut self, key: (String, QueryType), entry: CacheEntry) {
+        self.count(&key, &entry, true);
+        if let Some(old) = self.entries.insert(key.clone(), entry) {
+            self.count(&key, &old, false);
+        }
+    }
+
+    fn remove(&mut self, key: &(String, QueryType)) {
+        if let Some(old) = self.entries.remove(key) {
+            self.count(key, &old, false);
+        }
+    }
+
+    fn retain<F: FnMut(&(String, QueryType), &CacheEntry) -> bool>(&mut self, mut keep: F) {
+        let removed: Vec<(String, QueryType)> = self
+            .entries
+            .iter()
+            .filter(|(key, entry)| !keep(key, entry))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in removed {
+            self.remove(&key);
+        }
+    }
+
+    fn clear(&mut self) {
+        *self = ResponseCache::default();
+    }
+
+    fn count(&mut self, key: &(String, QueryType), entry: &CacheEntry, added: bool) {
+        let counter = if entry.is_negative() {
+            &mut self.negative
+        } else {
+            match entry.source {
+                CacheSource::Blockchain => &mut self.alfis,
+                CacheSource::Forwarded => &mut self.forwarded,
+            }
+        };
+        let size = entry.approx_size(&key.0);
+        if added {
+            *counter += 1;
+            self.bytes += size;
+        } else {
+            *counter = counter.saturating_sub(1);
+            self.bytes = self.bytes.saturating_sub(size);
+        }
+    }
+}
+
+// Workers resolving UDP queries, the pool size adapts between min and max.
+// The max is `dns.threads` from the config unless set by setAdaptiveThreads.
+static UDP_POOL: WorkerPool = WorkerPool::new("DNS-UDP-Worker", &ADAPTIVE_MIN_THREADS, &ADAPTIVE_MAX_THREADS, usize::MAX);
+static ADAPTIVE_MIN_THREADS: AtomicUsize = AtomicUsize::new(1);
+static ADAPTIVE_MAX_THREADS: AtomicUsize = AtomicUsize::new(8);
+static ADAPTIVE_THREADS_SET: AtomicBool = AtomicBool::new(false);
+// Workers serving TCP connections, one connection per worker, up to `dns.threads` of them.
+// Connections beyond the queue are closed right away.
+static TCP_POOL: WorkerPool = WorkerPool::new("DNS-TCP-Worker", &TCP_MIN_THREADS, &TCP_MAX_THREADS, TCP_MAX_QUEUED);
+static TCP_MIN_THREADS: AtomicUsize = AtomicUsize::new(1);
+static TCP_MAX_THREADS: AtomicUsize = AtomicUsize::new(8);
+const TCP_MAX_QUEUED: usize = 16;
+// Open DNS-over-TCP connections and the cap set by setMaxTcpConnections (0 is unlimited)
+static TCP_CONNECTIONS_ACTIVE: AtomicUsize = AtomicUsize::new(0);
+static MAX_TCP_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+// Extra workers exit after being idle this long
+const WORKER_IDLE_COOLDOWN: Duration = Duration::from_secs(30);
+const MAX_WORKER_THREADS: usize = 64;
+
+// Upstreams for forwarded queries, taken from settings when the DNS server starts
+static FORWARDERS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+static BOOTSTRAPS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+// Device DNS servers supplied by the app, used only when all forwarders fail
+static SYSTEM_DNS_FALLBACK: RwLock<Vec<String>> = RwLock::new(Vec::new());
+// How long one upstream gets to answer a forwarded query, in milliseconds
+static FORWARD_TIMEOUT_MS: AtomicU64 = AtomicU64::new(DEFAULT_FORWARD_TIMEOUT_MS);
+const DEFAULT_FORWARD_TIMEOUT_MS: u64 = 5000;
+// Asked by testForwarders and selfTest, any working resolver has an address for it
+const TEST_QUERY_NAME: &str = "example.com";
+// Registered on the main network since its start, selfTest resolves it from the chain
+const SELF_TEST_CHAIN_NAME: &str = "alfis.ygg";
+const MIN_FORWARD_TIMEOUT_MS: u64 = 100;
+const MAX_FORWARD_TIMEOUT_MS: u64 = 30000;
+// EDNS(0) padding of encrypted upstream queries (RFC 7830), block size as recommended by RFC 8467
+static EDNS_PADDING: AtomicBool = AtomicBool::new(false);
+const EDNS_QUERY_PADDING_BLOCK: usize = 128;
+const EDNS_OPTION_PADDING: u16 = 12;
+const EDNS_UDP_PAYLOAD_SIZE: u16 = 1232;
+// Largest UDP response we send, whatever the client advertises
+const MAX_UDP_PAYLOAD_SIZE: usize = 4096;
+// Under memory pressure (reported by the app) the resolver uses smaller buffers, pool and cache
+// until no new report comes for MEMORY_PRESSURE_HOLD
+static MEMORY_PRESSURE_UNTIL: Mutex<Option<Instant>> = Mutex::new(None);
+const MEMORY_PRESSURE_HOLD: Duration = Duration::from_secs(300);
+const LOW_MEMORY_UDP_PAYLOAD_SIZE: usize = 512;
+const LOW_MEMORY_CACHE_ENTRIES: usize = MAX_CACHE_ENTRIES / 4;
+const LOW_MEMORY_MAX_THREADS: usize = 2;
+// Root name, type, class, TTL and RDLENGTH of an OPT record
+const OPT_RECORD_HEADER_LEN: usize = 11;
+
+// Heavy tasks deferred until the device is idle and charging
+static MAINTENANCE: Mutex<MaintenanceState> = Mutex::new(MaintenanceState::new());
+static MAINTENANCE_WAKE: Condvar = Condvar::new();
+const MAINTENANCE_TASKS: [&str; 2] = ["vacuum", "integrity_check"];
+const MAINTENANCE_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+#[cfg(feature = "doh")]
+lazy_static! {
+    static ref DOH_AGENT: ureq::Agent = ureq::AgentBuilder::new()
+        .resolver(|netloc: &str| resolve_with_bootstraps(netloc))
+        .build();
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Thread pool that starts workers when jobs queue up and lets extra ones go after a cooldown
+struct WorkerPool {
+    name: &'static str,
+    min_threads: &'static AtomicUsize,
+    max_threads: &'static AtomicUsize,
+    max_queued: usize,
+    state: Mutex<PoolState>,
+    available: Condvar,
+}
+
+struct PoolState {
+    queue: VecDeque<Job>,
+    workers: usize,
+    idle: usize,
+}
+
+impl WorkerPool {
+    const fn new(name: &'static str, min_threads: &'static AtomicUsize, max_threads: &'static AtomicUsize, max_queued: usize) -> Self {
+        WorkerPool {
+            name,
+            min_threads,
+            max_threads,
+            max_queued,
+            state: Mutex::new(PoolState { queue: VecDeque::new(), workers: 0, idle: 0 }),
+            available: Condvar::new(),
+        }
+    }
+
+    /// Queue a job, false if the queue is full and the job was dropped
+    fn submit(&'static self, job: Job) -> bool {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return false,
+        };
+        if state.queue.len() >= self.max_queued {
+            return false;
+        }
+        state.queue.push_back(job);
+        let mut max = self.max_threads.load(Ordering::Relaxed);
+        if memory_constrained() {
+            max = max.min(LOW_MEMORY_MAX_THREADS.max(self.min_threads.load(Ordering::Relaxed)));
+        }
+        if state.queue.len() > state.idle && state.workers < max {
+            match thread::Builder::new().name(self.name.to_string()).spawn(move || self.work()) {
+                Ok(_) => state.workers += 1,
+                Err(e) => error!("Failed to spawn {} thread: {}", self.name, e),
+            }
+        }
+        self.available.notify_one();
+        true
+    }
+
+    fn work(&'static self) {
+        let mut idle_since = Instant::now();
+        loop {
+            let job = {
+                let mut state = match self.state.lock() {
+                    Ok(state) => state,
+                    Err(_) => return,
+                };
+                loop {
+                    if let Some(job) = state.queue.pop_front() {
+                        break Some(job);
+                    }
+                    let cooled_down = idle_since.elapsed() >= WORKER_IDLE_COOLDOWN
+                        && state.workers > self.min_threads.load(Ordering::Relaxed);
+                    if DNS_SHUTDOWN_FLAG.load(Ordering::Relaxed) || cooled_down {
+                        break None;
+                    }
+                    state.idle += 1;
+                    // Wake up now and then to check the shutdown flag and the cooldown
+                    state = match self.available.wait_timeout(state, Duration::from_millis(500)) {
+                        Ok((state, _)) => state,
+                        Err(_) => return,
+                    };
+                    state.idle -= 1;
+                }
+            };
+            match job {
+                Some(job) => {
+                    job();
+                    idle_since = Instant::now();
+                }
+                None => {
+                    if let Ok(mut state) = self.state.lock() {
+                        state.workers -= 1;
+                        // Jobs left after shutdown belong to a stopped server
+                        if DNS_SHUTDOWN_FLAG.load(Ordering::Relaxed) {
+                            state.queue.clear();
+                        }
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Wake all idle workers so they notice the shutdown flag without waiting for the timeout
+    fn wake_all(&self) {
+        self.available.notify_all();
+    }
+
+    fn stats(&self) -> serde_json::Value {
+        let (current, idle, queued) = match self.state.lock() {
+            Ok(state) => (state.workers, state.idle, state.queue.len()),
+            Err(_) => (0, 0, 0),
+        };
+        serde_json::json!({
+            "current": current,
+            "idle": idle,
+            "queued": queued,
+            "min": self.min_threads.load(Ordering::Relaxed),
+            "max": self.max_threads.load(Ordering::Relaxed),
+        })
+    }
+}
+
+/// Replacement answers for a name, or for a name and all its subdomains
+struct AnswerRewrite {
+    name: String,
+    suffix: bool,
+    targets: Vec<RewriteTarget>,
+    ttl: u32,
+}
+
+enum RewriteTarget {
+    V4(Ipv4Addr),
+    V6(Ipv6Addr),
+    Cname(String),
+}
+
+impl AnswerRewrite {
+    /// Parse {"name": "cdn.example.com" or "*.example.com", "records": [{"type": "A", "value": "10.0.0.1"}], "ttl": 300}
+    fn from_json(rule: &serde_json::Value) -> Result<Self, String> {
+        let name = rule["name"].as_str().ok_or("Rule without name")?.trim().trim_end_matches('.').to_lowercase();
+        let (name, suffix) = match name.strip_prefix("*.") {
+            Some(base) => (base.to_string(), true),
+            None => (name, false),
+        };
+        if name.is_empty() || name.ends_with(".alfis") {
+            return Err(format!("Can't rewrite \"{}\"", name));
+        }
+        let ttl = match rule.get("ttl") {
+            Some(ttl) => ttl.as_u64().and_then(|ttl| u32::try_from(ttl).ok()).ok_or("Invalid ttl")?,
+            None => DEFAULT_REWRITE_TTL,
+        };
+
+        let mut targets = Vec::new();
+        for record in rule["records"].as_array().ok_or("Rule without records")? {
+            let value = record["value"].as_str().ok_or("Record without value")?.trim();
+            let target = match record["type"].as_str().map(|t| t.to_uppercase()).as_deref() {
+                Some("A") => RewriteTarget::V4(value.parse().map_err(|_| format!("Invalid IPv4 address {}", value))?),
+                Some("AAAA") => RewriteTarget::V6(value.parse().map_err(|_| format!("Invalid IPv6 address {}", value))?),
+                Some("CNAME") if !value.is_empty() => RewriteTarget::Cname(value.trim_end_matches('.').to_string()),
+                _ => return Err(format!("Unsupported record for {}, only A, AAAA and CNAME", name)),
+            };
+            targets.push(target);
+        }
+        let cnames = targets.iter().filter(|t| matches!(t, RewriteTarget::Cname(_))).count();
+        if targets.is_empty() || (cnames > 0 && targets.len() > 1) {
+            return Err(format!("{} needs A/AAAA records or a single CNAME", name));
+        }
+        Ok(AnswerRewrite { name, suffix, targets, ttl })
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        name == self.name || (self.suffix && name.strip_suffix(self.name.as_str()).map_or(false, |sub| sub.ends_with('.')))
+    }
+
+    /// Records answering the question, None if the rule doesn't cover its type
+    fn answers(&self, question: &DnsQuestion) -> Option<Vec<DnsRecord>> {
+        let ttl = TransientTtl(self.ttl);
+        let domain = question.name.clone();
+        if let [RewriteTarget::Cname(host)] = self.targets.as_slice() {
+            return Some(vec![DnsRecord::CNAME { domain, host: host.clone(), ttl }]);
+        }
+        // A name with only IPv4 overrides gets no IPv6 answer and vice versa, the real address mustn't leak
+        let records = match question.qtype {
+            QueryType::A => self.targets.iter().filter_map(|t| match t {
+                RewriteTarget::V4(addr) => Some(DnsRecord::A { domain: domain.clone(), addr: *addr, ttl }),
+                _ => None,
+            }).collect(),
+            QueryType::AAAA => self.targets.iter().filter_map(|t| match t {
+                RewriteTarget::V6(addr) => Some(DnsRecord::AAAA { domain: domain.clone(), addr: *addr, ttl }),
+                _ => None,
+            }).collect(),
+            _ => return None,
+        };
+        Some(records)
+    }
+}
+
+/// Counters of the Android query pipeline, reset on every start
+struct DnsCounters {
+    dnssec_stripped: AtomicU64,
+    unix_queries: AtomicU64,
+    tcp_oversized_rejected: AtomicU64,
+    refused_by_type: AtomicU64,
+    fallback_served: AtomicU64,
+    prefetch_hits: AtomicU64,
+    udp_truncated: AtomicU64,
+    rewritten_answers: AtomicU64,
+    responses: AtomicU64,
+    responses_noerror: AtomicU64,
+    responses_nxdomain: AtomicU64,
+    responses_servfail: AtomicU64,
+    answered_local: AtomicU64,
+    answered_forwarded: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    tcp_rejected: AtomicU64,
+    rate_limited: AtomicU64,
+    doh_queries: AtomicU64,
+    blocked_queries: AtomicU64,
+    forward_timeouts: AtomicU64,
+    local_overrides: AtomicU64,
+    tcp_connections_rejected: AtomicU64,
+    negative_cache_hits: AtomicU64,
+}
+
+impl DnsCounters {
+    const fn new() -> Self {
+        DnsCounters {
+            dnssec_stripped: AtomicU64::new(0),
+            unix_queries: AtomicU64::new(0),
+            tcp_oversized_rejected: AtomicU64::new(0),
+            refused_by_type: AtomicU64::new(0),
+            fallback_served: AtomicU64::new(0),
+            prefetch_hits: AtomicU64::new(0),
+            udp_truncated: AtomicU64::new(0),
+            rewritten_answers: AtomicU64::new(0),
+            responses: AtomicU64::new(0),
+            responses_noerror: AtomicU64::new(0),
+            responses_nxdomain: AtomicU64::new(0),
+            responses_servfail: AtomicU64::new(0),
+            answered_local: AtomicU64::new(0),
+            answered_forwarded: AtomicU64::new(0),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            tcp_rejected: AtomicU64::new(0),
+            rate_limited: AtomicU64::new(0),
+            doh_queries: AtomicU64::new(0),
+            blocked_queries: AtomicU64::new(0),
+            forward_timeouts: AtomicU64::new(0),
+            local_overrides: AtomicU64::new(0),
+            tcp_connections_rejected: AtomicU64::new(0),
+            negative_cache_hits: AtomicU64::new(0),
+        }
+    }
+
+    fn reset(&self) {
+        self.dnssec_stripped.store(0, Ordering::Relaxed);
+        self.unix_queries.store(0, Ordering::Relaxed);
+        self.tcp_oversized_rejected.store(0, Ordering::Relaxed);
+        self.refused_by_type.store(0, Ordering::Relaxed);
+        self.fallback_served.store(0, Ordering::Relaxed);
+        self.prefetch_hits.store(0, Ordering::Relaxed);
+        self.udp_truncated.store(0, Ordering::Relaxed);
+        self.rewritten_answers.store(0, Ordering::Relaxed);
+        self.responses.store(0, Ordering::Relaxed);
+        self.responses_noerror.store(0, Ordering::Relaxed);
+        self.responses_nxdomain.store(0, Ordering::Relaxed);
+        self.responses_servfail.store(0, Ordering::Relaxed);
+        self.answered_local.store(0, Ordering::Relaxed);
+        self.answered_forwarded.store(0, Ordering::Relaxed);
+        self.cache_hits.store(0, Ordering::Relaxed);
+        self.cache_misses.store(0, Ordering::Relaxed);
+        self.tcp_rejected.store(0, Ordering::Relaxed);
+        self.rate_limited.store(0, Ordering::Relaxed);
+        self.doh_queries.store(0, Ordering::Relaxed);
+        self.blocked_queries.store(0, Ordering::Relaxed);
+        self.forward_timeouts.store(0, Ordering::Relaxed);
+        self.local_overrides.store(0, Ordering::Relaxed);
+        self.tcp_connections_rejected.store(0, Ordering::Relaxed);
+        self.negative_cache_hits.store(0, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> serde_json::Map<String, serde_json::Value> {
+        let mut map = serde_json::Map::new();
+        map.insert("dnssec_stripped".to_string(), self.dnssec_stripped.load(Ordering::Relaxed).into());
+        map.insert("unix_queries".to_string(), self.unix_queries.load(Ordering::Relaxed).into());
+        map.insert("tcp_oversized_rejected".to_string(), self.tcp_oversized_rejected.load(Ordering::Relaxed).into());
+        map.insert("refused_by_type".to_string(), self.refused_by_type.load(Ordering::Relaxed).into());
+        map.insert("fallback_served".to_string(), self.fallback_served.load(Ordering::Relaxed).into());
+        map.insert("prefetch_hits".to_string(), self.prefetch_hits.load(Ordering::Relaxed).into());
+        map.insert("udp_truncated".to_string(), self.udp_truncated.load(Ordering::Relaxed).into());
+        map.insert("rewritten_answers".to_string(), self.rewritten_answers.load(Ordering::Relaxed).into());
+        map.insert("responses".to_string(), self.responses.load(Ordering::Relaxed).into());
+        map.insert("responses_noerror".to_string(), self.responses_noerror.load(Ordering::Relaxed).into());
+        map.insert("responses_nxdomain".to_string(), self.responses_nxdomain.load(Ordering::Relaxed).into());
+        map.insert("responses_servfail".to_string(), self.responses_servfail.load(Ordering::Relaxed).into());
+        map.insert("answered_local".to_string(), self.answered_local.load(Ordering::Relaxed).into());
+        map.insert("answered_forwarded".to_string(), self.answered_forwarded.load(Ordering::Relaxed).into());
+        map.insert("cache_hits".to_string(), self.cache_hits.load(Ordering::Relaxed).into());
+        map.insert("cache_misses".to_string(), self.cache_misses.load(Ordering::Relaxed).into());
+        map.insert("tcp_rejected".to_string(), self.tcp_rejected.load(Ordering::Relaxed).into());
+        map.insert("rate_limited".to_string(), self.rate_limited.load(Ordering::Relaxed).into());
+        map.insert("doh_queries".to_string(), self.doh_queries.load(Ordering::Relaxed).into());
+        map.insert("blocked_queries".to_string(), self.blocked_queries.load(Ordering::Relaxed).into());
+        map.insert("forward_timeouts".to_string(), self.forward_timeouts.load(Ordering::Relaxed).into());
+        map.insert("local_overrides".to_string(), self.local_overrides.load(Ordering::Relaxed).into());
+        map.insert("tcp_connections_rejected".to_string(), self.tcp_connections_rejected.load(Ordering::Relaxed).into());
+        map.insert("negative_cache_hits".to_string(), self.negative_cache_hits.load(Ordering::Relaxed).into());
+        map
+    }
+}
+
+/// A domain block created on this device that still needs mining and propagation
+struct PendingBlock {
+    domain: String,
+    action: &'static str,
+    block: Block,
+    keystore: Keystore,
+    submitted: u64,
+    // Handed to the running miner, given to it again when the miner is restarted
+    mining: bool,
+}
+
+/// Speed reports of the miner threads for the current job, started is None between jobs
+struct MinerReports {
+    started: Option<Instant>,
+    last: Vec<Option<Instant>>,
+    speeds: Vec<u64>,
+    attempts: u64,
+    target_diff: u32,
+    max_diff: u32,
+}
+
+impl MinerReports {
+    const fn new() -> Self {
+        MinerReports { started: None, last: Vec::new(), speeds: Vec::new(), attempts: 0, target_diff: 0, max_diff: 0 }
+    }
+
+    /// Attempts since the previous report of this thread, or since the job started
+    fn attempts(&mut self, thread: usize, speed: u64) -> u64 {
+        let now = Instant::now();
+        if self.last.len() <= thread {
+            self.last.resize(thread + 1, None);
+            self.speeds.resize(thread + 1, 0);
+        }
+        self.speeds[thread] = speed;
+        let since = self.last[thread].replace(now).or(self.started).unwrap_or(now);
+        let attempts = (speed as f64 * now.duration_since(since).as_secs_f64()) as u64;
+        self.attempts += attempts;
+        attempts
+    }
+
+    fn progress(&self) -> serde_json::Value {
+        let started = match self.started {
+            Some(started) => started,
+            None => return serde_json::json!({"active": false, "status": "no active job"}),
+        };
+        serde_json::json!({
+            "active": true,
+            "target_difficulty": self.target_diff,
+            "best_difficulty": self.max_diff,
+            "attempts": self.attempts,
+            "hash_rate": self.speeds.iter().sum::<u64>(),
+            "elapsed_secs": started.elapsed().as_secs(),
+        })
+    }
+}
+
+/// Running statistics of block sizes, used to estimate sync traffic
+struct SyncDataStats {
+    session_start_height: u64,
+    sampled_height: u64,
+    sampled_blocks: u64,
+    sampled_bytes: u64,
+    downloaded_bytes: u64,
+}
+
+impl SyncDataStats {
+    const fn new() -> Self {
+        SyncDataStats { session_start_height: 0, sampled_height: 0, sampled_blocks: 0, sampled_bytes: 0, downloaded_bytes: 0 }
+    }
+
+    fn average_block_size(&self) -> u64 {
+        if self.sampled_blocks == 0 {
+            0
+        } else {
+            self.sampled_bytes / self.sampled_blocks
+        }
+    }
+}
+
+/// Everything the running node consists of, set on start and taken apart on stop
+struct AlfisState {
+    alfis_context: Option<Arc<Mutex<Context>>>,
+    server_context: Option<Arc<ServerContext>>,
+    network_handle: Option<thread::JoinHandle<()>>,
+    dns_udp_handle: Option<thread::JoinHandle<()>>,
+    dns_tcp_handle: Option<thread::JoinHandle<()>>,
+    // UDP and TCP listeners on the second loopback address with dual stack
+    dns_dual_stack_handles: Vec<thread::JoinHandle<()>>,
+    // Persistent key and the file it was loaded from
+    keystore: Option<(String, Keystore)>,
+    // Mines the pending domain blocks while mining is on
+    miner: Option<Arc<Mutex<Miner>>>,
+}
+
+impl AlfisState {
+    const fn new() -> Self {
+        AlfisState {
+            alfis_context: None,
+            server_context: None,
+            network_handle: None,
+            dns_udp_handle: None,
+            dns_tcp_handle: None,
+            dns_dual_stack_handles: Vec::new(),
+            keystore: None,
+            miner: None,
+        }
+    }
+}
+
+/// Lock the node state, a panic in another thread doesn't make it unusable
+fn state() -> std::sync::MutexGuard<'static, AlfisState> {
+    STATE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// The node context, only while the DNS server is running
+fn running_context() -> Option<Arc<Mutex<Context>>> {
+    if !DNS_RUNNING.load(Ordering::Acquire) {
+        return None;
+    }
+    state().alfis_context.clone()
+}
+
+/// The DNS server context, only while the DNS server is running
+fn running_server_context() -> Option<Arc<ServerContext>> {
+    if !DNS_RUNNING.load(Ordering::Acquire) {
+        return None;
+    }
+    state().server_context.clone()
+}
+
+/// Queued maintenance tasks and the device conditions they wait for
+struct MaintenanceState {
+    queue: Vec<(String, Instant)>,
+    running: Option<String>,
+    device_idle: bool,
+    charging: bool,
+    worker_active: bool,
+    last_run: Option<serde_json::Value>,
+}
+
+impl MaintenanceState {
+    const fn new() -> Self {
+        MaintenanceState { queue: Vec::new(), running: None, device_idle: false, charging: false, worker_active: false, last_run: None }
+    }
+
+    fn can_run(&self) -> bool {
+        self.device_idle && self.charging
+    }
+
+    fn status(&self) -> serde_json::Value {
+        let now = Instant::now();
+        let pending: Vec<serde_json::Value> = self
+            .queue
+            .iter()
+            .map(|(task, not_before)| serde_json::json!({
+                "task": task,
+                "due_in_secs": not_before.saturating_duration_since(now).as_secs(),
+            }))
+            .collect();
+        serde_json::json!({
+            "pending": pending,
+            "running": self.running,
+            "device_idle": self.device_idle,
+            "charging": self.charging,
+            "last_run": self.last_run,
+        })
+    }
+}
+
+/// Why a query to an upstream failed, timeouts are counted separately
+enum UpstreamError {
+    Timeout,
+    Failed(String),
+}
+
+impl std::fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpstreamError::Timeout => write!(f, "Timed out"),
+            UpstreamError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<String> for UpstreamError {
+    fn from(e: String) -> Self {
+        UpstreamError::Failed(e)
+    }
+}
+
+impl From<std::io::Error> for UpstreamError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => UpstreamError::Timeout,
+            _ => UpstreamError::Failed(e.to_string()),
+        }
+    }
+}
+
+#[cfg(feature = "doh")]
+impl From<ureq::Error> for UpstreamError {
+    fn from(e: ureq::Error) -> Self {
+        use std::error::Error;
+
+        let timed_out = e
+            .source()
+            .and_then(|source| source.downcast_ref::<std::io::Error>())
+            .map(|io| matches!(io.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut))
+            .unwrap_or(false);
+        match timed_out {
+            true => UpstreamError::Timeout,
+            false => UpstreamError::Failed(e.to_string()),
+        }
+    }
+}
+
+/// Query counters of one forwarder
+#[derive(Default)]
+struct UpstreamStats {
+    queries: u64,
+    successes: u64,
+    timeouts: u64,
+    total_latency_ms: u64,
+}
 
 /// Initialize Android logging
 #[no_mangle]
 pub extern "C" fn Java_name_alfis_android_native_AlfisNative_initLogging(
-    _env: JNIEnv,
+    env: JNIEnv,
     _class: JClass,
 ) {
-    android_logger::init_once(
-        android_logger::Config::default()
-            .with_max_level(log::LevelFilter::Info)
-            .with_tag("AlfisRust"),
-    );
+    static LOGGER: Once = Once::new();
+    LOGGER.call_once(|| {
+        // The logger itself passes everything, the level is controlled by the global max level
+        let config = android_logger::Config::default()
+            .with_max_level(log::LevelFilter::Trace)
+            .with_tag("AlfisRust");
+        let logger = ConsoleLogger { android: android_logger::AndroidLogger::new(config) };
+        if log::set_logger(Box::leak(Box::new(logger))).is_err() {
+            warn!("Logger was already set");
+        }
+        log::set_max_level(log::LevelFilter::Info);
+    });
     
     // Initialize log buffer for console output
-    unsafe {
-        LOG_BUFFER = Some(Arc::new(Mutex::new(VecDeque::new())));
+    if let Ok(mut buffer) = LOG_BUFFER.lock() {
+        buffer.clear();
+        LOG_BUFFER_BYTES.store(0, Ordering::Relaxed);
+    }
+    install_panic_hook();
+    match env.get_java_vm() {
+        Ok(vm) => {
+            let _ = JAVA_VM.set(vm);
+        }
+        Err(e) => error!("Failed to get JavaVM: {}", e),
     }
     
     add_log_message("Alfis Android logging initialized".to_string());
     info!("Alfis Android logging initialized");
 }
 
+/// Set the level of native logging: "error", "warn", "info", "debug" or "trace"
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_setLogLevel(
+    mut env: JNIEnv,
+    _class: JClass,
+    level: JString,
+) -> jboolean {
+    let level: String = match env.get_string(&level) {
+        Ok(level) => level.into(),
+        Err(e) => {
+            error!("Failed to get log level from Java: {}", e);
+            return 0; // false
+        }
+    };
+    let filter = match level.trim().to_lowercase().as_str() {
+        "error" => log::LevelFilter::Error,
+        "warn" => log::LevelFilter::Warn,
+        "info" => log::LevelFilter::Info,
+        "debug" => log::LevelFilter::Debug,
+        "trace" => log::LevelFilter::Trace,
+        _ => {
+            warn!("Unknown log level '{}'", level);
+            return 0; // false
+        }
+    };
+
+    log::set_max_level(filter);
+    add_log_message(format!("Log level set to {}", filter));
+    1 // true
+}
+
 /// Start the DNS server
 #[no_mangle]
 pub extern "C" fn Java_name_alfis_android_native_AlfisNative_startDnsServer(
@@ -84,76 +1044,79 @@ pub extern "C" fn Java_name_alfis_android_native_AlfisNative_startDnsServer(
         }
     };
 
-    unsafe {
-        if DNS_RUNNING {
-            add_log_message("DNS server is already running".to_string());
-            warn!("DNS server is already running");
-            return 1; // true - already running
-        }
-        
-        add_log_message("Starting DNS server...".to_string());
-        info!("Starting DNS server...");
-        
-        // Reset shutdown flag and network peer count for new start
-        DNS_SHUTDOWN_FLAG.store(false, Ordering::Relaxed);
-        NETWORK_PEER_COUNT = 0;
+    match start_node(&config_path, &work_dir, &log_file) {
+        true => 1, // true
+        false => 0, // false
+    }
+}
 
-        // Start the DNS server in a background thread to avoid blocking the main thread
-        let config_path_clone = config_path.clone();
-        let work_dir_clone = work_dir.clone();
-        let log_file_clone = log_file.clone();
-        
-        thread::spawn(move || {
-            match start_dns_server_internal(&config_path_clone, &work_dir_clone, &log_file_clone) {
-                Ok((context, server_context)) => {
-                    unsafe {
-                        ALFIS_CONTEXT = Some(context);
-                        SERVER_CONTEXT = Some(server_context);
-                        DNS_RUNNING = true;
-                        DNS_START_TIME = SystemTime::now()
-                            .duration_since(UNIX_EPOCH)
-                            .unwrap()
-                            .as_secs();
-                    }
-                    add_log_message("DNS server started successfully".to_string());
-                    add_log_message("UDP and TCP servers listening on configured address".to_string());
-                    
-                    // Log initial blockchain status
-                    unsafe {
-                        if let Some(alfis_ctx) = &ALFIS_CONTEXT {
-                            if let Ok(ctx_guard) = alfis_ctx.lock() {
-                                let blocks = ctx_guard.chain.get_height();
-                                add_log_message(format!("Blockchain loaded with {} blocks", blocks));
-                            }
-                        }
-                    }
-                    
-                    add_log_message("Ready to resolve .alfis domains".to_string());
-                    add_log_message("DNS forwarding enabled for regular domains".to_string());
-                    info!("DNS server started successfully");
+/// Start the node in a background thread, true if it is running half a second later
+fn start_node(config_path: &str, work_dir: &str, log_file: &str) -> bool {
+    remember_config_path(config_path);
+
+    if DNS_RUNNING.load(Ordering::Acquire) {
+        add_log_message("DNS server is already running".to_string());
+        warn!("DNS server is already running");
+        return true; // already running
+    }
+
+    add_log_message("Starting DNS server...".to_string());
+    info!("Starting DNS server...");
+    set_last_start_error(String::new());
+
+    // Reset shutdown flags and network peer count for new start
+    DNS_SHUTDOWN_FLAG.store(false, Ordering::Relaxed);
+    NETWORK_SHUTDOWN.store(false, Ordering::Relaxed);
+    NETWORK_PAUSED.store(false, Ordering::Relaxed);
+    NETWORK_PEER_COUNT.store(0, Ordering::Relaxed);
+    DNS_COUNTERS.reset();
+    load_stats_totals(work_dir);
+    MINING_HASHES.store(0, Ordering::Relaxed);
+    MINED_BLOCKS.store(0, Ordering::Relaxed);
+    cache_clear(None);
+
+    // Start the DNS server in a background thread to avoid blocking the main thread
+    let config_path_clone = config_path.to_string();
+    let work_dir_clone = work_dir.to_string();
+    let log_file_clone = log_file.to_string();
+
+    thread::spawn(move || {
+        match start_dns_server_internal(&config_path_clone, &work_dir_clone, &log_file_clone) {
+            Ok((context, server_context)) => {
+                // Log initial blockchain status
+                let blocks = context.lock().map(|ctx| ctx.chain.get_height()).ok();
+                {
+                    let mut state = state();
+                    state.alfis_context = Some(context);
+                    state.server_context = Some(server_context);
                 }
-                Err(e) => {
-                    add_log_message(format!("Failed to start DNS server: {}", e));
-                    error!("Failed to start DNS server: {}", e);
-                    unsafe {
-                        DNS_RUNNING = false;
-                    }
+                DNS_START_TIME.store(unix_time(), Ordering::Relaxed);
+                DNS_RUNNING.store(true, Ordering::Release);
+                add_log_message("DNS server started successfully".to_string());
+                add_log_message("UDP and TCP servers listening on configured address".to_string());
+
+                if let Some(blocks) = blocks {
+                    add_log_message(format!("Blockchain loaded with {} blocks", blocks));
                 }
+
+                add_log_message("Ready to resolve .alfis domains".to_string());
+                add_log_message("DNS forwarding enabled for regular domains".to_string());
+                info!("DNS server started successfully");
             }
-        });
-        
-        // Give the server a moment to start
-        thread::sleep(Duration::from_millis(500));
-        
-        // Check if server was started
-        unsafe {
-            if DNS_RUNNING {
-                1 // true
-            } else {
-                0 // false
+            Err(e) => {
+                add_log_message(format!("Failed to start DNS server: {}", e));
+                error!("Failed to start DNS server: {}", e);
+                set_last_start_error(e.to_string());
+                DNS_RUNNING.store(false, Ordering::Release);
             }
         }
-    }
+    });
+
+    // Give the server a moment to start
+    thread::sleep(Duration::from_millis(500));
+
+    // Check if server was started
+    DNS_RUNNING.load(Ordering::Acquire)
 }
 
 
@@ -163,81 +1126,134 @@ pub extern "C" fn Java_name_alfis_android_native_AlfisNative_stopDnsServer(
     _env: JNIEnv,
     _class: JClass,
 ) -> jboolean {
-    unsafe {
-        if DNS_RUNNING {
-            add_log_message("Stopping DNS server...".to_string());
-            info!("Stopping DNS server...");
-            
-            // Signal shutdown to all DNS threads
-            DNS_SHUTDOWN_FLAG.store(true, Ordering::Relaxed);
-            DNS_RUNNING = false;
-            
-            add_log_message("Waiting for DNS threads to stop...".to_string());
-            
-            // Give threads a moment to see the shutdown flag
-            thread::sleep(Duration::from_millis(100));
-            
-            // Try to join UDP thread with timeout
-            if let Some(handle) = DNS_UDP_HANDLE.take() {
-                add_log_message("Stopping UDP server thread...".to_string());
-                // Note: std::thread::JoinHandle doesn't have timeout, so we'll just join
-                // The threads should exit quickly due to the 10ms sleep in the loop
-                match handle.join() {
-                    Ok(_) => add_log_message("UDP server thread stopped".to_string()),
-                    Err(e) => {
-                        add_log_message(format!("UDP thread join failed: {:?}", e));
-                        error!("Failed to join UDP thread: {:?}", e);
-                    }
-                }
-            }
-            
-            // Try to join TCP thread
-            if let Some(handle) = DNS_TCP_HANDLE.take() {
-                add_log_message("Stopping TCP server thread...".to_string());
-                match handle.join() {
-                    Ok(_) => add_log_message("TCP server thread stopped".to_string()),
-                    Err(e) => {
-                        add_log_message(format!("TCP thread join failed: {:?}", e));
-                        error!("Failed to join TCP thread: {:?}", e);
-                    }
-                }
-            }
-            
-            // Stop network thread if it exists
-            if let Some(handle) = NETWORK_HANDLE.take() {
-                add_log_message("Stopping network thread...".to_string());
-                // The network thread should stop when contexts are cleared
-                // We don't join it to avoid hanging
-                drop(handle);
-            }
-            
-            // Clear all contexts and handles
-            ALFIS_CONTEXT = None;
-            SERVER_CONTEXT = None;
-            NETWORK_PEER_COUNT = 0;
-            
-            add_log_message("DNS server stopped cleanly - port 5353 released".to_string());
-            info!("DNS server stopped cleanly");
-            1 // true
-        } else {
-            warn!("DNS server was not running");
-            0 // false
-        }
+    match stop_node() {
+        true => 1, // true
+        false => 0, // false
     }
 }
 
+/// Stop the DNS listeners and the network, false if the node wasn't running
+fn stop_node() -> bool {
+    if !DNS_RUNNING.swap(false, Ordering::AcqRel) {
+        warn!("DNS server was not running");
+        return false;
+    }
+    add_log_message("Stopping DNS server...".to_string());
+    info!("Stopping DNS server...");
+
+    // Signal shutdown to all DNS threads
+    DNS_SHUTDOWN_FLAG.store(true, Ordering::Relaxed);
+
+    add_log_message("Waiting for DNS threads to stop...".to_string());
+
+    // Give threads a moment to see the shutdown flag
+    thread::sleep(Duration::from_millis(100));
+
+    // Take the handles out first, joining must not happen under the state lock
+    let (udp_handle, tcp_handle, dual_stack_handles, network_handle) = {
+        let mut state = state();
+        (
+            state.dns_udp_handle.take(),
+            state.dns_tcp_handle.take(),
+            std::mem::take(&mut state.dns_dual_stack_handles),
+            state.network_handle.take(),
+        )
+    };
+
+    // Try to join UDP thread with timeout
+    if let Some(handle) = udp_handle {
+        add_log_message("Stopping UDP server thread...".to_string());
+        // Note: std::thread::JoinHandle doesn't have timeout, so we'll just join
+        // The threads should exit quickly due to the 10ms sleep in the loop
+        match handle.join() {
+            Ok(_) => add_log_message("UDP server thread stopped".to_string()),
+            Err(e) => {
+                add_log_message(format!("UDP thread join failed: {:?}", e));
+                error!("Failed to join UDP thread: {:?}", e);
+            }
+        }
+    }
+
+    // Try to join TCP thread
+    if let Some(handle) = tcp_handle {
+        add_log_message("Stopping TCP server thread...".to_string());
+        match handle.join() {
+            Ok(_) => add_log_message("TCP server thread stopped".to_string()),
+            Err(e) => {
+                add_log_message(format!("TCP thread join failed: {:?}", e));
+                error!("Failed to join TCP thread: {:?}", e);
+            }
+        }
+    }
+
+    // Listeners on the second loopback address
+    if !dual_stack_handles.is_empty() {
+        add_log_message("Stopping dual-stack server threads...".to_string());
+        for handle in dual_stack_handles {
+            if let Err(e) = handle.join() {
+                add_log_message(format!("Dual-stack thread join failed: {:?}", e));
+                error!("Failed to join dual-stack thread: {:?}", e);
+            }
+        }
+    }
+
+    // Join DoH listener thread, if it was enabled
+    stop_doh_listener();
+
+    // Try to join Unix socket thread, it removes its socket file
+    if let Some((_path, handle)) = DNS_UNIX_HANDLE.lock().ok().and_then(|mut guard| guard.take()) {
+        add_log_message("Stopping Unix socket server thread...".to_string());
+        if let Err(e) = handle.join() {
+            add_log_message(format!("Unix socket thread join failed: {:?}", e));
+            error!("Failed to join Unix socket thread: {:?}", e);
+        }
+    }
+
+    // release_node forgets the address, read it first
+    let active_addr = ACTIVE_DNS_ADDR.lock().ok().and_then(|addr| *addr);
+    release_node(network_handle);
+
+    match active_addr {
+        Some(addr) => add_log_message(format!("DNS server stopped cleanly - {} released", addr)),
+        None => add_log_message("DNS server stopped cleanly".to_string()),
+    }
+    info!("DNS server stopped cleanly");
+    true
+}
+
+/// Stop the network thread and forget the contexts once the DNS listeners are gone
+fn release_node(network_handle: Option<thread::JoinHandle<()>>) {
+    // Stop network thread if it exists, it isn't there while the network is paused
+    if let Some(handle) = network_handle {
+        stop_network_thread(handle);
+    }
+    NETWORK_PAUSED.store(false, Ordering::Relaxed);
+
+    save_stats_totals();
+
+    if let Ok(mut active) = ACTIVE_DNS_ADDR.lock() {
+        *active = None;
+    }
+
+    // Clear all contexts and handles
+    {
+        let mut state = state();
+        state.alfis_context = None;
+        state.server_context = None;
+    }
+    NETWORK_PEER_COUNT.store(0, Ordering::Relaxed);
+}
+
 /// Check if DNS server is running
 #[no_mangle]
 pub extern "C" fn Java_name_alfis_android_native_AlfisNative_isDnsServerRunning(
     _env: JNIEnv,
     _class: JClass,
 ) -> jboolean {
-    unsafe {
-        if DNS_RUNNING {
-            1 // true
-        } else {
-            0 // false
-        }
+    if DNS_RUNNING.load(Ordering::Acquire) {
+        1 // true
+    } else {
+        0 // false
     }
 }
 
@@ -247,44 +1263,7 @@ pub extern "C" fn Java_name_alfis_android_native_AlfisNative_getDnsStats(
     env: JNIEnv,
     _class: JClass,
 ) -> jstring {
-    let stats_json = unsafe {
-        if DNS_RUNNING {
-            let _uptime = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs() - DNS_START_TIME;
-                
-            // Get comprehensive statistics 
-            let (queries, responses, blocks, peers) = match (&SERVER_CONTEXT, &ALFIS_CONTEXT) {
-                (Some(server_ctx), Some(alfis_ctx)) => {
-                    let udp_queries = server_ctx.statistics.get_udp_query_count();
-                    let tcp_queries = server_ctx.statistics.get_tcp_query_count();
-                    let total_queries = udp_queries + tcp_queries;
-                    
-                    // Get blockchain statistics
-                    let (block_count, peer_count) = if let Ok(ctx_guard) = alfis_ctx.lock() {
-                        let blocks = ctx_guard.chain.get_height();
-                        let peers = unsafe { NETWORK_PEER_COUNT };
-                        // Add debug logging for block count
-                        if blocks > 0 {
-                            log::debug!("Blockchain height: {}", blocks);
-                        }
-                        (blocks, peers)
-                    } else {
-                        log::warn!("Failed to acquire context lock for statistics");
-                        (0, 0)
-                    };
-                    
-                    (total_queries, total_queries, block_count, peer_count)
-                }
-                _ => (0, 0, 0, 0)
-            };
-                
-            format!(r#"{{"blocks": {}, "peers": {}, "queries": {}, "responses": {}}}"#, blocks, peers, queries, responses)
-        } else {
-            r#"{"blocks": 0, "peers": 0, "queries": 0, "responses": 0}"#.to_string()
-        }
-    };
+    let stats_json = dns_stats().to_string();
 
     match env.new_string(stats_json) {
         Ok(jstr) => jstr.as_raw(),
@@ -310,6 +1289,8 @@ pub extern "C" fn Java_name_alfis_android_native_AlfisNative_generateDefaultConf
         }
     };
 
+    remember_config_path(&config_path);
+
     match generate_android_config(&config_path) {
         Ok(_) => {
             add_log_message(format!("Default configuration generated at: {}", config_path));
@@ -324,23 +1305,78 @@ pub extern "C" fn Java_name_alfis_android_native_AlfisNative_generateDefaultConf
     }
 }
 
+/// Generate the default config for another Alfis network: `origin` is the hash of its first block
+/// (64 hex characters), `peers_json` a JSON array of "host:port" bootstrap peers, empty for the defaults
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_generateConfigWithParams(
+    mut env: JNIEnv,
+    _class: JClass,
+    config_path: JString,
+    origin: JString,
+    check_blocks: jint,
+    peers_json: JString,
+) -> jboolean {
+    let config_path: String = match env.get_string(&config_path) {
+        Ok(path) => path.into(),
+        Err(e) => {
+            error!("Failed to get config path: {}", e);
+            return 0; // false
+        }
+    };
+    let origin: String = match env.get_string(&origin) {
+        Ok(origin) => origin.into(),
+        Err(e) => {
+            error!("Failed to get origin: {}", e);
+            return 0; // false
+        }
+    };
+    let peers_json: String = match env.get_string(&peers_json) {
+        Ok(peers) => peers.into(),
+        Err(e) => {
+            error!("Failed to get peers: {}", e);
+            return 0; // false
+        }
+    };
+    if check_blocks < 0 {
+        add_log_message(format!("Failed to generate configuration: check_blocks can't be negative ({})", check_blocks));
+        return 0; // false
+    }
+    let peers = match peers_json.trim() {
+        "" | "null" => None,
+        json => match serde_json::from_str::<Vec<String>>(json) {
+            Ok(peers) => Some(peers.into_iter().map(|peer| peer.trim().to_string()).collect::<Vec<_>>()),
+            Err(e) => {
+                add_log_message(format!("Failed to generate configuration: invalid peers list: {}", e));
+                return 0; // false
+            }
+        },
+    };
+
+    remember_config_path(&config_path);
+
+    match generate_config_with_params(&config_path, origin.trim(), check_blocks as u64, peers.as_deref()) {
+        Ok(_) => {
+            add_log_message(format!("Configuration for origin {} generated at: {}", origin.trim(), config_path));
+            info!("Configuration for origin {} generated at: {}", origin.trim(), config_path);
+            1 // true
+        }
+        Err(e) => {
+            add_log_message(format!("Failed to generate configuration: {}", e));
+            error!("Failed to generate configuration: {}", e);
+            0 // false
+        }
+    }
+}
+
 /// Get console output for the Android app
 #[no_mangle]
 pub extern "C" fn Java_name_alfis_android_native_AlfisNative_getConsoleOutput(
     env: JNIEnv,
     _class: JClass,
 ) -> jstring {
-    let console_output = unsafe {
-        match &LOG_BUFFER {
-            Some(buffer) => {
-                if let Ok(buffer_guard) = buffer.lock() {
-                    buffer_guard.iter().cloned().collect::<Vec<String>>().join("\n")
-                } else {
-                    "Failed to get log buffer lock".to_string()
-                }
-            }
-            None => "Log buffer not initialized".to_string(),
-        }
+    let console_output = match LOG_BUFFER.lock() {
+        Ok(buffer_guard) => buffer_guard.iter().cloned().collect::<Vec<String>>().join("\n"),
+        Err(_) => "Failed to get log buffer lock".to_string(),
     };
 
     match env.new_string(console_output) {
@@ -352,473 +1388,6602 @@ pub extern "C" fn Java_name_alfis_android_native_AlfisNative_getConsoleOutput(
     }
 }
 
-/// Trigger network reconnection after connectivity change
+/// Clear the console buffer, e.g. before reproducing a problem. Safe to call before initLogging.
 #[no_mangle]
-pub extern "C" fn Java_name_alfis_android_native_AlfisNative_triggerNetworkReconnect(
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_clearLogs(
     _env: JNIEnv,
     _class: JClass,
 ) {
-    unsafe {
-        if DNS_RUNNING {
-            add_log_message("Network connectivity changed - triggering reconnection".to_string());
-            
-            // Signal the network thread to reconnect
-            if let Some(context) = &ALFIS_CONTEXT {
-                if let Ok(ctx) = context.lock() {
-                    let current_height = ctx.chain.get_height();
-                    
-                    // Force network reconnection by restarting network connections
-                    // This helps recover from network switches (WiFi <-> cellular)
-                    info!("Triggering network reconnection due to connectivity change");
-                    add_log_message(format!("Reconnecting at block {} - clearing stale connections", current_height));
-                    
-                    // The network layer should automatically handle reconnection
-                    // when it detects connection issues during its periodic operations
-                } else {
-                    warn!("Could not acquire context lock for network reconnection");
-                }
-            }
-        }
+    if let Ok(mut buffer) = LOG_BUFFER.lock() {
+        buffer.clear();
+        LOG_BUFFER_BYTES.store(0, Ordering::Relaxed);
     }
 }
 
-// Internal implementation functions
-
-fn start_dns_server_internal(
-    config_path: &str,
-    _work_dir: &str,
-    _log_file: &str,
-) -> Result<(Arc<Mutex<Context>>, Arc<ServerContext>), Box<dyn std::error::Error>> {
-    add_log_message("Alfis DNS resolver starting...".to_string());
-    add_log_message(format!("Loading configuration from {}", config_path));
-    info!("Starting DNS server with config: {}", config_path);
-
-    // Load settings with error handling
-    let mut settings = match Settings::load(config_path) {
-        Some(s) => s,
-        None => {
-            add_log_message("Configuration not found, generating defaults".to_string());
-            warn!("Failed to load settings from {}, generating default config", config_path);
-            generate_android_config(config_path)?;
-            add_log_message("Default configuration created".to_string());
-            Settings::load(config_path).ok_or("Failed to load generated settings")?
+/// Write the whole console buffer to a file, one timestamped message per line.
+/// Parent directories are created.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_exportLogs(
+    mut env: JNIEnv,
+    _class: JClass,
+    path: JString,
+) -> jboolean {
+    let path: String = match env.get_string(&path) {
+        Ok(s) => s.into(),
+        Err(e) => {
+            error!("Failed to get log export path from Java: {}", e);
+            return 0; // false
         }
     };
-    
-    // Debug: Log the DNS listen address from config
-    add_log_message(format!("Loaded DNS listen address from config: {}", settings.dns.listen));
-    
-    // Override settings for Android
-    // Note: DNS listen address is taken from config file, no override here
-    settings.dns.threads = 8; // Increased for better performance
-    
-    // Initialize context with better error handling
-    let keystore = Keystore::new();
-    let keystores = vec![keystore];
-    
-    // Initialize chain with better Android-specific error handling
-    let db_path = format!("{}/alfis.db", _work_dir);
-    add_log_message("Initializing blockchain database...".to_string());
-    info!("Initializing blockchain database at: {}", db_path);
-    
-    // Ensure the directory exists
-    if let Some(parent) = std::path::Path::new(&db_path).parent() {
+
+    // Copy first, the file is written without holding the buffer lock
+    let mut logs = match LOG_BUFFER.lock() {
+        Ok(buffer_guard) => buffer_guard.iter().cloned().collect::<Vec<String>>().join("\n"),
+        Err(_) => return 0, // false
+    };
+    logs.push('\n');
+    if let Some(parent) = std::path::Path::new(&path).parent() {
         if let Err(e) = std::fs::create_dir_all(parent) {
-            warn!("Could not create database directory: {}", e);
+            error!("Failed to create directory for logs {}: {}", path, e);
+            return 0; // false
         }
     }
-    
-    let chain = match create_chain_safely(&settings, &db_path) {
-        Ok(chain) => chain,
+    match std::fs::write(&path, logs) {
+        Ok(_) => 1, // true
         Err(e) => {
-            warn!("Failed to create file-based database ({}), falling back to in-memory database", e);
-            // Fallback to in-memory database for Android compatibility
-            match create_chain_safely(&settings, ":memory:") {
-                Ok(chain) => {
-                    add_log_message("Blockchain initialized (in-memory mode)".to_string());
-                    info!("Successfully created in-memory blockchain database");
-                    chain
-                }
-                Err(e) => {
-                    add_log_message(format!("Error: Blockchain initialization failed: {}", e));
-                    error!("Even in-memory database failed: {}", e);
-                    return Err("Blockchain initialization failed completely".into());
-                }
-            }
+            error!("Failed to export logs to {}: {}", path, e);
+            0 // false
         }
-    };
-    
-    let context = Arc::new(Mutex::new(Context::new("0.8.6".to_owned(), settings.clone(), keystores, chain)));
-    
-    // Start the real DNS server with statistics tracking
-    add_log_message("Starting DNS servers...".to_string());
-    info!("Starting DNS servers...");
-    let server_context = start_dns_server_with_context(&context, &settings)?;
-
-    // Start the P2P network to connect to peers and sync blocks
-    add_log_message("Starting P2P network...".to_string());
-    info!("Starting P2P network...");
-    start_network_with_context(Arc::clone(&context))?;
-
-    info!("DNS server and network started successfully");
-    Ok((context, server_context))
+    }
 }
 
-fn generate_android_config(config_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let config = r#"# Alfis Android Configuration
-# The hash of first block in a chain to know with which nodes to work
-origin = "0000001D2A77D63477172678502E51DE7F346061FF7EB188A2445ECA3FC0780E"
-# Key files (empty for mobile)
-key_files = []
-# Reduced block checking for mobile
-check_blocks = 4
+/// Trigger network reconnection after connectivity change, returns right away
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_triggerNetworkReconnect(
+    _env: JNIEnv,
+    _class: JClass,
+) {
+    trigger_network_reconnect("Network connectivity changed");
+}
 
-# Network settings
-[net]
-# Bootstrap nodes
-peers = ["peer-v4.alfis.name:4244", "peer-v6.alfis.name:4244"]
-# Listen address (Android may restrict this)
-listen = "127.0.0.1:42440"
-# Mobile devices shouldn't be public peers
-public = false
-yggdrasil_only = true
+/// Add a bootstrap peer as host:port, saved to the config and used by the running node
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_addPeer(
+    mut env: JNIEnv,
+    _class: JClass,
+    address: JString,
+) -> jboolean {
+    let address: String = match env.get_string(&address) {
+        Ok(address) => address.into(),
+        Err(e) => {
+            error!("Failed to get peer address from Java: {}", e);
+            return 0; // false
+        }
+    };
+    let address = address.trim().to_string();
+    if !is_valid_peer_address(&address) {
+        warn!("Invalid peer address '{}', expected host:port", address);
+        return 0; // false
+    }
 
-# DNS resolver options
-[dns]
-# Listen on localhost IPv6 (non-privileged port)
-listen = "[::1]:5353"
-# Increased threads for better performance
-threads = 8
-# Use DoH when available, fallback to regular DNS
-forwarders = ["https://dns.adguard.com/dns-query", "8.8.8.8:53"]
-bootstraps = ["8.8.8.8:53", "1.1.1.1:53"]
+    let added = update_peers(|peers| {
+        if peers.contains(&address) {
+            return false;
+        }
+        peers.push(address.clone());
+        true
+    });
+    match added {
+        Ok(true) => {
+            add_log_message(format!("Peer {} added", address));
+            trigger_network_reconnect("Peer added");
+            1 // true
+        }
+        Ok(false) => 1, // true, already known
+        Err(e) => {
+            error!("Failed to add peer {}: {}", address, e);
+            0 // false
+        }
+    }
+}
 
-# Mining disabled on mobile
-[mining]
-threads = 0
-lower = true
-"#;
+/// Remove a bootstrap peer from the config and the running node
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_removePeer(
+    mut env: JNIEnv,
+    _class: JClass,
+    address: JString,
+) -> jboolean {
+    let address: String = match env.get_string(&address) {
+        Ok(address) => address.into(),
+        Err(e) => {
+            error!("Failed to get peer address from Java: {}", e);
+            return 0; // false
+        }
+    };
+    let address = address.trim().to_string();
 
-    std::fs::write(config_path, config)?;
-    Ok(())
+    let removed = update_peers(|peers| {
+        let before = peers.len();
+        peers.retain(|peer| peer != &address);
+        peers.len() != before
+    });
+    match removed {
+        Ok(true) => {
+            add_log_message(format!("Peer {} removed", address));
+            1 // true
+        }
+        Ok(false) => 0, // false, not in the list
+        Err(e) => {
+            error!("Failed to remove peer {}: {}", address, e);
+            0 // false
+        }
+    }
 }
 
-// Helper function to add messages to log buffer
-fn add_log_message(message: String) {
-    let timestamp = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
-    let formatted_message = format!("[{}] {}", timestamp, message);
-    
-    unsafe {
-        if let Some(buffer) = &LOG_BUFFER {
-            if let Ok(mut buffer_guard) = buffer.lock() {
-                buffer_guard.push_back(formatted_message);
-                // Keep only last 100 messages
-                if buffer_guard.len() > 100 {
-                    buffer_guard.pop_front();
-                }
+/// Watch the peer count and force a reconnect to the bootstrap peers after a long time without any,
+/// exits when re-bootstrap is turned off
+fn rebootstrap_worker() {
+    let mut no_peers_since: Option<Instant> = None;
+    loop {
+        let interval = REBOOTSTRAP_INTERVAL_SECS.load(Ordering::Relaxed);
+        if interval == 0 {
+            REBOOTSTRAP_WORKER.store(false, Ordering::Release);
+            // Turned on again right before we stopped, keep going
+            if REBOOTSTRAP_INTERVAL_SECS.load(Ordering::Relaxed) == 0 || REBOOTSTRAP_WORKER.swap(true, Ordering::AcqRel) {
+                return;
+            }
+            continue;
+        }
+
+        // A paused network has no peers on purpose
+        if !DNS_RUNNING.load(Ordering::Acquire) || NETWORK_PAUSED.load(Ordering::Relaxed) || NETWORK_PEER_COUNT.load(Ordering::Relaxed) > 0 {
+            no_peers_since = None;
+        } else if RECONNECTS_IN_FLIGHT.load(Ordering::Acquire) > 0 {
+            // The last reconnect is still dialing, count from when it's done
+            no_peers_since = Some(Instant::now());
+        } else {
+            let since = *no_peers_since.get_or_insert_with(Instant::now);
+            if since.elapsed() >= Duration::from_secs(interval) {
+                trigger_network_reconnect(&format!("No peers for {} s, forcing re-bootstrap", since.elapsed().as_secs()));
+                no_peers_since = Some(Instant::now());
             }
         }
+        thread::sleep(REBOOTSTRAP_POLL_INTERVAL);
     }
 }
 
-// Real DNS server implementation using Alfis core components
-
-/// Start DNS server with controllable threads
+/// Restart the network thread in the background: stale sockets are dropped with the old
+/// Network and the new one dials the bootstrap peers again. Nothing happens while paused.
+fn trigger_network_reconnect(reason: &str) {
+    if running_context().is_none() {
+        return;
+    }
+    add_log_message(format!("{} - triggering reconnection", reason));
+    let reason = reason.to_string();
+    RECONNECTS_IN_FLIGHT.fetch_add(1, Ordering::AcqRel);
+    let spawned = thread::Builder::new()
+        .name(String::from("Network-Reconnect"))
+        .spawn(move || {
+            if let Err(e) = reconnect_network(&reason) {
+                add_log_message(format!("Reconnection failed: {}", e));
+                warn!("Reconnection failed: {}", e);
+            }
+            RECONNECTS_IN_FLIGHT.fetch_sub(1, Ordering::AcqRel);
+        });
+    if let Err(e) = spawned {
+        RECONNECTS_IN_FLIGHT.fetch_sub(1, Ordering::AcqRel);
+        error!("Failed to spawn reconnect thread: {}", e);
+    }
+}
+
+fn reconnect_network(reason: &str) -> Result<(), String> {
+    let guard = NETWORK_CONTROL_LOCK.lock().map_err(|_| "Network lock poisoned")?;
+    let context = running_context().ok_or("DNS server is not running")?;
+    if NETWORK_PAUSED.load(Ordering::Relaxed) {
+        add_log_message("Network is paused, not reconnecting".to_string());
+        return Ok(());
+    }
+
+    let peers_before = NETWORK_PEER_COUNT.load(Ordering::Relaxed);
+    info!("Triggering network reconnection: {}", reason);
+    add_log_message(format!("Reconnecting with {} peers - clearing stale connections", peers_before));
+    let handle = state().network_handle.take();
+    if let Some(handle) = handle {
+        stop_network_thread(handle);
+    }
+    NETWORK_PEER_COUNT.store(0, Ordering::Relaxed);
+    if !DNS_RUNNING.load(Ordering::Acquire) {
+        return Err("DNS server stopped while reconnecting".to_string());
+    }
+    NETWORK_SHUTDOWN.store(false, Ordering::Relaxed);
+    let handle = spawn_network_thread(context).map_err(|e| format!("Failed to start network thread: {}", e))?;
+    state().network_handle = Some(handle);
+    // stop_node doesn't take the network lock, it may have taken the handles before we stored ours
+    if !DNS_RUNNING.load(Ordering::Acquire) {
+        let handle = state().network_handle.take();
+        if let Some(handle) = handle {
+            stop_network_thread(handle);
+        }
+        return Err("DNS server stopped while reconnecting".to_string());
+    }
+    drop(guard);
+
+    // Peers come back over the next status events, give them a moment before reporting
+    thread::sleep(RECONNECT_REPORT_DELAY);
+    add_log_message(format!(
+        "Reconnected: {} peers before, {} peers now",
+        peers_before,
+        NETWORK_PEER_COUNT.load(Ordering::Relaxed)
+    ));
+    Ok(())
+}
+
+/// Re-dial the bootstrap peers whenever no peer was connected for `secs` seconds, 0 turns it off
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_setRebootstrapInterval(
+    _env: JNIEnv,
+    _class: JClass,
+    secs: jint,
+) -> jboolean {
+    if secs < 0 {
+        return 0; // false
+    }
+    REBOOTSTRAP_INTERVAL_SECS.store(secs as u64, Ordering::Relaxed);
+    if secs == 0 {
+        add_log_message("Periodic re-bootstrap disabled".to_string());
+        return 1; // true
+    }
+
+    add_log_message(format!("Re-bootstrap after {} s without peers", secs));
+    if !REBOOTSTRAP_WORKER.swap(true, Ordering::AcqRel) {
+        if let Err(e) = thread::Builder::new().name("Re-Bootstrap".to_string()).spawn(rebootstrap_worker) {
+            REBOOTSTRAP_WORKER.store(false, Ordering::Release);
+            error!("Failed to spawn re-bootstrap thread: {}", e);
+            return 0; // false
+        }
+    }
+    1 // true
+}
+
+/// Take all pending app events as a JSON array string
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_pollEvents(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let events = match APP_EVENTS.lock() {
+        Ok(mut queue) => queue.drain(..).collect::<Vec<String>>(),
+        Err(_) => Vec::new(),
+    };
+    let events_json = format!("[{}]", events.join(","));
+
+    match env.new_string(events_json) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for events: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Estimate how much data the rest of the sync will download, as JSON string
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_getSyncDataEstimate(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let (height, average, downloaded) = match running_context() {
+        Some(context) => match (context.lock(), SYNC_DATA.lock()) {
+            (Ok(ctx), Ok(mut stats)) => {
+                sample_block_sizes(&ctx.chain, &mut stats);
+                (ctx.chain.get_height(), stats.average_block_size(), stats.downloaded_bytes)
+            }
+            _ => (0, 0, 0),
+        },
+        None => (0, 0, 0),
+    };
+    let remaining_blocks = NETWORK_HEIGHT.load(Ordering::Relaxed).saturating_sub(height);
+    let estimate_json = format!(
+        r#"{{"remaining_blocks": {}, "est_bytes": {}, "downloaded_bytes": {}, "avg_block_bytes": {}}}"#,
+        remaining_blocks, remaining_blocks * average, downloaded, average
+    );
+
+    match env.new_string(estimate_json) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for sync estimate: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Get the height of the local blockchain, -1 if the node is not running
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_getBlockchainHeight(
+    _env: JNIEnv,
+    _class: JClass,
+) -> jlong {
+    match running_context() {
+        Some(context) => match context.lock() {
+            Ok(ctx) => ctx.chain.get_height() as jlong,
+            Err(_) => -1,
+        },
+        None => -1,
+    }
+}
+
+/// Get seconds since the last block was received or added to the chain, -1 if none since start
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_getLastBlockAge(
+    _env: JNIEnv,
+    _class: JClass,
+) -> jlong {
+    match LAST_BLOCK_RECEIVED.load(Ordering::Relaxed) {
+        0 => -1,
+        time => uptime_secs(unix_time(), time) as jlong,
+    }
+}
+
+/// Get sync progress from the last Syncing event as JSON string of {have, height, percent}.
+/// Once synced, have equals height and percent is 100.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_getSyncProgress(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    // Both only grow within a session, a have read ahead of its height is clamped
+    let height = NETWORK_HEIGHT.load(Ordering::Relaxed);
+    let (have, height) = match SYNC_STATE.load(Ordering::Relaxed) {
+        SYNC_STATE_SYNCED => {
+            let have = SYNC_HAVE.load(Ordering::Relaxed).max(height);
+            (have, have)
+        }
+        _ => (SYNC_HAVE.load(Ordering::Relaxed).min(height), height),
+    };
+    let percent = match height {
+        0 => 0.0,
+        height => (have as f64 * 1000.0 / height as f64).round() / 10.0,
+    };
+
+    let progress = serde_json::json!({
+        "have": have,
+        "height": height,
+        "percent": percent,
+    });
+    match env.new_string(progress.to_string()) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for sync progress: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Enable or disable removal of DNSSEC records (RRSIG, NSEC, DNSKEY...) from responses,
+/// queries with the DO bit are never stripped
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_setStripDnssecRecords(
+    _env: JNIEnv,
+    _class: JClass,
+    enabled: jboolean,
+) {
+    let enabled = enabled != 0;
+    STRIP_DNSSEC.store(enabled, Ordering::Relaxed);
+    add_log_message(format!("DNSSEC record stripping {}", if enabled { "enabled" } else { "disabled" }));
+}
+
+/// Prefer one address family for names that have both: "ipv4" answers AAAA queries of such
+/// names without addresses, "ipv6" does the same to A queries, "any" keeps all answers.
+/// Answers are only filtered and reordered, never made up. Returns false for other values.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_setAddressFamilyPreference(
+    mut env: JNIEnv,
+    _class: JClass,
+    pref: JString,
+) -> jboolean {
+    let pref: String = match env.get_string(&pref) {
+        Ok(pref) => pref.into(),
+        Err(e) => {
+            error!("Failed to get address family preference: {}", e);
+            return 0; // false
+        }
+    };
+    let family = match pref.trim().to_lowercase().as_str() {
+        "any" => FAMILY_ANY,
+        "ipv4" => FAMILY_IPV4,
+        "ipv6" => FAMILY_IPV6,
+        _ => {
+            warn!("Unknown address family preference '{}'", pref);
+            return 0; // false
+        }
+    };
+    ADDRESS_FAMILY_PREFERENCE.store(family, Ordering::Relaxed);
+    add_log_message(format!("Address family preference set to {}", family_name(family)));
+    1 // true
+}
+
+/// Enable or disable dual-stack prefetch: a query for A also resolves AAAA in the background
+/// and vice versa
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_setDualStackPrefetch(
+    _env: JNIEnv,
+    _class: JClass,
+    enabled: jboolean,
+) {
+    let enabled = enabled != 0;
+    DUAL_STACK_PREFETCH.store(enabled, Ordering::Relaxed);
+    if !enabled {
+        if let Ok(mut prefetched) = PREFETCHED.lock() {
+            prefetched.clear();
+        }
+    }
+    add_log_message(format!("Dual-stack prefetch {}", if enabled { "enabled" } else { "disabled" }));
+}
+
+/// Listen on both 127.0.0.1 and [::1] with the configured port, takes effect on the next start.
+/// Saved as `dns.dual_stack` once the config file is known.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_setDualStack(
+    _env: JNIEnv,
+    _class: JClass,
+    enabled: jboolean,
+) {
+    let enabled = enabled != 0;
+    DNS_DUAL_STACK.store(enabled, Ordering::Relaxed);
+    if let Some(config_path) = CONFIG_PATH.lock().ok().and_then(|guard| guard.clone()) {
+        if let Err(e) = write_config_value(&config_path, "dns", "dual_stack", toml::Value::Boolean(enabled)) {
+            error!("Failed to save dual stack option: {}", e);
+        }
+    }
+    add_log_message(format!("Dual-stack listening {}", if enabled { "enabled" } else { "disabled" }));
+}
+
+/// Enable or disable minimal responses, positive answers then carry no authority
+/// or additional records
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_setMinimalResponses(
+    _env: JNIEnv,
+    _class: JClass,
+    enabled: jboolean,
+) {
+    let enabled = enabled != 0;
+    MINIMAL_RESPONSES.store(enabled, Ordering::Relaxed);
+    add_log_message(format!("Minimal responses {}", if enabled { "enabled" } else { "disabled" }));
+}
+
+/// Enable or disable EDNS padding of queries sent to DoH forwarders.
+/// Padded queries also make the upstream pad its responses.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_setEdnsPadding(
+    _env: JNIEnv,
+    _class: JClass,
+    enabled: jboolean,
+) {
+    let enabled = enabled != 0;
+    EDNS_PADDING.store(enabled, Ordering::Relaxed);
+    add_log_message(format!("EDNS padding {}", if enabled { "enabled" } else { "disabled" }));
+}
+
+/// Check if an address or CIDR range is inside the Yggdrasil 200::/7 range
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_isYggdrasilAddress(
+    mut env: JNIEnv,
+    _class: JClass,
+    addr: JString,
+) -> jboolean {
+    let addr: String = match env.get_string(&addr) {
+        Ok(addr) => addr.into(),
+        Err(e) => {
+            error!("Failed to get address: {}", e);
+            return 0; // false
+        }
+    };
+
+    match parse_ip_or_cidr(&addr) {
+        // A range is only fully inside 200::/7 if its prefix is at least as long
+        Some((ip, prefix)) if prefix.map_or(true, |prefix| prefix >= 7) => is_yggdrasil(&ip) as jboolean,
+        _ => 0, // false
+    }
+}
+
+/// Check if the running chain fell back to an in-memory database, losing synced blocks on restart
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_isUsingInMemoryDb(
+    _env: JNIEnv,
+    _class: JClass,
+) -> jboolean {
+    match DNS_RUNNING.load(Ordering::Acquire) && IN_MEMORY_DB.load(Ordering::Relaxed) {
+        true => 1,  // true
+        false => 0, // false
+    }
+}
+
+/// Allow or forbid falling back to an in-memory chain when the database file can't be used.
+/// When forbidden, startDnsServer fails with a Database error instead. Applies to the next start.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_setAllowInMemoryFallback(
+    _env: JNIEnv,
+    _class: JClass,
+    allow: jboolean,
+) {
+    let allow = allow != 0;
+    ALLOW_IN_MEMORY_FALLBACK.store(allow, Ordering::Relaxed);
+    add_log_message(format!("In-memory database fallback {}", if allow { "allowed" } else { "disabled" }));
+}
+
+/// Get why the last startDnsServer failed, prefixed with the failed stage
+/// (Config, Database, DNS listener or Network). Empty if it succeeded.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_getLastStartError(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let message = LAST_START_ERROR.lock().map(|e| e.clone()).unwrap_or_default();
+    match env.new_string(message) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for last start error: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+fn set_last_start_error(message: String) {
+    if let Ok(mut last) = LAST_START_ERROR.lock() {
+        *last = message;
+    }
+}
+
+/// Get the port the DNS server listens on, which differs from the configured one when that
+/// was taken by another app. 0 if the server is not running.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_getActiveDnsPort(
+    _env: JNIEnv,
+    _class: JClass,
+) -> jint {
+    if !DNS_RUNNING.load(Ordering::Acquire) {
+        return 0;
+    }
+    ACTIVE_DNS_ADDR.lock().ok().and_then(|addr| addr.map(|addr| addr.port() as jint)).unwrap_or(0)
+}
+
+/// Get the address the DNS server is bound to, like "[::1]:5353", for routing queries
+/// to the real port. Empty if the server is not running.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_getActiveDnsAddress(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let addr = match DNS_RUNNING.load(Ordering::Acquire) {
+        true => ACTIVE_DNS_ADDR.lock().ok().and_then(|addr| *addr).map(|addr| addr.to_string()).unwrap_or_default(),
+        false => String::new(),
+    };
+    match env.new_string(addr) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for active DNS address: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Additionally serve DNS on a Unix domain socket (length-prefixed messages like TCP).
+/// The DNS server must be running, the socket is closed by stopDnsServer.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_startDnsServerUnix(
+    mut env: JNIEnv,
+    _class: JClass,
+    socket_path: JString,
+) -> jboolean {
+    let socket_path: String = match env.get_string(&socket_path) {
+        Ok(path) => path.into(),
+        Err(e) => {
+            error!("Failed to get socket path: {}", e);
+            return 0; // false
+        }
+    };
+
+    let server_context = match running_server_context() {
+        Some(server_context) => server_context,
+        None => {
+            warn!("Cannot start Unix socket server: DNS server is not running");
+            return 0; // false
+        }
+    };
+
+    let mut unix_handle = match DNS_UNIX_HANDLE.lock() {
+        Ok(guard) => guard,
+        Err(_) => return 0, // false
+    };
+    if let Some((path, _)) = unix_handle.as_ref() {
+        warn!("Unix socket server is already listening on {}", path);
+        return (path == &socket_path) as jboolean;
+    }
+
+    start_unix_listener(server_context, &socket_path, &mut unix_handle) as jboolean
+}
+
+/// Set the memory budget of the console log buffer in bytes (at least 4 KB)
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_setLogBufferMaxBytes(
+    _env: JNIEnv,
+    _class: JClass,
+    bytes: jint,
+) -> jboolean {
+    if bytes <= 0 {
+        return 0; // false
+    }
+    let bytes = (bytes as usize).max(MIN_LOG_BUFFER_MAX_BYTES);
+    LOG_BUFFER_MAX_BYTES.store(bytes, Ordering::Relaxed);
+
+    if let Ok(mut buffer_guard) = LOG_BUFFER.lock() {
+        trim_log_buffer(&mut buffer_guard);
+    }
+    info!("Log buffer limit set to {} bytes", bytes);
+    1 // true
+}
+
+/// Set how many messages the console log buffer keeps (clamped to 50..5000)
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_setLogBufferSize(
+    _env: JNIEnv,
+    _class: JClass,
+    size: jint,
+) -> jboolean {
+    if size <= 0 {
+        return 0; // false
+    }
+    let size = (size as usize).clamp(MIN_LOG_BUFFER_MAX_LINES, MAX_LOG_BUFFER_MAX_LINES);
+    LOG_BUFFER_MAX_LINES.store(size, Ordering::Relaxed);
+
+    if let Ok(mut buffer_guard) = LOG_BUFFER.lock() {
+        trim_log_buffer(&mut buffer_guard);
+    }
+    info!("Log buffer size set to {} messages", size);
+    1 // true
+}
+
+/// Allow or forbid exposing query names, in cache dumps and a log line per query
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_setQueryLogging(
+    _env: JNIEnv,
+    _class: JClass,
+    enabled: jboolean,
+) {
+    let enabled = enabled != 0;
+    QUERY_LOGGING.store(enabled, Ordering::Relaxed);
+    add_log_message(format!("Query logging {}", if enabled { "enabled" } else { "disabled" }));
+}
+
+/// Get up to `limit` cached responses as JSON array string.
+/// Reveals lookup history, so it only works while query logging is enabled.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_dumpCache(
+    env: JNIEnv,
+    _class: JClass,
+    limit: jint,
+) -> jstring {
+    let dump_json = if !QUERY_LOGGING.load(Ordering::Relaxed) {
+        r#"{"error": "Query logging is disabled"}"#.to_string()
+    } else {
+        let now = Instant::now();
+        let entries = match RESPONSE_CACHE.lock() {
+            Ok(cache) => cache
+                .iter()
+                .filter(|(_, entry)| entry.expires > now)
+                .take(limit.max(0) as usize)
+                .map(|((name, qtype), entry)| {
+                    serde_json::json!({
+                        "name": name,
+                        "qtype": format!("{:?}", qtype),
+                        "ttl_remaining": entry.expires.duration_since(now).as_secs(),
+                        "records": serde_json::to_value(&entry.response.answers).unwrap_or_default(),
+                        "source": entry.source.name(),
+                    })
+                })
+                .collect::<Vec<_>>(),
+            Err(_) => Vec::new(),
+        };
+        serde_json::Value::Array(entries).to_string()
+    };
+
+    match env.new_string(dump_json) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for cache dump: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Set the largest DNS message accepted over TCP and Unix sockets (512..65535 bytes)
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_setMaxTcpMessageSize(
+    _env: JNIEnv,
+    _class: JClass,
+    bytes: jint,
+) -> jboolean {
+    if !(512..=65535).contains(&bytes) {
+        return 0; // false
+    }
+    TCP_MAX_MESSAGE_SIZE.store(bytes as usize, Ordering::Relaxed);
+    info!("Maximum TCP message size set to {}", bytes);
+    1 // true
+}
+
+/// Get the block production health of the whole network as JSON string
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_getNetworkHealth(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let health = match running_context() {
+        Some(context) => match context.lock() {
+            Ok(ctx) => network_health(&ctx.chain),
+            Err(_) => serde_json::json!({"error": "Failed to acquire context lock"}),
+        },
+        None => serde_json::json!({"error": "DNS server is not running"}),
+    };
+
+    match env.new_string(health.to_string()) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for network health: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Set the query types answered with REFUSED, e.g. ["ANY", "AXFR", "IXFR"]
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_setRefusedQueryTypes(
+    mut env: JNIEnv,
+    _class: JClass,
+    types_json: JString,
+) -> jboolean {
+    let types_json: String = match env.get_string(&types_json) {
+        Ok(types) => types.into(),
+        Err(e) => {
+            error!("Failed to get query types: {}", e);
+            return 0; // false
+        }
+    };
+
+    let names: Vec<String> = match serde_json::from_str(&types_json) {
+        Ok(names) => names,
+        Err(e) => {
+            warn!("Invalid query types list: {}", e);
+            return 0; // false
+        }
+    };
+    let mut types = HashSet::new();
+    for name in &names {
+        match parse_query_type(name) {
+            Some(qtype) => {
+                types.insert(qtype.to_num());
+            }
+            None => {
+                warn!("Unknown query type: {}", name);
+                return 0; // false
+            }
+        }
+    }
+
+    match REFUSED_QUERY_TYPES.write() {
+        Ok(mut refused) => {
+            *refused = types;
+            add_log_message(format!("Refusing query types: {}", names.join(", ")));
+            1 // true
+        }
+        Err(_) => 0, // false
+    }
+}
+
+/// Get the recently recorded panics as JSON array string
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_getRecentPanics(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let panics = match RECENT_PANICS.lock() {
+        Ok(panics) => serde_json::Value::Array(panics.iter().cloned().collect()),
+        Err(_) => serde_json::Value::Array(Vec::new()),
+    };
+
+    match env.new_string(panics.to_string()) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for panics: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Forget the recorded panics
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_clearRecentPanics(
+    _env: JNIEnv,
+    _class: JClass,
+) {
+    if let Ok(mut panics) = RECENT_PANICS.lock() {
+        panics.clear();
+    }
+}
+
+/// Set the range of UDP worker threads, the pool grows under load and shrinks when idle
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_setAdaptiveThreads(
+    _env: JNIEnv,
+    _class: JClass,
+    min: jint,
+    max: jint,
+) -> jboolean {
+    if min < 1 || max < min || max as usize > MAX_WORKER_THREADS {
+        warn!("Rejected worker thread range {}..{}", min, max);
+        return 0; // false
+    }
+    ADAPTIVE_MIN_THREADS.store(min as usize, Ordering::Relaxed);
+    ADAPTIVE_MAX_THREADS.store(max as usize, Ordering::Relaxed);
+    ADAPTIVE_THREADS_SET.store(true, Ordering::Relaxed);
+    add_log_message(format!("DNS worker threads: {} to {}", min, max));
+    1 // true
+}
+
+/// Check if a peer accepts connections, without adding it to the peer list, as JSON string of
+/// {reachable, latency_ms, error}. Only the TCP connect is done: the handshake is private to
+/// the core p2p layer, so the protocol version and height of the peer can't be asked for.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_probePeer(
+    mut env: JNIEnv,
+    _class: JClass,
+    address: JString,
+) -> jstring {
+    let address: String = match env.get_string(&address) {
+        Ok(address) => address.into(),
+        Err(e) => {
+            error!("Failed to get peer address: {}", e);
+            return std::ptr::null_mut();
+        }
+    };
+
+    let result = probe_peer(&address);
+
+    match env.new_string(result.to_string()) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for peer probe: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Get the full history of a domain as JSON array string, oldest first
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_getDomainHistory(
+    mut env: JNIEnv,
+    _class: JClass,
+    name: JString,
+) -> jstring {
+    let name: String = match env.get_string(&name) {
+        Ok(name) => name.into(),
+        Err(e) => {
+            error!("Failed to get domain name: {}", e);
+            return std::ptr::null_mut();
+        }
+    };
+    let name = name.trim().trim_end_matches('.').to_lowercase();
+
+    // The latest transaction gives the identity hash all versions of the domain share
+    let identity = running_context().and_then(|context| {
+        context
+            .lock()
+            .ok()
+            .and_then(|ctx| ctx.chain.get_domain_transaction(&name))
+            .map(|transaction| transaction.identity)
+    });
+
+    let history = match (identity, chain_db_path()) {
+        (Some(identity), Some(db_path)) => match domain_history(&db_path, identity.as_slice()) {
+            Ok(history) => serde_json::Value::Array(history),
+            Err(e) => {
+                error!("Failed to read history of {}: {}", name, e);
+                serde_json::json!({"error": format!("Failed to read history: {}", e)})
+            }
+        },
+        (Some(_), None) => serde_json::json!({"error": "History is not available for an in-memory chain"}),
+        (None, _) => serde_json::Value::Array(Vec::new()),
+    };
+
+    match env.new_string(history.to_string()) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for domain history: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Look up any registered domain for the explorer, as JSON string of
+/// {name, owner_pubkey, records, blockHeight, timestamp, expiry}, or null if it isn't registered.
+/// blockHeight, timestamp and expiry are null for an in-memory chain. No keystore is needed.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_getDomainInfo(
+    mut env: JNIEnv,
+    _class: JClass,
+    name: JString,
+) -> jstring {
+    let name: String = match env.get_string(&name) {
+        Ok(name) => name.into(),
+        Err(e) => {
+            error!("Failed to get domain name: {}", e);
+            return std::ptr::null_mut();
+        }
+    };
+    let name = name.trim().trim_end_matches('.').to_lowercase();
+
+    // Only the transaction is read under the lock, the block goes to the database directly
+    let transaction = running_context().map(|context| {
+        context
+            .lock()
+            .ok()
+            .and_then(|ctx| ctx.chain.get_domain_transaction(&name))
+    });
+    let info = match transaction {
+        None => serde_json::json!({"error": "DNS server is not running"}),
+        Some(None) => serde_json::Value::Null,
+        Some(Some(transaction)) => {
+            let last_block = match chain_db_path() {
+                Some(db_path) => domain_last_block(&db_path, transaction.identity.as_slice()).unwrap_or_else(|e| {
+                    warn!("Failed to read last block of {}: {}", name, e);
+                    None
+                }),
+                None => None,
+            };
+            let records = serde_json::from_str::<serde_json::Value>(&transaction.data)
+                .ok()
+                .and_then(|data| data.get("records").cloned())
+                .unwrap_or_else(|| serde_json::Value::Array(Vec::new()));
+            serde_json::json!({
+                "name": name,
+                "owner_pubkey": transaction.signing.to_string(),
+                "records": records,
+                "blockHeight": last_block.map(|(height, _)| height),
+                "timestamp": last_block.map(|(_, timestamp)| timestamp),
+                "expiry": last_block.map(|(_, timestamp)| timestamp + DOMAIN_LIFETIME),
+            })
+        }
+    };
+
+    match env.new_string(info.to_string()) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for domain info: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Get the records of one type a domain publishes in the chain, as JSON array string.
+/// Nothing is forwarded, the array is empty if the domain has no such records and the result
+/// is null if the domain isn't registered. ANY returns all records.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_lookupRecords(
+    mut env: JNIEnv,
+    _class: JClass,
+    name: JString,
+    qtype: JString,
+) -> jstring {
+    let name: String = match env.get_string(&name) {
+        Ok(name) => name.into(),
+        Err(e) => {
+            error!("Failed to get domain name: {}", e);
+            return std::ptr::null_mut();
+        }
+    };
+    let qtype: String = match env.get_string(&qtype) {
+        Ok(qtype) => qtype.into(),
+        Err(e) => {
+            error!("Failed to get record type: {}", e);
+            return std::ptr::null_mut();
+        }
+    };
+    let name = name.trim().trim_end_matches('.').to_lowercase();
+
+    let transaction = running_context().map(|context| {
+        context
+            .lock()
+            .ok()
+            .and_then(|ctx| ctx.chain.get_domain_transaction(&name))
+    });
+    let records = match (parse_query_type(&qtype), transaction) {
+        (None, _) => serde_json::json!({"error": format!("Unknown record type '{}'", qtype)}),
+        (_, None) => serde_json::json!({"error": "DNS server is not running"}),
+        (_, Some(None)) => serde_json::Value::Null,
+        (Some(qtype), Some(Some(transaction))) => {
+            let records = serde_json::from_str::<serde_json::Value>(&transaction.data)
+                .ok()
+                .and_then(|data| data.get("records").and_then(|r| r.as_array()).cloned())
+                .unwrap_or_default();
+            let wanted = qtype.to_num();
+            let matching: Vec<serde_json::Value> = records
+                .into_iter()
+                .filter(|record| {
+                    wanted == 255
+                        || record["type"].as_str().and_then(parse_query_type).map(|t| t.to_num()) == Some(wanted)
+                })
+                .collect();
+            serde_json::Value::Array(matching)
+        }
+    };
+
+    match env.new_string(records.to_string()) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for domain records: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Create a new key and queue transfers of all domains of the active key to it.
+///
+/// The transfers are ordinary domain blocks signed by the old key, so they only take
+/// effect after they are mined and propagated. The old key stays active until then;
+/// the new one is saved to the work directory and can be loaded once the transfers
+/// are confirmed.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_rotateKeystore(
+    mut env: JNIEnv,
+    _class: JClass,
+    new_password: JString,
+) -> jstring {
+    let new_password: String = match env.get_string(&new_password) {
+        Ok(password) => password.into(),
+        Err(e) => {
+            error!("Failed to get keystore password: {}", e);
+            return std::ptr::null_mut();
+        }
+    };
+
+    let result = match running_context() {
+        Some(context) => match context.lock() {
+            Ok(ctx) => rotate_keystore(&ctx, &new_password),
+            Err(_) => serde_json::json!({"error": "Failed to acquire context lock"}),
+        },
+        None => serde_json::json!({"error": "DNS server is not running"}),
+    };
+
+    match env.new_string(result.to_string()) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for key rotation: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Set the device DNS servers (JSON array of addresses) used as the last resort
+/// when all configured forwarders fail, an empty array removes them
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_setSystemDnsFallback(
+    mut env: JNIEnv,
+    _class: JClass,
+    servers_json: JString,
+) -> jboolean {
+    let servers_json: String = match env.get_string(&servers_json) {
+        Ok(s) => s.into(),
+        Err(e) => {
+            error!("Failed to get system DNS servers from Java: {}", e);
+            return 0; // false
+        }
+    };
+    let servers: Vec<String> = match serde_json::from_str(&servers_json) {
+        Ok(servers) => servers,
+        Err(e) => {
+            error!("Invalid system DNS servers list: {}", e);
+            return 0; // false
+        }
+    };
+    // Only plain addresses, the fallback must not depend on name resolution
+    let servers: Vec<String> = servers
+        .into_iter()
+        .filter_map(|server| match parse_upstream_addr(&server) {
+            Ok(addr) => Some(addr.to_string()),
+            Err(e) => {
+                warn!("Skipping system DNS server: {}", e);
+                None
+            }
+        })
+        .collect();
+
+    add_log_message(format!("System DNS fallback set to {} servers", servers.len()));
+    if let Ok(mut fallback) = SYSTEM_DNS_FALLBACK.write() {
+        *fallback = servers;
+    }
+    1 // true
+}
+
+/// Get query counters and average latency of every forwarder as JSON array string
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_getForwarderStats(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    match env.new_string(forwarder_stats().to_string()) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for forwarder stats: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Queue a heavy task ("vacuum", "integrity_check") to run once the device is idle and charging,
+/// but not earlier than after_secs from now
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_scheduleMaintenance(
+    mut env: JNIEnv,
+    _class: JClass,
+    task: JString,
+    after_secs: jint,
+) -> jboolean {
+    let task: String = match env.get_string(&task) {
+        Ok(s) => s.into(),
+        Err(e) => {
+            error!("Failed to get maintenance task from Java: {}", e);
+            return 0; // false
+        }
+    };
+
+    let after = Duration::from_secs(after_secs.max(0) as u64);
+    if schedule_maintenance(&task, after) {
+        add_log_message(format!("Maintenance task '{}' scheduled", task));
+        1 // true
+    } else {
+        add_log_message(format!("Unknown maintenance task '{}'", task));
+        0 // false
+    }
+}
+
+/// Tell the native side about device power state, maintenance runs only when idle and charging
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_setDeviceState(
+    _env: JNIEnv,
+    _class: JClass,
+    idle: jboolean,
+    charging: jboolean,
+) {
+    if let Ok(mut state) = MAINTENANCE.lock() {
+        state.device_idle = idle != 0;
+        state.charging = charging != 0;
+    }
+    MAINTENANCE_WAKE.notify_all();
+}
+
+/// Get a short overview of the node state as JSON string, including pending maintenance
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_healthCheck(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    match env.new_string(health_status().to_string()) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for health check: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Truncate a damaged chain database back to the last valid block, so sync resumes from there.
+/// Works only while the node is stopped. Returns JSON string with repaired_to_height,
+/// blocks_discarded, error and recommend_reset.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_repairDatabase(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let db_path = chain_db_path().or_else(|| {
+        WORK_DIR.lock().ok().and_then(|dir| dir.as_ref().map(|dir| format!("{}/alfis.db", dir)))
+    });
+    let report = if DNS_RUNNING.load(Ordering::Acquire) {
+        serde_json::json!({
+            "repaired_to_height": null,
+            "blocks_discarded": 0,
+            "error": "Stop the node before repairing the database",
+            "recommend_reset": false,
+        })
+    } else {
+        let result = match db_path {
+            Some(db_path) => repair_chain_db(&db_path),
+            None => Err("Chain database location is not known yet".to_string()),
+        };
+        match result {
+            Ok((height, discarded)) => {
+                if discarded > 0 {
+                    add_log_message(format!("Database repaired: {} blocks discarded, chain height is {}", discarded, height));
+                } else {
+                    add_log_message(format!("Database check found no damaged blocks, chain height is {}", height));
+                }
+                serde_json::json!({
+                    "repaired_to_height": height,
+                    "blocks_discarded": discarded,
+                    "error": null,
+                    "recommend_reset": false,
+                })
+            }
+            Err(e) => {
+                add_log_message(format!("Database repair failed: {}", e));
+                serde_json::json!({
+                    "repaired_to_height": null,
+                    "blocks_discarded": 0,
+                    "error": e,
+                    "recommend_reset": true,
+                })
+            }
+        }
+    };
+
+    match env.new_string(report.to_string()) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for repair report: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Run PRAGMA integrity_check on a chain database file, read-only. Returns JSON string
+/// of {ok, problems, error}, problems lists at most 100 findings.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_verifyDatabase(
+    mut env: JNIEnv,
+    _class: JClass,
+    path: JString,
+) -> jstring {
+    let path: String = match env.get_string(&path) {
+        Ok(path) => path.into(),
+        Err(e) => {
+            error!("Failed to get database path: {}", e);
+            return std::ptr::null_mut();
+        }
+    };
+
+    let flags = sqlite::OpenFlags::new().with_read_only();
+    let result = sqlite::Connection::open_with_flags(&path, flags).and_then(|mut db| {
+        db.set_busy_timeout(CHAIN_DB_BUSY_TIMEOUT_MS)?;
+        integrity_problems(&db, "integrity_check")
+    });
+    let report = match result {
+        Ok(problems) => {
+            match problems.is_empty() {
+                true => add_log_message(format!("Database {} passed the integrity check", path)),
+                false => add_log_message(format!("Database {} has {} integrity problems", path, problems.len())),
+            }
+            serde_json::json!({"ok": problems.is_empty(), "problems": problems, "error": null})
+        }
+        Err(e) => serde_json::json!({"ok": false, "problems": [], "error": e.to_string()}),
+    };
+
+    match env.new_string(report.to_string()) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for database check: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Get what differs from the shipped defaults as JSON string: "config" has the changed
+/// settings from the config file, "runtime" the options changed through JNI calls
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_getConfigDiff(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    match env.new_string(config_diff_report().to_string()) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for config diff: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Sign a message with the active keystore to prove ownership of the identity.
+/// Returns JSON string with hex "signature" and "pubkey", or "error" when the node is not running
+/// or no keystore is loaded.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_signMessage(
+    mut env: JNIEnv,
+    _class: JClass,
+    message: JString,
+) -> jstring {
+    let message: String = match env.get_string(&message) {
+        Ok(message) => message.into(),
+        Err(e) => {
+            error!("Failed to get message to sign: {}", e);
+            return std::ptr::null_mut();
+        }
+    };
+
+    let result = match running_context() {
+        Some(context) => match context.lock() {
+            Ok(ctx) => match ctx.get_keystore() {
+                Some(keystore) => serde_json::json!({
+                    "signature": Bytes::from_bytes(&keystore.sign(message.as_bytes())).to_string(),
+                    "pubkey": keystore.get_public().to_string(),
+                }),
+                None => serde_json::json!({"error": "No keystore loaded"}),
+            },
+            Err(_) => serde_json::json!({"error": "Failed to acquire context lock"}),
+        },
+        None => serde_json::json!({"error": "DNS server is not running"}),
+    };
+
+    match env.new_string(result.to_string()) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for signature: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Check a signature made by signMessage, public key and signature are hex strings
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_verifySignature(
+    mut env: JNIEnv,
+    _class: JClass,
+    pubkey: JString,
+    message: JString,
+    signature: JString,
+) -> jboolean {
+    let mut read = |value: &JString| -> Option<String> {
+        match env.get_string(value) {
+            Ok(value) => Some(value.into()),
+            Err(e) => {
+                error!("Failed to get signature argument from Java: {}", e);
+                None
+            }
+        }
+    };
+    let (pubkey, message, signature) = match (read(&pubkey), read(&message), read(&signature)) {
+        (Some(pubkey), Some(message), Some(signature)) => (pubkey, message, signature),
+        _ => return 0, // false
+    };
+    let (pubkey, signature) = match (decode_hex(&pubkey), decode_hex(&signature)) {
+        (Some(pubkey), Some(signature)) => (pubkey, signature),
+        _ => {
+            warn!("Public key or signature is not valid hex");
+            return 0; // false
+        }
+    };
+
+    match Keystore::check(message.as_bytes(), &pubkey, &signature) {
+        true => 1,  // true
+        false => 0, // false
+    }
+}
+
+/// Get what the resolver cache holds by category as JSON string: .alfis answers, forwarded
+/// answers, negative entries, expired entries not purged yet and approximate memory use
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_getCacheBreakdown(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let breakdown = match RESPONSE_CACHE.lock() {
+        Ok(cache) => {
+            // Expired entries are dropped lazily, only their expiry times are looked at
+            let now = Instant::now();
+            let stale = cache.iter().filter(|(_, entry)| entry.expires <= now).count();
+            serde_json::json!({
+                "entries": cache.len(),
+                "alfis": cache.alfis,
+                "forwarded": cache.forwarded,
+                "negative": cache.negative,
+                "stale": stale,
+                "approx_bytes": cache.bytes,
+            })
+        }
+        Err(_) => serde_json::json!({"error": "Failed to acquire cache lock"}),
+    };
+
+    match env.new_string(breakdown.to_string()) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for cache breakdown: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Resolve a name like a client would, through the same pipeline as the DNS listeners.
+/// Returns the answer records as JSON array string, or an object with "error".
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_resolveDomain(
+    mut env: JNIEnv,
+    _class: JClass,
+    name: JString,
+    qtype: JString,
+) -> jstring {
+    let name: String = match env.get_string(&name) {
+        Ok(name) => name.into(),
+        Err(e) => {
+            error!("Failed to get domain name: {}", e);
+            return std::ptr::null_mut();
+        }
+    };
+    let qtype: String = match env.get_string(&qtype) {
+        Ok(qtype) => qtype.into(),
+        Err(e) => {
+            error!("Failed to get query type: {}", e);
+            return std::ptr::null_mut();
+        }
+    };
+
+    let result = match resolve_for_app(&name, &qtype) {
+        Ok(response) => serde_json::to_value(&response.answers).unwrap_or_else(|_| serde_json::Value::Array(Vec::new())),
+        Err(e) => serde_json::json!({"error": e}),
+    };
+
+    match env.new_string(result.to_string()) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for resolve result: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Like resolveDomain, but returns the whole response as JSON string of
+/// {header, question, answers, authority, additional}, or an object with "error"
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_resolveDomainFull(
+    mut env: JNIEnv,
+    _class: JClass,
+    name: JString,
+    qtype: JString,
+) -> jstring {
+    let name: String = match env.get_string(&name) {
+        Ok(name) => name.into(),
+        Err(e) => {
+            error!("Failed to get domain name: {}", e);
+            return std::ptr::null_mut();
+        }
+    };
+    let qtype: String = match env.get_string(&qtype) {
+        Ok(qtype) => qtype.into(),
+        Err(e) => {
+            error!("Failed to get query type: {}", e);
+            return std::ptr::null_mut();
+        }
+    };
+
+    let result = match resolve_for_app(&name, &qtype) {
+        Ok(response) => packet_to_json(&response),
+        Err(e) => serde_json::json!({"error": e}),
+    };
+
+    match env.new_string(result.to_string()) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for resolve result: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Load the keystore file, or create and save a new one if there is none, and use it
+/// from the next start on. Returns false for a wrong password or unreadable file.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_loadOrCreateKeystore(
+    mut env: JNIEnv,
+    _class: JClass,
+    path: JString,
+    password: JString,
+) -> jboolean {
+    let path: String = match env.get_string(&path) {
+        Ok(path) => path.into(),
+        Err(e) => {
+            error!("Failed to get keystore path: {}", e);
+            return 0; // false
+        }
+    };
+    let password: String = match env.get_string(&password) {
+        Ok(password) => password.into(),
+        Err(e) => {
+            error!("Failed to get keystore password: {}", e);
+            return 0; // false
+        }
+    };
+
+    let keystore = match load_or_create_keystore(&path, &password) {
+        Ok(keystore) => keystore,
+        Err(e) => {
+            add_log_message(format!("Failed to load keystore: {}", e));
+            error!("Failed to load keystore {}: {}", path, e);
+            return 0; // false
+        }
+    };
+    add_log_message(format!("Keystore {} ready, public key {}", path, keystore.get_public()));
+    state().keystore = Some((path, keystore));
+    if DNS_RUNNING.load(Ordering::Acquire) {
+        add_log_message("The key will be used after the DNS server restarts".to_string());
+    }
+    1 // true
+}
+
+/// Back up the keystore loaded by loadOrCreateKeystore to an encrypted file that desktop Alfis
+/// and loadOrCreateKeystore on another device can open with the same password
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_exportKeystore(
+    mut env: JNIEnv,
+    _class: JClass,
+    path: JString,
+    password: JString,
+) -> jboolean {
+    let path: String = match env.get_string(&path) {
+        Ok(path) => path.into(),
+        Err(e) => {
+            error!("Failed to get keystore export path: {}", e);
+            return 0; // false
+        }
+    };
+    let password: String = match env.get_string(&password) {
+        Ok(password) => password.into(),
+        Err(e) => {
+            error!("Failed to get keystore password: {}", e);
+            return 0; // false
+        }
+    };
+
+    let keystore = match state().keystore.as_ref() {
+        Some((_, keystore)) => keystore.clone(),
+        None => {
+            add_log_message("No keystore loaded to export".to_string());
+            return 0; // false
+        }
+    };
+    match export_keystore(&keystore, &path, &password) {
+        Ok(_) => {
+            add_log_message(format!("Keystore exported to {}", path));
+            1 // true
+        }
+        Err(e) => {
+            add_log_message(format!("Failed to export keystore: {}", e));
+            error!("Failed to export keystore to {}: {}", path, e);
+            0 // false
+        }
+    }
+}
+
+/// Get the identity of the keystore loaded by loadOrCreateKeystore as JSON string of
+/// {publicKeyHex, hashHex, encryptionKeyHex, path, hasPrivateKey}, or null if none is loaded
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_getKeystoreInfo(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let info = match state().keystore.as_ref() {
+        // Keystores are always loaded with their private key, the core has no public-only ones
+        Some((path, keystore)) => serde_json::json!({
+            "publicKeyHex": keystore.get_public().to_string(),
+            "hashHex": keystore.get_hash().to_string(),
+            "encryptionKeyHex": keystore.get_encryption_public().to_string(),
+            "path": path,
+            "hasPrivateKey": true,
+        }),
+        None => serde_json::Value::Null,
+    };
+
+    match env.new_string(info.to_string()) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for keystore info: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// React to onLowMemory/onTrimMemory: shrink the cache now and use smaller UDP buffers
+/// and fewer workers until memory has been fine for a while
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_onLowMemory(
+    _env: JNIEnv,
+    _class: JClass,
+) {
+    let was_constrained = memory_constrained();
+    if let Ok(mut until) = MEMORY_PRESSURE_UNTIL.lock() {
+        *until = Some(Instant::now() + MEMORY_PRESSURE_HOLD);
+    }
+    if let Ok(mut cache) = RESPONSE_CACHE.lock() {
+        shrink_cache(&mut cache, LOW_MEMORY_CACHE_ENTRIES);
+    }
+    if let Ok(mut prefetched) = PREFETCHED.lock() {
+        prefetched.clear();
+    }
+    if !was_constrained {
+        add_log_message("Low memory: reducing DNS cache, UDP buffers and worker threads".to_string());
+        warn!("Low memory reported, resolver limits reduced");
+    }
+}
+
+/// Register a new domain with the active key. The block is queued for mining and
+/// propagation; returns JSON string with name, tx_hash, identity and queued, or error.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_registerDomain(
+    mut env: JNIEnv,
+    _class: JClass,
+    name: JString,
+    records_json: JString,
+) -> jstring {
+    let name: String = match env.get_string(&name) {
+        Ok(name) => name.into(),
+        Err(e) => {
+            error!("Failed to get domain name: {}", e);
+            return std::ptr::null_mut();
+        }
+    };
+    let records_json: String = match env.get_string(&records_json) {
+        Ok(records) => records.into(),
+        Err(e) => {
+            error!("Failed to get domain records: {}", e);
+            return std::ptr::null_mut();
+        }
+    };
+
+    let result = match running_context() {
+        Some(context) => match context.lock() {
+            Ok(ctx) => register_domain(&ctx, &name, &records_json),
+            Err(_) => serde_json::json!({"error": "Failed to acquire context lock"}),
+        },
+        None => serde_json::json!({"error": "DNS server is not running"}),
+    };
+
+    match env.new_string(result.to_string()) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for domain registration: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Verify every block of the chain: hashes, signatures and continuity. Blocks until done,
+/// call it off the main thread. Progress comes as "verify_progress" events.
+/// Returns JSON string with ok, blocks_verified, first_bad_block and error.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_verifyChainFull(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let result = match running_context() {
+        None => serde_json::json!({"ok": false, "blocks_verified": 0, "first_bad_block": null, "error": "DNS server is not running"}),
+        Some(_) if VERIFY_RUNNING.swap(true, Ordering::AcqRel) => {
+            serde_json::json!({"ok": false, "blocks_verified": 0, "first_bad_block": null, "error": "Verification is already running"})
+        }
+        Some(context) => {
+            VERIFY_CANCELLED.store(false, Ordering::Relaxed);
+            add_log_message("Full chain verification started".to_string());
+            let result = verify_chain_full(&context);
+            VERIFY_RUNNING.store(false, Ordering::Release);
+            add_log_message(format!(
+                "Full chain verification finished: {} blocks verified{}",
+                result["blocks_verified"],
+                result["error"].as_str().map(|e| format!(", {}", e)).unwrap_or_default()
+            ));
+            result
+        }
+    };
+
+    match env.new_string(result.to_string()) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for chain verification: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Stop a running verifyChainFull, it returns with error "Cancelled"
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_cancelChainVerification(
+    _env: JNIEnv,
+    _class: JClass,
+) {
+    if VERIFY_RUNNING.load(Ordering::Acquire) {
+        VERIFY_CANCELLED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Get the domains owned by the active key as JSON array string of
+/// {name, records, expiry, blockHeight}, empty when no keystore is loaded
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_getOwnedDomains(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let domains = match running_context() {
+        Some(context) => match context.lock() {
+            Ok(ctx) => owned_domains(&ctx),
+            Err(_) => Vec::new(),
+        },
+        None => Vec::new(),
+    };
+
+    match env.new_string(serde_json::Value::Array(domains).to_string()) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for owned domains: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Set the rules rewriting forwarded answers from JSON array string of
+/// {name, records: [{type, value}], ttl}, a name like "*.example.com" also covers subdomains
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_setAnswerRewrites(
+    mut env: JNIEnv,
+    _class: JClass,
+    rules_json: JString,
+) -> jboolean {
+    let rules_json: String = match env.get_string(&rules_json) {
+        Ok(s) => s.into(),
+        Err(e) => {
+            error!("Failed to get answer rewrites from Java: {}", e);
+            return 0; // false
+        }
+    };
+    let rules: Vec<serde_json::Value> = match serde_json::from_str(&rules_json) {
+        Ok(rules) => rules,
+        Err(e) => {
+            error!("Invalid answer rewrites list: {}", e);
+            return 0; // false
+        }
+    };
+    let rules = match rules.iter().map(AnswerRewrite::from_json).collect::<Result<Vec<_>, _>>() {
+        Ok(rules) => rules,
+        Err(e) => {
+            error!("Invalid answer rewrite rule: {}", e);
+            return 0; // false
+        }
+    };
+
+    add_log_message(format!("Answer rewrites set to {} rules", rules.len()));
+    if let Ok(mut rewrites) = ANSWER_REWRITES.write() {
+        *rewrites = rules;
+    }
+    // Cached answers were made with the old rules
+    cache_clear(Some(CacheSource::Forwarded));
+    1 // true
+}
+
+/// Get approximate memory use of the native layer as JSON string,
+/// process figures are null where /proc can't be read
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_getNativeMemoryStats(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    match env.new_string(native_memory_stats().to_string()) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for native memory stats: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Replace the config file with the given TOML and apply it, restarting only what the changes need.
+/// Returns JSON string with changed (dotted paths), restarted, restart_required and error.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_applyConfig(
+    mut env: JNIEnv,
+    _class: JClass,
+    config_toml: JString,
+) -> jstring {
+    let config_toml: String = match env.get_string(&config_toml) {
+        Ok(s) => s.into(),
+        Err(e) => {
+            error!("Failed to get config from Java: {}", e);
+            return std::ptr::null_mut();
+        }
+    };
+
+    let result = match apply_config(&config_toml) {
+        Ok(result) => result,
+        Err(e) => {
+            add_log_message(format!("Config not applied: {}", e));
+            serde_json::json!({"changed": [], "restarted": [], "restart_required": [], "error": e})
+        }
+    };
+    match env.new_string(result.to_string()) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for config apply result: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Turn automatic renewal of owned domains on or off.
+/// Renewals are queued for mining like registrations, so the node must keep running and stay connected.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_setAutoRenew(
+    _env: JNIEnv,
+    _class: JClass,
+    enabled: jboolean,
+    threshold_blocks: jint,
+) -> jboolean {
+    if enabled == 0 {
+        AUTO_RENEW_THRESHOLD_BLOCKS.store(0, Ordering::Relaxed);
+        add_log_message("Automatic domain renewal disabled".to_string());
+        return 1; // true
+    }
+    if threshold_blocks <= 0 {
+        return 0; // false
+    }
+
+    AUTO_RENEW_THRESHOLD_BLOCKS.store(threshold_blocks as u64, Ordering::Relaxed);
+    add_log_message(format!("Automatic renewal of domains expiring within {} blocks enabled", threshold_blocks));
+    if !AUTO_RENEW_WORKER.swap(true, Ordering::AcqRel) {
+        if let Err(e) = thread::Builder::new().name("Auto-Renew".to_string()).spawn(auto_renew_worker) {
+            AUTO_RENEW_WORKER.store(false, Ordering::Release);
+            error!("Failed to spawn auto-renew thread: {}", e);
+            return 0; // false
+        }
+    }
+    1 // true
+}
+
+/// Check whether the node stays inside the Yggdrasil network (200::/7) as JSON string of
+/// {yggdrasilOnly, configuredPeersYggdrasilOnly, connectedPeers, bootstrapPeers}. The p2p layer only
+/// reports how many peers are connected, so the check covers the configured peers, which
+/// bootstrapPeers lists with their resolved addresses. Resolves names, so don't call it on
+/// the main thread.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_getPeerNetworkInfo(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let info = peer_network_info();
+
+    match env.new_string(info.to_string()) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for peer network info: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Drop all cached responses, e.g. after switching networks. Works whether or not the server runs.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_flushDnsCache(
+    _env: JNIEnv,
+    _class: JClass,
+) -> jboolean {
+    cache_clear(None);
+    add_log_message("DNS cache flushed".to_string());
+    1 // true
+}
+
+/// Get resolver cache statistics as JSON string of {entries, hits, misses, hitRate, bytesApprox},
+/// hitRate is a percentage with one decimal
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_getCacheStats(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    match env.new_string(cache_stats().to_string()) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for cache stats: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Trace every resolution stage of names matching the pattern to the log,
+/// e.g. "example.com" or "*.example.com". An empty pattern turns tracing off.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_setQueryTrace(
+    mut env: JNIEnv,
+    _class: JClass,
+    name_pattern: JString,
+) {
+    let pattern: String = match env.get_string(&name_pattern) {
+        Ok(pattern) => pattern.into(),
+        Err(e) => {
+            error!("Failed to get trace pattern from Java: {}", e);
+            return;
+        }
+    };
+    let pattern = pattern.trim().trim_end_matches('.').to_lowercase();
+
+    match pattern.is_empty() {
+        true => add_log_message("Query trace disabled".to_string()),
+        false => add_log_message(format!("Tracing queries for {}", pattern)),
+    }
+    if let Ok(mut trace) = QUERY_TRACE.write() {
+        *trace = if pattern.is_empty() { None } else { Some(pattern) };
+    }
+}
+
+/// Write stats, health, peers, config diff, recent logs and errors to one JSON file for bug reports
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_exportDiagnosticBundle(
+    mut env: JNIEnv,
+    _class: JClass,
+    out_path: JString,
+) -> jboolean {
+    let out_path: String = match env.get_string(&out_path) {
+        Ok(path) => path.into(),
+        Err(e) => {
+            error!("Failed to get diagnostic bundle path: {}", e);
+            return 0; // false
+        }
+    };
+
+    let bundle = match serde_json::to_string_pretty(&diagnostic_bundle()) {
+        Ok(bundle) => bundle,
+        Err(e) => {
+            error!("Failed to encode diagnostic bundle: {}", e);
+            return 0; // false
+        }
+    };
+    match std::fs::write(&out_path, bundle) {
+        Ok(_) => {
+            add_log_message(format!("Diagnostic bundle written to {}", out_path));
+            1 // true
+        }
+        Err(e) => {
+            add_log_message(format!("Failed to write diagnostic bundle to {}: {}", out_path, e));
+            error!("Failed to write diagnostic bundle to {}: {}", out_path, e);
+            0 // false
+        }
+    }
+}
+
+/// Switch resolving of non-blockchain names between "recursive" and "forward" without a node restart.
+/// `forwarders_json` is an optional JSON array of upstreams for "forward", empty keeps the current ones.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_setResolveStrategy(
+    mut env: JNIEnv,
+    _class: JClass,
+    mode: JString,
+    forwarders_json: JString,
+) -> jboolean {
+    let mode: String = match env.get_string(&mode) {
+        Ok(s) => s.into(),
+        Err(e) => {
+            error!("Failed to get resolve strategy from Java: {}", e);
+            return 0; // false
+        }
+    };
+    let forwarders_json: String = match env.get_string(&forwarders_json) {
+        Ok(s) => s.into(),
+        Err(e) => {
+            error!("Failed to get forwarders from Java: {}", e);
+            return 0; // false
+        }
+    };
+
+    let forwarders = match forwarders_json.trim() {
+        "" | "null" => None,
+        json => match serde_json::from_str::<Vec<String>>(json) {
+            Ok(forwarders) => Some(forwarders.into_iter().map(|f| f.trim().to_string()).collect()),
+            Err(e) => {
+                add_log_message(format!("Invalid forwarders list: {}", e));
+                return 0; // false
+            }
+        },
+    };
+    match set_resolve_strategy(&mode.trim().to_lowercase(), forwarders) {
+        Ok(_) => 1, // true
+        Err(e) => {
+            add_log_message(format!("Resolve strategy not changed: {}", e));
+            0 // false
+        }
+    }
+}
+
+/// Get detailed statistics for the dashboard as JSON string:
+/// {uptime_secs, network_paused, sync: {state, height, network_height}, peers, queries: {udp, tcp, total}, cache, last_block_received}.
+/// state is "syncing", "synced" or "idle", last_block_received is Unix time or null.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_getDnsStatsDetailed(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    match env.new_string(dns_stats_detailed().to_string()) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for detailed stats: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Register a listener for sync and network status events, replacing the previous one.
+/// Its `onStatus(String)` gets a JSON object with "event" of "syncing" (have, height),
+/// "sync_finished" or "network_status" (blocks, peers), called from a native thread.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_registerStatusCallback(
+    env: JNIEnv,
+    _class: JClass,
+    callback: JObject,
+) -> jboolean {
+    if JAVA_VM.get().is_none() {
+        error!("JavaVM is not known, initLogging was not called");
+        return 0; // false
+    }
+    let callback = match env.new_global_ref(callback) {
+        Ok(callback) => callback,
+        Err(e) => {
+            error!("Failed to create global reference for status callback: {}", e);
+            return 0; // false
+        }
+    };
+    if let Ok(mut current) = STATUS_CALLBACK.lock() {
+        *current = Some(callback);
+    }
+    add_log_message("Status callback registered".to_string());
+    1 // true
+}
+
+/// Drop the status listener, events are no longer delivered
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_unregisterStatusCallback(
+    _env: JNIEnv,
+    _class: JClass,
+) {
+    if let Ok(mut current) = STATUS_CALLBACK.lock() {
+        if current.take().is_some() {
+            add_log_message("Status callback unregistered".to_string());
+        }
+    }
+}
+
+/// Get the version of the native library as JSON string of {core, crate, commit},
+/// commit is null when the library wasn't built from a git checkout
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_getNativeVersion(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    match env.new_string(native_version().to_string()) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for native version: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Restart only the DNS listeners with the `[dns]` settings from the config file,
+/// the P2P network keeps syncing. Use after changing the listen address or forwarders.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_restartDnsListeners(
+    _env: JNIEnv,
+    _class: JClass,
+) -> jboolean {
+    let _guard = match CONFIG_APPLY_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(_) => return 0, // false
+    };
+    let context = match running_context() {
+        Some(context) => context,
+        None => {
+            warn!("DNS server is not running");
+            return 0; // false
+        }
+    };
+    let config_settings = CONFIG_PATH.lock().ok().and_then(|guard| guard.clone()).and_then(|path| Settings::load(&path));
+    let settings = match config_settings {
+        Some(settings) => settings,
+        None => match context.lock() {
+            Ok(ctx) => ctx.settings.clone(),
+            Err(_) => return 0, // false
+        },
+    };
+
+    match restart_dns(&context, &settings) {
+        Ok(_) => 1, // true
+        Err(e) => {
+            add_log_message(e.clone());
+            error!("{}", e);
+            0 // false
+        }
+    }
+}
+
+/// Limit queries per second from one source address, 0 turns the limiter off.
+/// UDP queries over the limit are dropped and TCP connections closed, see rate_limited in stats.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_setRateLimit(
+    _env: JNIEnv,
+    _class: JClass,
+    qps: jint,
+) -> jboolean {
+    if qps < 0 {
+        warn!("Rejected negative rate limit {}", qps);
+        return 0; // false
+    }
+    RATE_LIMIT_QPS.store(qps as u32, Ordering::Relaxed);
+    if let Ok(mut limiter) = RATE_BUCKETS.lock() {
+        limiter.buckets.clear();
+    }
+    match qps {
+        0 => add_log_message("Rate limit disabled".to_string()),
+        qps => add_log_message(format!("Rate limit set to {} queries per second per source", qps)),
+    }
+    1 // true
+}
+
+/// Set how long each upstream gets to answer a forwarded query, in milliseconds.
+/// When no upstream answers in time the client gets SERVFAIL, see forward_timeouts in stats.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_setForwardTimeout(
+    _env: JNIEnv,
+    _class: JClass,
+    ms: jint,
+) -> jboolean {
+    let ms = ms as i64;
+    if ms < MIN_FORWARD_TIMEOUT_MS as i64 || ms > MAX_FORWARD_TIMEOUT_MS as i64 {
+        warn!("Rejected forward timeout of {} ms", ms);
+        return 0; // false
+    }
+    FORWARD_TIMEOUT_MS.store(ms as u64, Ordering::Relaxed);
+    add_log_message(format!("Forward timeout set to {} ms", ms));
+    1 // true
+}
+
+/// Check every configured forwarder with an A query for example.com, all at once.
+/// Returns JSON array string of {upstream, ok, latencyMs, error}. Forwarder stats are not touched.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_testForwarders(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let results = test_forwarders();
+
+    match env.new_string(results.to_string()) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for forwarder test: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Check the whole resolve path of the running server: a name from the blockchain and one
+/// from the upstreams. Returns JSON string of {blockchainResolve, forwardResolve, details}.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_selfTest(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let report = self_test();
+    add_log_message(format!("Self-test: {}", report["details"].as_str().unwrap_or_default()));
+
+    match env.new_string(report.to_string()) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for self-test: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Cap the open DNS-over-TCP connections, 0 means unlimited. Connections over the cap are
+/// closed right away, see tcp_connections_rejected in stats.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_setMaxTcpConnections(
+    _env: JNIEnv,
+    _class: JClass,
+    n: jint,
+) -> jboolean {
+    if n < 0 {
+        warn!("Rejected negative TCP connection limit {}", n);
+        return 0; // false
+    }
+    MAX_TCP_CONNECTIONS.store(n as usize, Ordering::Relaxed);
+    match n {
+        0 => add_log_message("TCP connection limit disabled".to_string()),
+        n => add_log_message(format!("TCP connections limited to {}", n)),
+    }
+    1 // true
+}
+
+/// Set how long forwarded NXDOMAIN/NODATA answers are cached, 0 turns the negative cache off.
+/// A shorter SOA minimum in the answer wins. Cached negative answers are dropped on change.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_setNegativeCacheTtl(
+    _env: JNIEnv,
+    _class: JClass,
+    secs: jint,
+) -> jboolean {
+    if secs < 0 || secs as u32 > MAX_NEGATIVE_CACHE_TTL {
+        warn!("Rejected negative cache TTL {}, allowed 0..={}", secs, MAX_NEGATIVE_CACHE_TTL);
+        return 0; // false
+    }
+    NEGATIVE_CACHE_TTL.store(secs as u32, Ordering::Relaxed);
+    if let Ok(mut cache) = RESPONSE_CACHE.lock() {
+        cache.retain(|_, entry| !entry.is_negative());
+    }
+    match secs {
+        0 => add_log_message("Negative cache disabled".to_string()),
+        secs => add_log_message(format!("Negative cache TTL set to {}s", secs)),
+    }
+    1 // true
+}
+
+/// Get the blocks waiting to be mined as JSON string of {pendingCount, transactions}, each
+/// transaction being {domain, action, pubkey, submitted}. The core keeps no mempool of its own,
+/// domain blocks wait in the queue of this library until they are mined.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_getMempoolInfo(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let transactions: Vec<serde_json::Value> = match PENDING_BLOCKS.lock() {
+        Ok(pending) => pending
+            .iter()
+            .map(|block| {
+                serde_json::json!({
+                    "domain": block.domain,
+                    "action": block.action,
+                    "pubkey": block.keystore.get_public().to_string(),
+                    "submitted": block.submitted,
+                })
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+
+    let info = serde_json::json!({
+        "pendingCount": transactions.len(),
+        "transactions": transactions,
+    });
+    match env.new_string(info.to_string()) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for mempool info: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Mine the pending domain blocks (registrations, renewals, transfers) on this device.
+/// Threads are capped at half the cores and 4, the miner runs at low priority.
+/// Mining stays on across network restarts until stopMining.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_startMining(
+    _env: JNIEnv,
+    _class: JClass,
+    threads: jint,
+) -> jboolean {
+    if threads <= 0 {
+        warn!("Rejected mining with {} threads", threads);
+        return 0; // false
+    }
+    let context = match running_context() {
+        Some(context) => context,
+        None => {
+            warn!("Cannot start mining: DNS server is not running");
+            return 0; // false
+        }
+    };
+    let threads = mining_thread_cap(threads as usize);
+    // A running miner keeps its threads, restart it with the new count
+    if MINING_THREADS.swap(threads, Ordering::Relaxed) != threads {
+        stop_miner();
+    }
+    start_miner(&context);
+    1 // true
+}
+
+/// Get the progress of the block being mined as JSON string of {active, target_difficulty,
+/// best_difficulty, attempts, hash_rate, elapsed_secs, domains}, attempts being estimated from
+/// the hash rate the miner threads report. {active: false, status: "no active job"} when idle.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_getMiningProgress(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let mut progress = match MINER_REPORTS.lock() {
+        Ok(reports) => reports.progress(),
+        Err(_) => serde_json::json!({"active": false, "status": "no active job"}),
+    };
+    if progress["active"] == true {
+        // The miner doesn't say which block it works on, only which ones it has
+        let domains: Vec<String> = PENDING_BLOCKS
+            .lock()
+            .map(|pending| pending.iter().filter(|block| block.mining).map(|block| block.domain.clone()).collect())
+            .unwrap_or_default();
+        progress["domains"] = serde_json::json!(domains);
+    }
+
+    match env.new_string(progress.to_string()) {
+        Ok(jstr) => jstr.as_raw(),
+        Err(e) => {
+            error!("Failed to create Java string for mining progress: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Stop mining, blocks that weren't mined yet stay pending
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_stopMining(
+    _env: JNIEnv,
+    _class: JClass,
+) -> jboolean {
+    if MINING_THREADS.swap(0, Ordering::Relaxed) == 0 {
+        return 0; // false
+    }
+    stop_miner();
+    1 // true
+}
+
+/// Stop P2P connections and sync to save battery or data, the DNS server keeps running
+/// and answers .alfis names from the local chain. Resume with resumeNetwork.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_pauseNetwork(
+    _env: JNIEnv,
+    _class: JClass,
+) -> jboolean {
+    match pause_network() {
+        Ok(_) => 1, // true
+        Err(e) => {
+            warn!("Network not paused: {}", e);
+            0 // false
+        }
+    }
+}
+
+/// Connect to the P2P network again after pauseNetwork
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_resumeNetwork(
+    _env: JNIEnv,
+    _class: JClass,
+) -> jboolean {
+    match resume_network() {
+        Ok(_) => 1, // true
+        Err(e) => {
+            add_log_message(format!("Network not resumed: {}", e));
+            warn!("Network not resumed: {}", e);
+            0 // false
+        }
+    }
+}
+
+/// Replace the forwarders at runtime with a JSON array of upstreams ("ip:port" or "https://" DoH URLs),
+/// e.g. the resolvers Android reports for a new network. Nothing is changed if any entry is malformed.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_setForwarders(
+    mut env: JNIEnv,
+    _class: JClass,
+    forwarders_json: JString,
+) -> jboolean {
+    let forwarders_json: String = match env.get_string(&forwarders_json) {
+        Ok(s) => s.into(),
+        Err(e) => {
+            error!("Failed to get forwarders from Java: {}", e);
+            return 0; // false
+        }
+    };
+
+    let forwarders = match serde_json::from_str::<Vec<String>>(&forwarders_json) {
+        Ok(forwarders) => forwarders.into_iter().map(|f| f.trim().to_string()).collect(),
+        Err(e) => {
+            add_log_message(format!("Invalid forwarders list: {}", e));
+            return 0; // false
+        }
+    };
+    match replace_forwarders(forwarders) {
+        Ok(_) => 1, // true
+        Err(e) => {
+            add_log_message(format!("Forwarders not changed: {}", e));
+            0 // false
+        }
+    }
+}
+
+/// Enable or disable the DNS-over-HTTPS server on https://127.0.0.1:8443/dns-query for apps
+/// that only speak DoH. It uses a self-signed certificate saved as doh-cert.pem in the work
+/// directory, generated on first use, which the apps have to trust.
+/// While the DNS server is stopped this only takes effect on the next start.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_setDohEnabled(
+    _env: JNIEnv,
+    _class: JClass,
+    enabled: jboolean,
+) -> jboolean {
+    let enabled = enabled != 0;
+    DOH_ENABLED.store(enabled, Ordering::Relaxed);
+    if !enabled {
+        // The listener notices the flag by itself
+        stop_doh_listener();
+        add_log_message("DoH server disabled".to_string());
+        return 1; // true
+    }
+    match running_server_context() {
+        Some(server_context) => start_doh_listener(&server_context) as jboolean,
+        None => {
+            add_log_message("DoH server will start with the DNS server".to_string());
+            1 // true
+        }
+    }
+}
+
+/// Load a blocklist in hosts file format, names on it are answered with NXDOMAIN before
+/// any cache or upstream lookup. Replaces the previous list, an empty file clears it.
+/// Returns the number of names loaded, or -1 if the file couldn't be read.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_loadBlocklist(
+    mut env: JNIEnv,
+    _class: JClass,
+    path: JString,
+) -> jlong {
+    let path: String = match env.get_string(&path) {
+        Ok(path) => path.into(),
+        Err(e) => {
+            error!("Failed to get blocklist path from Java: {}", e);
+            return -1;
+        }
+    };
+
+    let names = match std::fs::read_to_string(&path) {
+        Ok(text) => parse_hosts_list(&text),
+        Err(e) => {
+            add_log_message(format!("Failed to read blocklist {}: {}", path, e));
+            return -1;
+        }
+    };
+    let count = names.len();
+    if let Ok(mut blocklist) = BLOCKLIST.write() {
+        *blocklist = names;
+    }
+    // Answers cached before may be for names that are blocked now
+    cache_clear(None);
+    add_log_message(format!("Blocklist loaded: {} names", count));
+    count as jlong
+}
+
+/// Pin a name to an address on this device, answered as A or AAAA by the address family
+/// before the blocklist, the chain and upstreams. Replaces an earlier override of the name.
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_setLocalOverride(
+    mut env: JNIEnv,
+    _class: JClass,
+    name: JString,
+    ip: JString,
+) -> jboolean {
+    let name: String = match env.get_string(&name) {
+        Ok(name) => name.into(),
+        Err(e) => {
+            error!("Failed to get override name: {}", e);
+            return 0; // false
+        }
+    };
+    let ip: String = match env.get_string(&ip) {
+        Ok(ip) => ip.into(),
+        Err(e) => {
+            error!("Failed to get override address: {}", e);
+            return 0; // false
+        }
+    };
+
+    let name = name.trim().trim_end_matches('.').to_lowercase();
+    let addr = match ip.trim().parse::<IpAddr>() {
+        Ok(addr) if is_valid_domain_name(&name) => addr,
+        _ => {
+            warn!("Rejected local override {} -> {}", name, ip);
+            return 0; // false
+        }
+    };
+    if let Ok(mut overrides) = LOCAL_OVERRIDES.write() {
+        overrides.insert(name.clone(), addr);
+    }
+    add_log_message(format!("Local override: {} -> {}", name, addr));
+    1 // true
+}
+
+/// Remove the override of a name, returns false if it had none
+#[no_mangle]
+pub extern "C" fn Java_name_alfis_android_native_AlfisNative_clearLocalOverride(
+    mut env: JNIEnv,
+    _class: JClass,
+    name: JString,
+) -> jboolean {
+    let name: String = match env.get_string(&name) {
+        Ok(name) => name.into(),
+        Err(e) => {
+            error!("Failed to get override name: {}", e);
+            return 0; // false
+        }
+    };
+
+    let name = name.trim().trim_end_matches('.').to_lowercase();
+    let removed = LOCAL_OVERRIDES.write().map(|mut overrides| overrides.remove(&name).is_some()).unwrap_or(false);
+    if !removed {
+        return 0; // false
+    }
+    add_log_message(format!("Local override of {} removed", name));
+    1 // true
+}
+
+// Internal implementation functions
+
+fn start_dns_server_internal(
+    config_path: &str,
+    _work_dir: &str,
+    _log_file: &str,
+) -> Result<(Arc<Mutex<Context>>, Arc<ServerContext>), Box<dyn std::error::Error>> {
+    add_log_message("Alfis DNS resolver starting...".to_string());
+    add_log_message(format!("Loading configuration from {}", config_path));
+    info!("Starting DNS server with config: {}", config_path);
+
+    // Load settings with error handling
+    let mut settings = match Settings::load(config_path) {
+        Some(s) => s,
+        None => {
+            add_log_message("Configuration not found, generating defaults".to_string());
+            warn!("Failed to load settings from {}, generating default config", config_path);
+            generate_android_config(config_path).map_err(|e| format!("Config error: failed to write {}: {}", config_path, e))?;
+            add_log_message("Default configuration created".to_string());
+            Settings::load(config_path).ok_or("Config error: failed to load generated settings")?
+        }
+    };
+    
+    // Debug: Log the DNS listen address from config
+    add_log_message(format!("Loaded DNS listen address from config: {}", settings.dns.listen));
+
+    load_android_options(config_path);
+    
+    // Override settings for Android
+    // Note: DNS listen address is taken from config file, no override here
+    if settings.dns.threads == 0 {
+        settings.dns.threads = 8; // Increased for better performance
+    }
+    
+    // Initialize context with better error handling
+    // The key loaded by loadOrCreateKeystore survives restarts, otherwise a throwaway one is used
+    let keystore = match state().keystore.clone() {
+        Some((path, keystore)) => {
+            add_log_message(format!("Using keystore {}", path));
+            keystore
+        }
+        None => Keystore::new(),
+    };
+    let keystores = vec![keystore];
+    
+    // Initialize chain with better Android-specific error handling
+    if let Ok(mut guard) = WORK_DIR.lock() {
+        *guard = Some(_work_dir.to_string());
+    }
+    let db_path = format!("{}/alfis.db", _work_dir);
+    add_log_message("Initializing blockchain database...".to_string());
+    info!("Initializing blockchain database at: {}", db_path);
+    
+    // Ensure the directory exists
+    if let Some(parent) = std::path::Path::new(&db_path).parent() {
+        if let Err(e) = std::fs::create_dir_all(parent) {
+            warn!("Could not create database directory: {}", e);
+        }
+    }
+    
+    let chain = match create_chain_safely(&settings, &db_path) {
+        Ok(chain) => {
+            set_chain_db_path(Some(&db_path));
+            IN_MEMORY_DB.store(false, Ordering::Relaxed);
+            chain
+        }
+        Err(e) if !ALLOW_IN_MEMORY_FALLBACK.load(Ordering::Relaxed) => {
+            add_log_message(format!("Error: Blockchain database {} failed and in-memory fallback is disabled: {}", db_path, e));
+            error!("Failed to create file-based database ({}), in-memory fallback is disabled", e);
+            return Err(format!("Database error: {} failed: {}", db_path, e).into());
+        }
+        Err(e) => {
+            warn!("Failed to create file-based database ({}), falling back to in-memory database", e);
+            // Fallback to in-memory database for Android compatibility
+            match create_chain_safely(&settings, ":memory:") {
+                Ok(chain) => {
+                    set_chain_db_path(None);
+                    IN_MEMORY_DB.store(true, Ordering::Relaxed);
+                    add_log_message(format!("WARNING: Blockchain database {} failed ({}), using in-memory mode. Synced blocks will be lost on restart!", db_path, e));
+                    info!("Successfully created in-memory blockchain database");
+                    chain
+                }
+                Err(e) => {
+                    add_log_message(format!("Error: Blockchain initialization failed: {}", e));
+                    error!("Even in-memory database failed: {}", e);
+                    return Err(format!("Database error: blockchain initialization failed: {}", e).into());
+                }
+            }
+        }
+    };
+    
+    let context = Arc::new(Mutex::new(Context::new(ALFIS_CORE_VERSION.to_owned(), settings.clone(), keystores, chain)));
+    
+    // Start the real DNS server with statistics tracking
+    add_log_message("Starting DNS servers...".to_string());
+    info!("Starting DNS servers...");
+    let server_context = start_dns_server_with_context(&context, &settings)
+        .map_err(|e| format!("DNS listener error on {}: {}", settings.dns.listen, e))?;
+
+    // Start the P2P network to connect to peers and sync blocks
+    add_log_message("Starting P2P network...".to_string());
+    info!("Starting P2P network...");
+    start_network_with_context(Arc::clone(&context)).map_err(|e| format!("Network error: {}", e))?;
+
+    info!("DNS server and network started successfully");
+    Ok((context, server_context))
+}
+
+fn generate_android_config(config_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    std::fs::write(config_path, DEFAULT_ANDROID_CONFIG)?;
+    Ok(())
+}
+
+/// The default config for another network: origin, check_blocks and, if given, the bootstrap peers
+/// replaced. Works on the text, so the comments of the template stay.
+fn generate_config_with_params(config_path: &str, origin: &str, check_blocks: u64, peers: Option<&[String]>) -> Result<(), Box<dyn std::error::Error>> {
+    if origin.len() != 64 || !origin.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("Origin must be 64 hex characters, got '{}'", origin).into());
+    }
+    if let Some(invalid) = peers.and_then(|peers| peers.iter().find(|peer| !is_valid_peer_address(peer))) {
+        return Err(format!("Invalid peer address '{}'", invalid).into());
+    }
+
+    let origin = toml::Value::String(origin.to_uppercase());
+    let config: Vec<String> = DEFAULT_ANDROID_CONFIG
+        .lines()
+        .map(|line| {
+            if line.starts_with("origin = ") {
+                format!("origin = {}", origin)
+            } else if line.starts_with("check_blocks = ") {
+                format!("check_blocks = {}", check_blocks)
+            } else if let (true, Some(peers)) = (line.starts_with("peers = "), peers) {
+                let peers: toml::Value = peers.iter().cloned().map(toml::Value::String).collect::<Vec<_>>().into();
+                format!("peers = {}", peers)
+            } else {
+                line.to_string()
+            }
+        })
+        .collect();
+    // Must still be a config the core accepts
+    toml::from_str::<Settings>(&config.join("\n"))?;
+    std::fs::write(config_path, config.join("\n") + "\n")?;
+    Ok(())
+}
+
+/// Config written on first start, also the reference for getConfigDiff
+const DEFAULT_ANDROID_CONFIG: &str = r#"# Alfis Android Configuration
+# The hash of first block in a chain to know with which nodes to work
+origin = "0000001D2A77D63477172678502E51DE7F346061FF7EB188A2445ECA3FC0780E"
+# Key files (empty for mobile)
+key_files = []
+# Reduced block checking for mobile
+check_blocks = 4
+
+# Network settings
+[net]
+# Bootstrap nodes
+peers = ["peer-v4.alfis.name:4244", "peer-v6.alfis.name:4244"]
+# Listen address (Android may restrict this)
+listen = "127.0.0.1:42440"
+# Mobile devices shouldn't be public peers
+public = false
+yggdrasil_only = true
+
+# DNS resolver options
+[dns]
+# Listen on localhost IPv6 (non-privileged port)
+listen = "[::1]:5353"
+# Increased threads for better performance
+threads = 8
+# Use DoH when available, fallback to regular DNS
+forwarders = ["https://dns.adguard.com/dns-query", "8.8.8.8:53"]
+bootstraps = ["8.8.8.8:53", "1.1.1.1:53"]
+
+# Mining disabled on mobile, startMining turns it on for a session
+[mining]
+threads = 0
+lower = true
+"#;
+
+/// Compare the config file with the shipped defaults, only changed fields are returned
+/// as {"dotted.path": {"default": .., "current": ..}}
+fn config_diff(config_path: &str) -> Result<serde_json::Map<String, serde_json::Value>, String> {
+    let defaults: Settings = toml::from_str(DEFAULT_ANDROID_CONFIG).map_err(|e| e.to_string())?;
+    let current = Settings::load(config_path).ok_or("Failed to load settings")?;
+    let defaults = serde_json::to_value(&defaults).map_err(|e| e.to_string())?;
+    let current = serde_json::to_value(&current).map_err(|e| e.to_string())?;
+
+    let mut changes = serde_json::Map::new();
+    collect_changes("", &defaults, &current, &mut changes);
+    Ok(changes)
+}
+
+fn collect_changes(path: &str, default: &serde_json::Value, current: &serde_json::Value, changes: &mut serde_json::Map<String, serde_json::Value>) {
+    use serde_json::Value;
+
+    if let (Value::Object(default), Value::Object(current)) = (default, current) {
+        let keys: std::collections::BTreeSet<&String> = default.keys().chain(current.keys()).collect();
+        for key in keys {
+            let path = if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) };
+            collect_changes(&path, default.get(key).unwrap_or(&Value::Null), current.get(key).unwrap_or(&Value::Null), changes);
+        }
+    } else if default != current {
+        changes.insert(path.to_string(), serde_json::json!({"default": default, "current": current}));
+    }
+}
+
+/// Validate and write a new config, then apply it to the running node:
+/// `[dns]` changes restart the DNS listeners only.
+/// Other changes need the network to be restarted, which is left to the app (restart_required).
+fn apply_config(config_toml: &str) -> Result<serde_json::Value, String> {
+    let _guard = CONFIG_APPLY_LOCK.lock().map_err(|_| "Config lock poisoned")?;
+    let config_path = CONFIG_PATH.lock().ok().and_then(|guard| guard.clone()).ok_or("Config file is not known yet")?;
+    let new_settings: Settings = toml::from_str(config_toml).map_err(|e| format!("Invalid config: {}", e))?;
+
+    let mut changes = serde_json::Map::new();
+    match Settings::load(&config_path) {
+        Some(old_settings) => {
+            let old = serde_json::to_value(&old_settings).map_err(|e| e.to_string())?;
+            let new = serde_json::to_value(&new_settings).map_err(|e| e.to_string())?;
+            collect_changes("", &old, &new, &mut changes);
+        }
+        None => {
+            changes.insert("*".to_string(), serde_json::Value::Null);
+        }
+    }
+    // Settings skips dual_stack, compare it here so a change restarts the listeners like dns.listen
+    let old_dual_stack = read_config_value(&config_path, "dns", "dual_stack").and_then(|v| v.as_bool()).unwrap_or(false);
+    let new_dual_stack = toml::from_str::<toml::Table>(config_toml)
+        .ok()
+        .and_then(|table| table.get("dns")?.get("dual_stack")?.as_bool())
+        .unwrap_or(false);
+    if old_dual_stack != new_dual_stack {
+        changes.insert("dns.dual_stack".to_string(), serde_json::json!({"default": old_dual_stack, "current": new_dual_stack}));
+    }
+    let changed: Vec<String> = changes.keys().cloned().collect();
+    if changed.is_empty() {
+        return Ok(serde_json::json!({"changed": [], "restarted": [], "restart_required": []}));
+    }
+
+    // Write next to the file and rename, a crash mid-write must not leave a broken config
+    let temp_path = format!("{}.tmp", config_path);
+    std::fs::write(&temp_path, config_toml).map_err(|e| format!("Failed to write config: {}", e))?;
+    std::fs::rename(&temp_path, &config_path).map_err(|e| format!("Failed to replace config: {}", e))?;
+    add_log_message(format!("Config updated, changed: {}", changed.join(", ")));
+    DNS_DUAL_STACK.store(new_dual_stack, Ordering::Relaxed);
+
+    let mut restarted = Vec::new();
+    let mut restart_required = Vec::new();
+    let needs_network = changed.iter().any(|path| !path.starts_with("dns."));
+    let needs_dns = changed.iter().any(|path| path.starts_with("dns.") || path == "*");
+    if DNS_RUNNING.load(Ordering::Acquire) {
+        if needs_dns {
+            let context = running_context().ok_or("Node is stopping")?;
+            restart_dns(&context, &new_settings)?;
+            restarted.push("dns");
+        }
+        if needs_network {
+            restart_required.push("network");
+        }
+    }
+
+    Ok(serde_json::json!({
+        "changed": changed,
+        "restarted": restarted,
+        "restart_required": restart_required,
+    }))
+}
+
+/// Switch between recursion and forwarding by restarting the DNS listeners with new forwarders.
+/// Forwarding without a list keeps the current forwarders. The config file is not changed.
+fn set_resolve_strategy(mode: &str, forwarders: Option<Vec<String>>) -> Result<(), String> {
+    let _guard = CONFIG_APPLY_LOCK.lock().map_err(|_| "Config lock poisoned")?;
+    if !DNS_RUNNING.load(Ordering::Acquire) {
+        return Err("DNS server is not running".to_string());
+    }
+    let context = running_context().ok_or("Node is stopping")?;
+    let mut settings = context.lock().map_err(|_| "Context lock poisoned")?.settings.clone();
+    settings.dns.forwarders = match mode {
+        "recursive" => Vec::new(),
+        "forward" => {
+            let forwarders = forwarders.unwrap_or_else(|| settings.dns.forwarders.clone());
+            if forwarders.is_empty() {
+                return Err("Forwarding needs at least one upstream".to_string());
+            }
+            if let Some(invalid) = forwarders.iter().find(|upstream| !is_valid_upstream(upstream)) {
+                return Err(format!("Invalid upstream address '{}'", invalid));
+            }
+            forwarders
+        }
+        _ => return Err(format!("Unknown resolve strategy '{}'", mode)),
+    };
+    restart_dns(&context, &settings)?;
+    add_log_message(format!("Resolve strategy set to {} ({} upstreams)", mode, settings.dns.forwarders.len()));
+    Ok(())
+}
+
+/// Replace the upstreams of forwarding, e.g. with the resolvers of a new network.
+/// While resolving recursively the listeners are restarted to switch to forwarding.
+fn replace_forwarders(forwarders: Vec<String>) -> Result<(), String> {
+    use alfis::dns::context::ResolveStrategy;
+
+    if forwarders.is_empty() {
+        return Err("The forwarder list is empty".to_string());
+    }
+    if let Some(invalid) = forwarders.iter().find(|upstream| !is_valid_upstream(upstream)) {
+        return Err(format!("Invalid upstream address '{}'", invalid));
+    }
+    let server_context = running_server_context().ok_or("DNS server is not running")?;
+    if !matches!(server_context.resolve_strategy, ResolveStrategy::Forward { .. }) {
+        return set_resolve_strategy("forward", Some(forwarders));
+    }
+
+    // Queries read the list on every forward, nothing needs a restart
+    if let Some(context) = running_context() {
+        if let Ok(mut ctx) = context.lock() {
+            ctx.settings.dns.forwarders = forwarders.clone();
+        }
+    }
+    add_log_message(format!("Forwarders set to {}", forwarders.join(", ")));
+    set_forwarders(forwarders);
+    Ok(())
+}
+
+/// Stop the DNS listeners and start them again with new settings, the P2P network keeps running.
+/// A Unix socket listener is started again on the same path. If the listeners can't be started
+/// the node is stopped, with the reason in getLastStartError.
+fn restart_dns(context: &Arc<Mutex<Context>>, settings: &Settings) -> Result<(), String> {
+    add_log_message("Restarting DNS listeners...".to_string());
+    DNS_SHUTDOWN_FLAG.store(true, Ordering::Relaxed);
+    UDP_POOL.wake_all();
+    TCP_POOL.wake_all();
+    let handles = {
+        let mut state = state();
+        let mut handles = std::mem::take(&mut state.dns_dual_stack_handles);
+        handles.extend(state.dns_udp_handle.take());
+        handles.extend(state.dns_tcp_handle.take());
+        handles
+    };
+    let unix_handle = DNS_UNIX_HANDLE.lock().ok().and_then(|mut guard| guard.take());
+    for handle in handles {
+        if let Err(e) = handle.join() {
+            error!("Failed to join DNS thread: {:?}", e);
+        }
+    }
+    let unix_path = unix_handle.map(|(path, handle)| {
+        let _ = handle.join();
+        path
+    });
+    stop_doh_listener();
+    DNS_SHUTDOWN_FLAG.store(false, Ordering::Relaxed);
+
+    if let Ok(mut ctx) = context.lock() {
+        ctx.settings.dns = settings.dns.clone();
+    }
+    let server_context = match start_dns_server_with_context(context, settings) {
+        Ok(server_context) => server_context,
+        Err(e) => {
+            // The old listeners are gone, stop the node instead of running it without them
+            set_last_start_error(format!("DNS listener error on {}: {}", settings.dns.listen, e));
+            if DNS_RUNNING.swap(false, Ordering::AcqRel) {
+                let network_handle = state().network_handle.take();
+                release_node(network_handle);
+            }
+            return Err(format!("Failed to restart DNS, node stopped: {}", e));
+        }
+    };
+    state().server_context = Some(Arc::clone(&server_context));
+    if let Some(path) = unix_path {
+        if let Ok(mut unix_handle) = DNS_UNIX_HANDLE.lock() {
+            start_unix_listener(server_context, &path, &mut unix_handle);
+        }
+    }
+    add_log_message("DNS listeners restarted".to_string());
+    Ok(())
+}
+
+/// Options changed at runtime through JNI calls, only the ones that differ from their defaults
+fn runtime_overrides() -> serde_json::Map<String, serde_json::Value> {
+    let refused: Vec<u16> = match REFUSED_QUERY_TYPES.read() {
+        Ok(types) => {
+            let mut types: Vec<u16> = types.iter().cloned().collect();
+            types.sort_unstable();
+            types
+        }
+        Err(_) => DEFAULT_REFUSED_QUERY_TYPES.to_vec(),
+    };
+    let fallback = SYSTEM_DNS_FALLBACK.read().map(|f| f.clone()).unwrap_or_default();
+    let rewrites = ANSWER_REWRITES.read().map(|r| r.len()).unwrap_or_default();
+    let options: Vec<(&str, serde_json::Value, serde_json::Value)> = vec![
+        ("negative_cache_ttl", NEGATIVE_CACHE_TTL.load(Ordering::Relaxed).into(), DEFAULT_NEGATIVE_CACHE_TTL.into()),
+        ("address_family_preference", family_name(ADDRESS_FAMILY_PREFERENCE.load(Ordering::Relaxed)).into(), family_name(FAMILY_ANY).into()),
+        ("strip_dnssec", STRIP_DNSSEC.load(Ordering::Relaxed).into(), false.into()),
+        ("edns_padding", EDNS_PADDING.load(Ordering::Relaxed).into(), false.into()),
+        ("minimal_responses", MINIMAL_RESPONSES.load(Ordering::Relaxed).into(), false.into()),
+        ("dual_stack_prefetch", DUAL_STACK_PREFETCH.load(Ordering::Relaxed).into(), false.into()),
+        ("dual_stack", DNS_DUAL_STACK.load(Ordering::Relaxed).into(), false.into()),
+        ("doh_server", DOH_ENABLED.load(Ordering::Relaxed).into(), false.into()),
+        ("local_overrides", LOCAL_OVERRIDES.read().map(|o| o.len()).unwrap_or_default().into(), 0.into()),
+        ("blocklist_names", BLOCKLIST.read().map(|b| b.len()).unwrap_or_default().into(), 0.into()),
+        ("query_logging", QUERY_LOGGING.load(Ordering::Relaxed).into(), false.into()),
+        ("refused_query_types", serde_json::json!(refused), serde_json::json!(DEFAULT_REFUSED_QUERY_TYPES)),
+        ("tcp_max_message_size", TCP_MAX_MESSAGE_SIZE.load(Ordering::Relaxed).into(), DEFAULT_TCP_MAX_MESSAGE_SIZE.into()),
+        ("log_buffer_max_bytes", LOG_BUFFER_MAX_BYTES.load(Ordering::Relaxed).into(), DEFAULT_LOG_BUFFER_MAX_BYTES.into()),
+        ("log_buffer_max_lines", LOG_BUFFER_MAX_LINES.load(Ordering::Relaxed).into(), DEFAULT_LOG_BUFFER_MAX_LINES.into()),
+        ("log_level", log::max_level().to_string().to_lowercase().into(), "info".into()),
+        ("adaptive_min_threads", ADAPTIVE_MIN_THREADS.load(Ordering::Relaxed).into(), 1.into()),
+        ("forward_timeout_ms", FORWARD_TIMEOUT_MS.load(Ordering::Relaxed).into(), DEFAULT_FORWARD_TIMEOUT_MS.into()),
+        ("allow_in_memory_fallback", ALLOW_IN_MEMORY_FALLBACK.load(Ordering::Relaxed).into(), true.into()),
+        ("mining_threads", MINING_THREADS.load(Ordering::Relaxed).into(), 0.into()),
+        ("max_tcp_connections", MAX_TCP_CONNECTIONS.load(Ordering::Relaxed).into(), 0.into()),
+        ("rate_limit_qps", RATE_LIMIT_QPS.load(Ordering::Relaxed).into(), 0.into()),
+        ("adaptive_max_threads", ADAPTIVE_MAX_THREADS.load(Ordering::Relaxed).into(), 8.into()),
+        ("system_dns_fallback", serde_json::json!(fallback), serde_json::json!([])),
+        ("answer_rewrites", rewrites.into(), 0.into()),
+        ("rebootstrap_interval_secs", REBOOTSTRAP_INTERVAL_SECS.load(Ordering::Relaxed).into(), 0.into()),
+        ("auto_renew_threshold_blocks", AUTO_RENEW_THRESHOLD_BLOCKS.load(Ordering::Relaxed).into(), 0.into()),
+    ];
+
+    let mut overrides = serde_json::Map::new();
+    for (name, current, default) in options {
+        if current != default {
+            overrides.insert(name.to_string(), serde_json::json!({"default": default, "current": current}));
+        }
+    }
+    overrides
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim();
+    if text.len() % 2 != 0 {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Parse "addr", "addr/prefix" or "[addr]:port" into an IP and optional prefix length
+fn parse_ip_or_cidr(text: &str) -> Option<(IpAddr, Option<u8>)> {
+    let text = text.trim();
+    if let Ok(socket) = text.parse::<SocketAddr>() {
+        return Some((socket.ip(), None));
+    }
+    match text.split_once('/') {
+        Some((ip, prefix)) => {
+            let ip = ip.trim_matches(|c| c == '[' || c == ']').parse::<IpAddr>().ok()?;
+            let prefix = prefix.parse::<u8>().ok()?;
+            let max_prefix = if ip.is_ipv4() { 32 } else { 128 };
+            if prefix > max_prefix {
+                return None;
+            }
+            Some((ip, Some(prefix)))
+        }
+        None => text.trim_matches(|c| c == '[' || c == ']').parse::<IpAddr>().ok().map(|ip| (ip, None)),
+    }
+}
+
+/// Remember the chain database file, None when the chain lives in memory
+fn set_chain_db_path(db_path: Option<&str>) {
+    if let Ok(mut guard) = CHAIN_DB_PATH.lock() {
+        *guard = db_path.map(|path| path.to_string());
+    }
+}
+
+fn chain_db_path() -> Option<String> {
+    CHAIN_DB_PATH.lock().ok().and_then(|guard| guard.clone())
+}
+
+fn remember_config_path(config_path: &str) {
+    if let Ok(mut guard) = CONFIG_PATH.lock() {
+        *guard = Some(config_path.to_string());
+    }
+}
+
+/// Load the options only this library knows from the config, Settings skips them
+fn load_android_options(config_path: &str) {
+    // Not known to Settings, but it belongs with the other listener options
+    if let Some(value) = read_config_value(config_path, "dns", "dual_stack").and_then(|v| v.as_bool()) {
+        DNS_DUAL_STACK.store(value, Ordering::Relaxed);
+    }
+}
+
+/// Read a raw value from a table of the config file, including keys Settings doesn't know
+fn read_config_value(config_path: &str, table_name: &str, key: &str) -> Option<toml::Value> {
+    let text = std::fs::read_to_string(config_path).ok()?;
+    let table: toml::Table = toml::from_str(&text).ok()?;
+    table.get(table_name)?.get(key).cloned()
+}
+
+/// Write a raw value to a table of the config file, keeping other settings intact.
+/// The text is edited in place like generate_config_with_params does, so comments survive.
+fn write_config_value(config_path: &str, table_name: &str, key: &str, value: toml::Value) -> Result<(), Box<dyn std::error::Error>> {
+    let text = std::fs::read_to_string(config_path)?;
+    let edited = set_config_line(&text, table_name, key, &value);
+    // Must still be valid and hold the new value, the line editing doesn't understand all of TOML
+    let table: toml::Table = toml::from_str(&edited)?;
+    if table.get(table_name).and_then(|section| section.get(key)) != Some(&value) {
+        return Err(format!("Failed to set {}.{} in config", table_name, key).into());
+    }
+    std::fs::write(config_path, edited)?;
+    Ok(())
+}
+
+/// Replace `key = ...` in the `[table_name]` section of config text, a value spanning several
+/// lines included. The key is added at the end of the section if missing, the section at the
+/// end of the text. Other lines are left as they are.
+fn set_config_line(text: &str, table_name: &str, key: &str, value: &toml::Value) -> String {
+    let new_line = format!("{} = {}", key, value);
+    let lines: Vec<&str> = text.lines().collect();
+    let mut section = String::new();
+    // Index after the last non-empty line of the section, where a missing key goes
+    let mut section_end = None;
+    for (i, line) in lines.iter().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            section = trimmed.trim_start_matches('[').split(']').next().unwrap_or_default().trim().to_string();
+            if section == table_name {
+                section_end = Some(i + 1);
+            }
+        } else if section == table_name {
+            if config_line_key(trimmed) == Some(key) {
+                // Skip the lines of a multi-line array as well
+                let mut depth = bracket_depth(trimmed);
+                let mut last = i;
+                while depth > 0 && last + 1 < lines.len() {
+                    last += 1;
+                    depth += bracket_depth(lines[last]);
+                }
+                let indent = &line[..line.len() - line.trim_start().len()];
+                let mut result: Vec<String> = lines[..i].iter().map(|line| line.to_string()).collect();
+                result.push(format!("{}{}", indent, new_line));
+                result.extend(lines[last + 1..].iter().map(|line| line.to_string()));
+                return result.join("\n") + "\n";
+            }
+            if !trimmed.is_empty() && !trimmed.starts_with('#') {
+                section_end = Some(i + 1);
+            }
+        }
+    }
+
+    let mut result: Vec<String> = lines.iter().map(|line| line.to_string()).collect();
+    match section_end {
+        Some(end) => result.insert(end, new_line),
+        None => {
+            if result.last().map_or(false, |line| !line.trim().is_empty()) {
+                result.push(String::new());
+            }
+            result.push(format!("[{}]", table_name));
+            result.push(new_line);
+        }
+    }
+    result.join("\n") + "\n"
+}
+
+/// Key of a `key = value` line, None for comments and other lines
+fn config_line_key(line: &str) -> Option<&str> {
+    let (key, _) = line.split_once('=')?;
+    let key = key.trim();
+    match !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        true => Some(key),
+        false => None,
+    }
+}
+
+/// Opened minus closed square brackets on a line, not counting strings and comments
+fn bracket_depth(line: &str) -> i32 {
+    let mut depth = 0;
+    let mut quote = None;
+    for c in line.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, '#') => break,
+            (None, '[') => depth += 1,
+            (None, ']') => depth -= 1,
+            _ => {}
+        }
+    }
+    depth
+}
+
+/// Change the bootstrap peer list with `change`, which returns true if it changed anything.
+/// Changes go to the config file and to the settings of the running node.
+fn update_peers<F: FnMut(&mut Vec<String>) -> bool>(mut change: F) -> Result<bool, Box<dyn std::error::Error>> {
+    let config_path = CONFIG_PATH.lock().ok().and_then(|guard| guard.clone()).ok_or("Config file is not known yet")?;
+    let mut settings = Settings::load(&config_path).ok_or("Failed to load settings")?;
+    if !change(&mut settings.net.peers) {
+        return Ok(false);
+    }
+
+    let peers = settings.net.peers.iter().cloned().map(toml::Value::String).collect();
+    write_config_value(&config_path, "net", "peers", toml::Value::Array(peers))?;
+
+    if let Some(context) = running_context() {
+        if let Ok(mut ctx) = context.lock() {
+            ctx.settings.net.peers = settings.net.peers;
+        }
+    }
+    Ok(true)
+}
+
+/// Peer addresses are host:port, IPv6 hosts in brackets
+fn is_valid_peer_address(address: &str) -> bool {
+    if address.parse::<SocketAddr>().is_ok() {
+        return true;
+    }
+    match address.rsplit_once(':') {
+        Some((host, port)) => {
+            !host.is_empty()
+                && port.parse::<u16>().map_or(false, |port| port != 0)
+                && host.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+        }
+        None => false,
+    }
+}
+
+/// Seconds since the Unix epoch, 0 if the clock is set before it
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Seconds since `start`, 0 when the clock went back behind the start time
+fn uptime_secs(now: u64, start: u64) -> u64 {
+    now.saturating_sub(start)
+}
+
+// Helper function to add messages to log buffer
+fn add_log_message(message: String) {
+    let timestamp = unix_time();
+    let mut formatted_message = format!("[{}] {}", timestamp, message);
+
+    // A single huge line must not take more than the whole budget
+    let max_bytes = LOG_BUFFER_MAX_BYTES.load(Ordering::Relaxed);
+    if formatted_message.len() > max_bytes {
+        let mut end = max_bytes;
+        while !formatted_message.is_char_boundary(end) {
+            end -= 1;
+        }
+        formatted_message.truncate(end);
+    }
+    
+    if let Ok(mut buffer_guard) = LOG_BUFFER.lock() {
+        LOG_BUFFER_BYTES.fetch_add(formatted_message.len(), Ordering::Relaxed);
+        buffer_guard.push_back(formatted_message);
+        trim_log_buffer(&mut buffer_guard);
+    }
+}
+
+/// Sends log records to logcat, debug and trace ones also to the console buffer.
+/// Info and above get there through add_log_message already.
+struct ConsoleLogger {
+    android: android_logger::AndroidLogger,
+}
+
+impl log::Log for ConsoleLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.android.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        self.android.log(record);
+        if record.level() > log::Level::Info && self.enabled(record.metadata()) {
+            add_log_message(format!("{} {}: {}", record.level(), record.target(), record.args()));
+        }
+    }
+
+    fn flush(&self) {
+        self.android.flush();
+    }
+}
+
+/// Drop oldest messages until the buffer fits both the line and the byte limits.
+/// Must be called with the buffer lock held, LOG_BUFFER_BYTES is only changed under it.
+fn trim_log_buffer(buffer: &mut VecDeque<String>) {
+    let max_bytes = LOG_BUFFER_MAX_BYTES.load(Ordering::Relaxed);
+    let max_lines = LOG_BUFFER_MAX_LINES.load(Ordering::Relaxed);
+    while buffer.len() > max_lines || LOG_BUFFER_BYTES.load(Ordering::Relaxed) > max_bytes {
+        match buffer.pop_front() {
+            Some(message) => {
+                LOG_BUFFER_BYTES.fetch_sub(message.len(), Ordering::Relaxed);
+            }
+            None => break,
+        }
+    }
+}
+
+// Real DNS server implementation using Alfis core components
+
+/// Start DNS server with controllable threads
 fn start_dns_server_with_context(context: &Arc<Mutex<Context>>, settings: &Settings) -> Result<Arc<ServerContext>, Box<dyn std::error::Error>> {
     // Create server context
     let server_context = create_android_server_context(Arc::clone(context), settings);
+    if !ADAPTIVE_THREADS_SET.load(Ordering::Relaxed) {
+        let max = settings.dns.threads.clamp(ADAPTIVE_MIN_THREADS.load(Ordering::Relaxed), MAX_WORKER_THREADS);
+        ADAPTIVE_MAX_THREADS.store(max, Ordering::Relaxed);
+        add_log_message(format!("DNS worker threads: up to {}", max));
+    }
+    TCP_MAX_THREADS.store(settings.dns.threads.clamp(1, MAX_WORKER_THREADS), Ordering::Relaxed);
+
+    // Sockets are bound here, so a taken port fails the start instead of a server thread
+    let (active_addr, udp_socket, tcp_listener) = bind_dns_listeners(&server_context)?;
+    if let Ok(mut active) = ACTIVE_DNS_ADDR.lock() {
+        *active = Some(active_addr);
+    }
+
+    // Start UDP server in controllable thread
+    if let Some(socket) = udp_socket {
+        add_log_message("Starting UDP DNS server...".to_string());
+        let server_ctx_clone = Arc::clone(&server_context);
+        let udp_handle = thread::Builder::new()
+            .name("DNS-UDP".to_string())
+            .spawn(move || {
+                run_controllable_udp_server(server_ctx_clone, socket);
+            })?;
+        
+        state().dns_udp_handle = Some(udp_handle);
+        add_log_message("UDP DNS server started successfully".to_string());
+    }
+
+    // Start TCP server in controllable thread  
+    if let Some(listener) = tcp_listener {
+        add_log_message("Starting TCP DNS server...".to_string());
+        let server_ctx_clone = Arc::clone(&server_context);
+        let tcp_handle = thread::Builder::new()
+            .name("DNS-TCP".to_string())
+            .spawn(move || {
+                run_controllable_tcp_server(server_ctx_clone, listener);
+            })?;
+            
+        state().dns_tcp_handle = Some(tcp_handle);
+        add_log_message("TCP DNS server started successfully".to_string());
+    }
+
+    if DOH_ENABLED.load(Ordering::Relaxed) {
+        start_doh_listener(&server_context);
+    }
+
+    if DNS_DUAL_STACK.load(Ordering::Relaxed) {
+        match dual_stack_address(&active_addr.to_string()) {
+            Some(listen) => start_dual_stack_listeners(&server_context, listen)?,
+            None => add_log_message(format!("Dual stack needs a loopback listen address, not {}", active_addr)),
+        }
+    }
+    
+    Ok(server_context)
+}
+
+/// Bind UDP and TCP on the listen address. When its port is taken by another app,
+/// the same address with one of DNS_FALLBACK_PORTS is used and the port change is logged.
+fn bind_dns_listeners(server_context: &ServerContext) -> Result<(SocketAddr, Option<std::net::UdpSocket>, Option<std::net::TcpListener>), String> {
+    let configured: SocketAddr = server_context
+        .dns_listen
+        .parse()
+        .map_err(|_| format!("invalid listen address '{}'", server_context.dns_listen))?;
+    let mut ports = vec![configured.port()];
+    ports.extend(DNS_FALLBACK_PORTS.iter().filter(|port| **port != configured.port()));
+
+    for port in &ports {
+        let addr = SocketAddr::new(configured.ip(), *port);
+        let bound = (|| -> std::io::Result<_> {
+            let udp = match server_context.enable_udp {
+                true => Some(bind_udp(addr)?),
+                false => None,
+            };
+            let tcp = match server_context.enable_tcp {
+                true => Some(bind_tcp(addr)?),
+                false => None,
+            };
+            Ok((udp, tcp))
+        })();
+        match bound {
+            Ok((udp, tcp)) => {
+                // What the socket actually got, a configured port 0 is picked by the system
+                let addr = udp
+                    .as_ref()
+                    .and_then(|socket| socket.local_addr().ok())
+                    .or_else(|| tcp.as_ref().and_then(|listener| listener.local_addr().ok()))
+                    .unwrap_or(addr);
+                if addr.port() != configured.port() && configured.port() != 0 {
+                    add_log_message(format!("WARNING: Port {} is in use, DNS server listens on {} instead", configured.port(), addr));
+                }
+                return Ok((addr, udp, tcp));
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {
+                warn!("DNS port {} is in use", port);
+            }
+            Err(e) => return Err(format!("failed to bind {}: {}", addr, e)),
+        }
+    }
+    let ports: Vec<String> = ports.iter().map(|port| port.to_string()).collect();
+    Err(format!("port in use: ports {} are taken by another app", ports.join(", ")))
+}
+
+fn bind_udp(addr: SocketAddr) -> std::io::Result<std::net::UdpSocket> {
+    let socket = std::net::UdpSocket::bind(addr)?;
+    // Non-blocking, so the server loop can check the shutdown flag
+    socket.set_nonblocking(true)?;
+    add_log_message(format!("UDP server bound to {}", addr));
+    Ok(socket)
+}
+
+fn bind_tcp(addr: SocketAddr) -> std::io::Result<std::net::TcpListener> {
+    let listener = std::net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    add_log_message(format!("TCP server bound to {}", addr));
+    Ok(listener)
+}
+
+/// The loopback address of the other family with the same port: 127.0.0.1 for [::1] and back
+fn dual_stack_address(listen: &str) -> Option<String> {
+    let addr = listen.parse::<SocketAddr>().ok()?;
+    let other: IpAddr = match addr.ip() {
+        IpAddr::V6(ip) if ip.is_loopback() => Ipv4Addr::LOCALHOST.into(),
+        IpAddr::V4(ip) if ip.is_loopback() => Ipv6Addr::LOCALHOST.into(),
+        _ => return None,
+    };
+    Some(SocketAddr::new(other, addr.port()).to_string())
+}
+
+/// Second UDP and TCP pair on `listen`, sharing the server context and the shutdown flag
+fn start_dual_stack_listeners(server_context: &Arc<ServerContext>, listen: String) -> Result<(), Box<dyn std::error::Error>> {
+    add_log_message(format!("Starting dual-stack DNS listeners on {}...", listen));
+    let mut handles = Vec::new();
+    if server_context.enable_udp {
+        let server_ctx_clone = Arc::clone(server_context);
+        let listen = listen.clone();
+        handles.push(thread::Builder::new()
+            .name("DNS-UDP-2".to_string())
+            .spawn(move || run_udp_listener(server_ctx_clone, &listen))?);
+    }
+    if server_context.enable_tcp {
+        let server_ctx_clone = Arc::clone(server_context);
+        handles.push(thread::Builder::new()
+            .name("DNS-TCP-2".to_string())
+            .spawn(move || run_tcp_listener(server_ctx_clone, &listen))?);
+    }
+    state().dns_dual_stack_handles.extend(handles);
+    Ok(())
+}
+
+/// Create server context for Android (based on dns_utils.rs create_server_context)
+fn create_android_server_context(context: Arc<Mutex<Context>>, settings: &Settings) -> Arc<ServerContext> {
+    use alfis::dns::context::ResolveStrategy;
+    use alfis::blockchain::filter::BlockchainFilter;
+
+    let mut server_context = ServerContext::new(settings.dns.listen.clone(), settings.dns.bootstraps.clone());
+    add_log_message(format!("DNS server configured to listen on: {}", settings.dns.listen));
+    server_context.allow_recursive = true;
+    server_context.resolve_strategy = match settings.dns.forwarders.is_empty() {
+        true => ResolveStrategy::Recursive,
+        false => ResolveStrategy::Forward { upstreams: settings.dns.forwarders.clone() }
+    };
+    set_forwarders(settings.dns.forwarders.clone());
+    if let Ok(mut bootstraps) = BOOTSTRAPS.write() {
+        *bootstraps = settings.dns.bootstraps.clone();
+    }
+
+    // Add blockchain filter for .alfis domains
+    server_context.filters.push(Box::new(BlockchainFilter::new(context)));
+    
+    match server_context.initialize() {
+        Ok(_) => info!("Server context initialized successfully"),
+        Err(e) => error!("Server context initialization failed: {:?}", e)
+    }
+
+    Arc::new(server_context)
+}
+
+/// Safely create a blockchain with proper error handling for Android
+fn create_chain_safely(settings: &Settings, db_path: &str) -> Result<Chain, Box<dyn std::error::Error>> {
+    info!("Attempting to create blockchain database at: {}", db_path);
+    if db_path != ":memory:" {
+        preflight_chain_db(db_path);
+    }
+    
+    // Pre-check: test if we can create/access the database file
+    match sqlite::open(db_path) {
+        Ok(mut db) => {
+            match db.execute("SELECT 1") {
+                Ok(_) => {
+                    info!("Database connection test successful for {}", db_path);
+                    if db_path != ":memory:" {
+                        enable_wal(&mut db);
+                    }
+                    // Close the test connection
+                    drop(db);
+                }
+                Err(e) => {
+                    error!("Database functionality test failed: {}", e);
+                    return Err(format!("Database not functional: {}", e).into());
+                }
+            }
+        }
+        Err(e) => {
+            error!("Failed to open SQLite database at {}: {}", db_path, e);
+            return Err(format!("Database creation failed: {}", e).into());
+        }
+    }
+    
+    // Now try to create the chain - this might still panic due to internal expects
+    // but at least we know the database itself works
+    let result = std::panic::catch_unwind(|| {
+        Chain::new(settings, db_path)
+    });
+    
+    match result {
+        Ok(chain) => {
+            info!("Blockchain created successfully");
+            let wal = db_path != ":memory:" && chain_db_journal_mode(db_path).map(|mode| mode == "wal").unwrap_or(false);
+            CHAIN_DB_WAL.store(wal, Ordering::Relaxed);
+            if db_path != ":memory:" && !wal {
+                warn!("Chain database {} is not in WAL mode", db_path);
+            }
+            Ok(chain)
+        }
+        Err(panic_err) => {
+            error!("Chain::new panicked during initialization");
+            // Try to extract panic message if possible
+            if let Some(msg) = panic_err.downcast_ref::<String>() {
+                error!("Panic message: {}", msg);
+                Err(format!("Chain creation panicked: {}", msg).into())
+            } else if let Some(msg) = panic_err.downcast_ref::<&str>() {
+                error!("Panic message: {}", msg);
+                Err(format!("Chain creation panicked: {}", msg).into())
+            } else {
+                Err("Chain creation panicked with unknown error".into())
+            }
+        }
+    }
+}
+
+/// Switch the database file to WAL, so readers and the writer don't lock each other out.
+/// The journal mode is stored in the file and so applies to the connection Chain opens later,
+/// the busy timeout is per connection and only covers our own connections like this one.
+fn enable_wal(db: &mut sqlite::Connection) {
+    if let Err(e) = db.set_busy_timeout(CHAIN_DB_BUSY_TIMEOUT_MS) {
+        warn!("Failed to set database busy timeout: {}", e);
+    }
+    match db.execute("PRAGMA journal_mode=WAL;") {
+        Ok(_) => info!("Requested WAL journal mode for the chain database"),
+        Err(e) => warn!("Failed to enable WAL journal mode: {}", e),
+    }
+}
+
+/// Journal mode of the database file as a new connection sees it, like the one of Chain
+fn chain_db_journal_mode(db_path: &str) -> Result<String, sqlite::Error> {
+    let db = sqlite::open(db_path)?;
+    let mut statement = db.prepare("PRAGMA journal_mode;")?;
+    statement.next()?;
+    let mode: String = statement.read(0)?;
+    Ok(mode.to_lowercase())
+}
+
+/// Stop the network thread, its loop quits on ActionQuit. A thread that doesn't stop in time is left behind.
+fn stop_network_thread(handle: thread::JoinHandle<()>) {
+    add_log_message("Stopping network thread...".to_string());
+    NETWORK_SHUTDOWN.store(true, Ordering::Relaxed);
+    alfis::eventbus::post(Event::ActionQuit);
+    // The miner quits on ActionQuit as well
+    stop_miner();
+    // JoinHandle can't join with a timeout, wait for the thread to finish first
+    let deadline = Instant::now() + NETWORK_JOIN_TIMEOUT;
+    while !handle.is_finished() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(50));
+    }
+    if handle.is_finished() {
+        match handle.join() {
+            Ok(_) => add_log_message("Network thread stopped".to_string()),
+            Err(e) => {
+                add_log_message(format!("Network thread join failed: {:?}", e));
+                error!("Failed to join network thread: {:?}", e);
+            }
+        }
+    } else {
+        add_log_message("Network thread didn't stop in time, leaving it behind".to_string());
+        warn!("Network thread didn't stop in {:?}", NETWORK_JOIN_TIMEOUT);
+    }
+}
+
+/// Stop P2P connections and sync, DNS keeps answering from the local chain
+fn pause_network() -> Result<(), String> {
+    let _guard = NETWORK_CONTROL_LOCK.lock().map_err(|_| "Network lock poisoned")?;
+    if !DNS_RUNNING.load(Ordering::Acquire) {
+        return Err("DNS server is not running".to_string());
+    }
+    if NETWORK_PAUSED.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    let handle = state().network_handle.take();
+    NETWORK_PAUSED.store(true, Ordering::Relaxed);
+    if let Some(handle) = handle {
+        stop_network_thread(handle);
+    }
+    NETWORK_PEER_COUNT.store(0, Ordering::Relaxed);
+    add_log_message("P2P network paused".to_string());
+    Ok(())
+}
+
+/// Start the network thread again with the running context
+fn resume_network() -> Result<(), String> {
+    let _guard = NETWORK_CONTROL_LOCK.lock().map_err(|_| "Network lock poisoned")?;
+    let context = running_context().ok_or("DNS server is not running")?;
+    if !NETWORK_PAUSED.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    NETWORK_SHUTDOWN.store(false, Ordering::Relaxed);
+    let handle = spawn_network_thread(context).map_err(|e| format!("Failed to start network thread: {}", e))?;
+    state().network_handle = Some(handle);
+    NETWORK_PAUSED.store(false, Ordering::Relaxed);
+    add_log_message("P2P network resumed".to_string());
+    Ok(())
+}
+
+/// Start the P2P network thread
+fn start_network_with_context(context: Arc<Mutex<Context>>) -> Result<(), Box<dyn std::error::Error>> {
+    // The listener lives as long as the process, registering it on every start would double the events
+    static NETWORK_LISTENER: Once = Once::new();
+    NETWORK_LISTENER.call_once(|| {
+        register(|_uuid, event| {
+            on_network_event(event);
+            true // Keep listening
+        });
+    });
+
+    if let Ok(ctx) = context.lock() {
+        let height = ctx.chain.get_height();
+        LAST_BLOCK_INDEX.store(height, Ordering::Relaxed);
+        if let Ok(mut hashes) = RECENT_BLOCK_HASHES.lock() {
+            hashes.clear();
+        }
+        remember_tip_hashes(&ctx.chain);
+        NETWORK_HEIGHT.store(0, Ordering::Relaxed);
+        SYNC_HAVE.store(height, Ordering::Relaxed);
+        SYNC_STATE.store(SYNC_STATE_IDLE, Ordering::Relaxed);
+        LAST_BLOCK_RECEIVED.store(0, Ordering::Relaxed);
+        if let Ok(mut stats) = SYNC_DATA.lock() {
+            // Seed the average with the last blocks we already have
+            *stats = SyncDataStats::new();
+            stats.session_start_height = height;
+            stats.sampled_height = height.saturating_sub(MAX_BLOCK_SAMPLES_PER_CALL);
+        }
+    }
+
+    let handle = spawn_network_thread(context)?;
+    state().network_handle = Some(handle);
+    
+    Ok(())
+}
+
+/// Track network status and block updates, registered once for all starts
+fn on_network_event(event: Event) {
+    match event {
+        Event::NetworkStatus { blocks, domains: _, keys: _, nodes } => {
+            NETWORK_PEER_COUNT.store(nodes, Ordering::Relaxed);
+            notify_status(serde_json::json!({"event": "network_status", "blocks": blocks, "peers": nodes}));
+            
+            // Log peer connectivity status periodically
+            static LAST_PEER_LOG: AtomicU64 = AtomicU64::new(0);
+            let now = unix_time();
+            
+            if now.saturating_sub(LAST_PEER_LOG.load(Ordering::Relaxed)) > 60 { // Log every 60 seconds
+                LAST_PEER_LOG.store(now, Ordering::Relaxed);
+                if nodes == 0 {
+                    add_log_message("Warning: No peer connections active".to_string());
+                    log::warn!("No active peer connections - this will prevent sync");
+                } else {
+                    add_log_message(format!("Network: {} peers, {} blocks", nodes, blocks));
+                    log::info!("Active peers: {}, known blocks: {}", nodes, blocks);
+                    
+                    // Check if we have a reasonable number of peers for good sync
+                    if nodes < 2 {
+                        add_log_message("Few peers: Consider checking network connectivity".to_string());
+                        log::warn!("Low peer count ({}) may impact sync performance", nodes);
+                    }
+                }
+            }
+        }
+        Event::BlockchainChanged { index } => {
+            LAST_BLOCK_RECEIVED.store(unix_time(), Ordering::Relaxed);
+            // A block at or below the known tip means blocks were replaced
+            let previous = LAST_BLOCK_INDEX.swap(index, Ordering::Relaxed);
+            if previous > 0 && index <= previous {
+                on_reorg(previous, index);
+            } else {
+                // The new block may change any domain, cached answers last one block at most
+                cache_clear(Some(CacheSource::Blockchain));
+                record_block_hashes();
+            }
+        }
+        Event::NewBlockReceived => {
+            // Silent - syncing events will show progress
+            LAST_BLOCK_RECEIVED.store(unix_time(), Ordering::Relaxed);
+        }
+        Event::Syncing { have, height } => {
+            NETWORK_HEIGHT.store(height, Ordering::Relaxed);
+            SYNC_HAVE.store(have, Ordering::Relaxed);
+            SYNC_STATE.store(SYNC_STATE_SYNCING, Ordering::Relaxed);
+            notify_status(serde_json::json!({"event": "syncing", "have": have, "height": height}));
+            let percent = if height > 0 { (have as f64 / height as f64) * 100.0 } else { 0.0 };
+            add_log_message(format!("Syncing: {}/{} blocks ({:.1}%)", have, height, percent));
+        }
+        Event::MinerStarted => {
+            if let Ok(mut reports) = MINER_REPORTS.lock() {
+                *reports = MinerReports::new();
+                reports.started = Some(Instant::now());
+            }
+        }
+        Event::MinerStats { thread, speed, max_diff, target_diff } => {
+            if let Ok(mut reports) = MINER_REPORTS.lock() {
+                MINING_HASHES.fetch_add(reports.attempts(thread, speed), Ordering::Relaxed);
+                reports.max_diff = reports.max_diff.max(max_diff);
+                reports.target_diff = target_diff;
+            }
+        }
+        Event::MinerStopped { success, full: _ } => {
+            if let Ok(mut reports) = MINER_REPORTS.lock() {
+                reports.started = None;
+            }
+            if success {
+                MINED_BLOCKS.fetch_add(1, Ordering::Relaxed);
+                // The miner may still hold the context, look at the chain from another thread
+                if let Some(context) = running_context() {
+                    let _ = thread::Builder::new()
+                        .name("Mined-Block".to_string())
+                        .spawn(move || remove_mined_block(&context));
+                }
+            }
+        }
+        Event::SyncFinished => {
+            SYNC_STATE.store(SYNC_STATE_SYNCED, Ordering::Relaxed);
+            notify_status(serde_json::json!({"event": "sync_finished"}));
+            add_log_message("Blockchain synchronization completed".to_string());
+        }
+        _ => {
+            // Other events - silent
+        }
+    }
+}
+
+/// Network thread running the P2P node on `context` until ActionQuit
+fn spawn_network_thread(context: Arc<Mutex<Context>>) -> std::io::Result<thread::JoinHandle<()>> {
+    start_miner(&context);
+    thread::Builder::new()
+        .name(String::from("Network"))
+        .spawn(move || {
+            // Give the DNS server time to start
+            thread::sleep(Duration::from_millis(1000));
+            // Stopped while we were waiting, ActionQuit has already been posted
+            if NETWORK_SHUTDOWN.load(Ordering::Relaxed) {
+                return;
+            }
+            
+            add_log_message("Connecting to P2P network...".to_string());
+            info!("Starting P2P network thread");
+            
+            add_log_message("Network thread started".to_string());
+            add_log_message("Attempting to connect to bootstrap peers...".to_string());
+            add_log_message("Looking for peers at peer-v4.alfis.name:4244 and peer-v6.alfis.name:4244".to_string());
+            
+            let mut network = Network::new(context);
+            network.start();
+        })
+}
+
+/// Measure blocks added since the last call, the JSON size is close to what peers send us.
+/// Blocks above the per-call limit are not read and are accounted with the average size.
+fn sample_block_sizes(chain: &Chain, stats: &mut SyncDataStats) {
+    let height = chain.get_height();
+    if height <= stats.sampled_height {
+        return;
+    }
+
+    let first = (stats.sampled_height + 1).max(height.saturating_sub(MAX_BLOCK_SAMPLES_PER_CALL - 1));
+    let skipped = first.saturating_sub((stats.sampled_height + 1).max(stats.session_start_height + 1));
+    stats.downloaded_bytes += skipped * stats.average_block_size();
+
+    for index in first..=height {
+        if let Some(block) = chain.get_block(index) {
+            let size = serde_json::to_vec(&block).map(|bytes| bytes.len() as u64).unwrap_or(0);
+            stats.sampled_blocks += 1;
+            stats.sampled_bytes += size;
+            if index > stats.session_start_height {
+                stats.downloaded_bytes += size;
+            }
+        }
+    }
+    stats.sampled_height = height;
+}
+
+/// Compute block intervals from the newest blocks and label the network health
+fn network_health(chain: &Chain) -> serde_json::Value {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    let height = chain.get_height();
+    let lowest = height.saturating_sub(HEALTH_MAX_BLOCKS_SCANNED) + 1;
+
+    let mut timestamps = Vec::new();
+    let mut blocks_last_hour = 0u64;
+    for index in (lowest..=height).rev() {
+        let block = match chain.get_block(index) {
+            Some(block) => block,
+            None => break,
+        };
+        if block.timestamp >= now - 3600 {
+            blocks_last_hour += 1;
+        } else if timestamps.len() >= HEALTH_INTERVAL_SAMPLES {
+            break;
+        }
+        if timestamps.len() < HEALTH_INTERVAL_SAMPLES {
+            timestamps.push(block.timestamp);
+        }
+    }
+
+    let tip_age_secs = timestamps.first().map(|timestamp| (now - timestamp).max(0) as u64);
+    let avg_block_interval_secs = if timestamps.len() > 1 {
+        let span = timestamps[0] - timestamps[timestamps.len() - 1];
+        Some(span.max(0) as u64 / (timestamps.len() as u64 - 1))
+    } else {
+        None
+    };
+    let health = match tip_age_secs {
+        Some(age) if age <= HEALTH_SLOW_TIP_AGE_SECS && avg_block_interval_secs.map_or(true, |avg| avg <= HEALTH_SLOW_TIP_AGE_SECS) => "good",
+        Some(age) if age <= HEALTH_STALLED_TIP_AGE_SECS => "slow",
+        _ => "stalled",
+    };
+
+    serde_json::json!({
+        "avg_block_interval_secs": avg_block_interval_secs,
+        "blocks_last_hour": blocks_last_hour,
+        "tip_age_secs": tip_age_secs,
+        "health": health,
+    })
+}
+
+/// Yggdrasil isolation as far as we can see it: the setting and the addresses of the
+/// configured peers, the connected ones are not exposed by the p2p layer.
+/// configuredPeersYggdrasilOnly is false if any configured peer is outside 200::/7, it says
+/// nothing about the peers we are connected to right now.
+fn peer_network_info() -> serde_json::Value {
+    use std::net::ToSocketAddrs;
+
+    let settings = match running_context() {
+        Some(context) => context.lock().ok().map(|ctx| ctx.settings.clone()),
+        None => CONFIG_PATH.lock().ok().and_then(|guard| guard.clone()).and_then(|path| Settings::load(&path)),
+    };
+    let settings = match settings {
+        Some(settings) => settings,
+        None => return serde_json::json!({"error": "Settings are not known yet"}),
+    };
+
+    let mut outside = Vec::new();
+    let bootstrap: Vec<serde_json::Value> = settings
+        .net
+        .peers
+        .iter()
+        .map(|peer| {
+            let addrs: Vec<IpAddr> = peer.to_socket_addrs().map(|addrs| addrs.map(|addr| addr.ip()).collect()).unwrap_or_default();
+            let yggdrasil = !addrs.is_empty() && addrs.iter().all(is_yggdrasil);
+            outside.extend(addrs.iter().filter(|ip| !is_yggdrasil(ip)).map(|ip| format!("{} ({})", peer, ip)));
+            serde_json::json!({
+                "address": peer,
+                "resolved": addrs.iter().map(|ip| ip.to_string()).collect::<Vec<_>>(),
+                "yggdrasil": yggdrasil,
+            })
+        })
+        .collect();
+
+    let yggdrasil_only = settings.net.yggdrasil_only;
+    if yggdrasil_only && !outside.is_empty() {
+        add_log_message(format!("WARNING: yggdrasil_only is set, but peers outside Yggdrasil are configured: {}", outside.join(", ")));
+        warn!("Non-Yggdrasil peers with yggdrasil_only: {:?}", outside);
+    }
+    serde_json::json!({
+        "yggdrasilOnly": yggdrasil_only,
+        "configuredPeersYggdrasilOnly": yggdrasil_only && outside.is_empty(),
+        "connectedPeers": NETWORK_PEER_COUNT.load(Ordering::Relaxed),
+        "bootstrapPeers": bootstrap,
+    })
+}
+
+/// Try to connect to every address of a peer until one answers
+fn probe_peer(address: &str) -> serde_json::Value {
+    use std::net::{TcpStream, ToSocketAddrs};
+
+    let probe_result = |reachable: bool, latency_ms: Option<u128>, error: Option<String>| {
+        serde_json::json!({
+            "reachable": reachable,
+            "latency_ms": latency_ms,
+            "error": error,
+        })
+    };
+
+    let addrs: Vec<SocketAddr> = match address.to_socket_addrs() {
+        Ok(addrs) => addrs.collect(),
+        Err(e) => return probe_result(false, None, Some(format!("Invalid address: {}", e))),
+    };
+    if addrs.is_empty() {
+        return probe_result(false, None, Some("Address did not resolve".to_string()));
+    }
+
+    let mut last_error = String::new();
+    for addr in addrs {
+        let started = Instant::now();
+        match TcpStream::connect_timeout(&addr, PEER_PROBE_TIMEOUT) {
+            Ok(_stream) => {
+                let latency = started.elapsed().as_millis();
+                info!("Peer {} ({}) reachable in {} ms", address, addr, latency);
+                return probe_result(true, Some(latency), None);
+            }
+            Err(e) => last_error = format!("{}: {}", addr, e),
+        }
+    }
+    probe_result(false, None, Some(last_error))
+}
+
+/// Read all transactions of a domain identity from the chain database.
+/// Uses a separate read-only connection and the index on domains.identity.
+fn domain_history(db_path: &str, identity: &[u8]) -> Result<Vec<serde_json::Value>, sqlite::Error> {
+    let flags = sqlite::OpenFlags::new().with_read_only();
+    let db = sqlite::Connection::open_with_flags(db_path, flags)?;
+    let mut statement = db.prepare("SELECT id, timestamp, data, signing FROM domains WHERE identity = ? ORDER BY id ASC LIMIT ?;")?;
+    statement.bind((1, identity))?;
+    statement.bind((2, MAX_DOMAIN_HISTORY as i64))?;
+
+    let mut history = Vec::new();
+    let mut previous: Option<(Vec<u8>, String)> = None;
+    while let sqlite::State::Row = statement.next()? {
+        let height: i64 = statement.read(0)?;
+        let timestamp: i64 = statement.read(1)?;
+        let data: String = statement.read(2)?;
+        let signing: Vec<u8> = statement.read(3)?;
+
+        let action = match &previous {
+            None => "register",
+            Some((owner, _)) if owner != &signing => "transfer",
+            Some((_, old_data)) if old_data != &data => "update",
+            Some(_) => "renew",
+        };
+        let records = serde_json::from_str::<serde_json::Value>(&data)
+            .ok()
+            .and_then(|data| data.get("records").cloned())
+            .unwrap_or_else(|| serde_json::Value::Array(Vec::new()));
+        history.push(serde_json::json!({
+            "block_height": height,
+            "timestamp": timestamp,
+            "action": action,
+            "owner": Bytes::from_bytes(&signing).to_string(),
+            "records": records,
+        }));
+        previous = Some((signing, data));
+    }
+    Ok(history)
+}
+
+/// Height and timestamp of the last block of a domain
+fn domain_last_block(db_path: &str, identity: &[u8]) -> Result<Option<(i64, i64)>, sqlite::Error> {
+    let flags = sqlite::OpenFlags::new().with_read_only();
+    let db = sqlite::Connection::open_with_flags(db_path, flags)?;
+    let mut statement = db.prepare("SELECT id, timestamp FROM domains WHERE identity = ? ORDER BY id DESC LIMIT 1;")?;
+    statement.bind((1, identity))?;
+    match statement.next()? {
+        sqlite::State::Row => Ok(Some((statement.read(0)?, statement.read(1)?))),
+        sqlite::State::Done => Ok(None),
+    }
+}
+
+/// Domains of the active key with their records, last block and expiry time.
+/// Alfis domains expire by time, DOMAIN_LIFETIME after their last block.
+fn owned_domains(ctx: &Context) -> Vec<serde_json::Value> {
+    let keystore = match ctx.get_keystore() {
+        Some(keystore) => keystore,
+        None => return Vec::new(),
+    };
+    // One query for the block heights of all owned domains instead of one per domain
+    let heights = match chain_db_path() {
+        Some(db_path) => owned_domain_heights(&db_path, keystore.get_public().as_slice()).unwrap_or_else(|e| {
+            warn!("Failed to read heights of owned domains: {}", e);
+            HashMap::new()
+        }),
+        None => HashMap::new(),
+    };
+
+    let mut domains: Vec<serde_json::Value> = ctx
+        .chain
+        .get_my_domains(Some(keystore))
+        .into_iter()
+        .map(|(identity, (name, timestamp, data))| {
+            serde_json::json!({
+                "name": name,
+                "records": serde_json::to_value(&data.records).unwrap_or_default(),
+                "expiry": timestamp + DOMAIN_LIFETIME,
+                "blockHeight": heights.get(identity.as_slice()),
+            })
+        })
+        .collect();
+    domains.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+    domains
+}
+
+fn owned_domain_heights(db_path: &str, pubkey: &[u8]) -> Result<HashMap<Vec<u8>, i64>, sqlite::Error> {
+    let flags = sqlite::OpenFlags::new().with_read_only();
+    let db = sqlite::Connection::open_with_flags(db_path, flags)?;
+    let mut statement = db.prepare("SELECT identity, MAX(id) FROM domains WHERE signing = ? GROUP BY identity;")?;
+    statement.bind((1, pubkey))?;
+
+    let mut heights = HashMap::new();
+    while let sqlite::State::Row = statement.next()? {
+        let identity: Vec<u8> = statement.read(0)?;
+        let height: i64 = statement.read(1)?;
+        heights.insert(identity, height);
+    }
+    Ok(heights)
+}
+
+/// Generate and save a new keystore, then queue a transfer block for every owned domain
+fn rotate_keystore(ctx: &Context, new_password: &str) -> serde_json::Value {
+    let old_keystore = match ctx.get_keystore() {
+        Some(keystore) => keystore.clone(),
+        None => return serde_json::json!({"error": "No keystore loaded"}),
+    };
+    let work_dir = match WORK_DIR.lock().ok().and_then(|guard| guard.clone()) {
+        Some(work_dir) => work_dir,
+        None => return serde_json::json!({"error": "Work directory is not known"}),
+    };
+
+    let mut new_keystore = Keystore::new();
+    let new_pubkey = new_keystore.get_public();
+    let key_path = format!("{}/rotated-{}.key", work_dir, &new_pubkey.to_string()[..16]);
+    new_keystore.save(&key_path, new_password);
+    if !std::path::Path::new(&key_path).exists() {
+        return serde_json::json!({"error": format!("Failed to save new keystore to {}", key_path)});
+    }
+
+    let mut transfers_queued = 0;
+    let mut errors = Vec::new();
+    for (_identity, (domain, _timestamp, data)) in ctx.chain.get_my_domains(Some(&old_keystore)) {
+        let data = match serde_json::to_string(&data) {
+            Ok(data) => data,
+            Err(e) => {
+                errors.push(format!("{}: {}", domain, e));
+                continue;
+            }
+        };
+        // The transaction names the new key as owner, the block is signed with the old one
+        let transaction = Transaction::from_str(
+            domain.clone(),
+            CLASS_DOMAIN.to_owned(),
+            data,
+            new_pubkey.clone(),
+            new_keystore.get_encryption_public(),
+        );
+        let block = Block::new(Some(transaction), old_keystore.get_public(), Bytes::default(), DOMAIN_DIFFICULTY);
+        queue_pending_block(&domain, "transfer", block, old_keystore.clone());
+        transfers_queued += 1;
+    }
+
+    add_log_message(format!("New key {} created, {} domain transfers queued", new_pubkey, transfers_queued));
+    info!("Key rotation: {} transfers queued, {} errors", transfers_queued, errors.len());
+    serde_json::json!({
+        "new_pubkey": new_pubkey.to_string(),
+        "keystore_path": key_path,
+        "transfers_queued": transfers_queued,
+        "errors": errors,
+    })
+}
+
+fn load_or_create_keystore(path: &str, password: &str) -> Result<Keystore, String> {
+    if std::path::Path::new(path).exists() {
+        // Decryption errors must not take the app down
+        return match std::panic::catch_unwind(|| Keystore::from_file(path, password)) {
+            Ok(Some(keystore)) => Ok(keystore),
+            Ok(None) => Err("Wrong password or damaged keystore file".to_string()),
+            Err(_) => Err("Keystore file could not be read".to_string()),
+        };
+    }
+
+    let mut keystore = Keystore::new();
+    keystore.save(path, password);
+    if !std::path::Path::new(path).exists() {
+        return Err(format!("Failed to save new keystore to {}", path));
+    }
+    info!("Created new keystore {}", path);
+    Ok(keystore)
+}
+
+/// Save a copy of the keystore encrypted with `password`, in the file format of desktop Alfis.
+/// The copy is read back before it replaces `path`, a backup that can't be restored is worse than none.
+fn export_keystore(keystore: &Keystore, path: &str, password: &str) -> Result<(), String> {
+    if let Some(parent) = std::path::Path::new(path).parent() {
+        std::fs::create_dir_all(parent).map_err(|e| format!("Failed to create directory: {}", e))?;
+    }
+    let temp_path = format!("{}.tmp", path);
+    let _ = std::fs::remove_file(&temp_path);
+    keystore.save(&temp_path, password);
+    let restored = match std::panic::catch_unwind(|| Keystore::from_file(&temp_path, password)) {
+        Ok(Some(restored)) => restored,
+        _ => {
+            let _ = std::fs::remove_file(&temp_path);
+            return Err("Failed to read back the exported keystore".to_string());
+        }
+    };
+    if restored.get_public() != keystore.get_public() {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err("The exported keystore has a different key".to_string());
+    }
+    std::fs::rename(&temp_path, path).map_err(|e| format!("Failed to write {}: {}", path, e))
+}
+
+/// Check a new domain and queue its registration block, signed with the active key
+fn register_domain(ctx: &Context, name: &str, records_json: &str) -> serde_json::Value {
+    use sha2::{Digest, Sha256};
+
+    let keystore = match ctx.get_keystore() {
+        Some(keystore) => keystore.clone(),
+        None => return serde_json::json!({"error": "No keystore loaded"}),
+    };
+    let name = name.trim().trim_end_matches('.').to_lowercase();
+    if !is_valid_domain_name(&name) {
+        return serde_json::json!({"error": format!("Invalid domain name '{}'", name)});
+    }
+    if ctx.chain.get_domain_transaction(&name).is_some() {
+        return serde_json::json!({"error": format!("Domain {} is already taken", name)});
+    }
+    let records: Vec<DnsRecord> = match serde_json::from_str(records_json) {
+        Ok(records) => records,
+        Err(e) => return serde_json::json!({"error": format!("Invalid records: {}", e)}),
+    };
+
+    let pubkey = keystore.get_public();
+    if let Err(reason) = check_can_mine(ctx, &name, &pubkey) {
+        return serde_json::json!({"error": reason});
+    }
+
+    let zone = name.rsplit('.').next().unwrap_or_default().to_string();
+    let data = serde_json::json!({
+        "zone": zone,
+        "records": records,
+        "contacts": [],
+        "owners": [],
+    });
+    let transaction = Transaction::from_str(
+        name.clone(),
+        CLASS_DOMAIN.to_owned(),
+        data.to_string(),
+        pubkey.clone(),
+        keystore.get_encryption_public(),
+    );
+    let tx_hash = match serde_json::to_string(&transaction) {
+        Ok(serialized) => Bytes::from_bytes(&Sha256::digest(serialized.as_bytes())).to_string(),
+        Err(e) => return serde_json::json!({"error": format!("Failed to encode transaction: {}", e)}),
+    };
+    let identity = transaction.identity.to_string();
+    let block = Block::new(Some(transaction), pubkey, Bytes::default(), DOMAIN_DIFFICULTY);
+    queue_pending_block(&name, "register", block, keystore);
+
+    add_log_message(format!("Registration of {} queued for mining", name));
+    serde_json::json!({
+        "name": name,
+        "tx_hash": tx_hash,
+        "identity": identity,
+        "queued": true,
+        "error": null,
+    })
+}
+
+/// Same rules the miner and other nodes apply: zone, key age, cooldown
+fn check_can_mine(ctx: &Context, name: &str, pubkey: &Bytes) -> Result<(), String> {
+    use alfis::blockchain::types::MineResult;
+
+    match ctx.chain.can_mine_domain(ctx.chain.get_height(), name, pubkey) {
+        MineResult::Fine => Ok(()),
+        MineResult::WrongName => Err("Wrong domain name".to_string()),
+        MineResult::WrongData => Err("Wrong domain data".to_string()),
+        MineResult::WrongKey => Err("This key can't register domains yet, not enough confirmations".to_string()),
+        MineResult::WrongZone => Err("Unknown or closed zone".to_string()),
+        MineResult::NotOwned => Err("Domain is owned by another key".to_string()),
+        MineResult::Cooldown { time } => Err(format!("Key is cooling down, try again in {} s", time)),
+    }
+}
+
+/// Check the domains of the active key once in a while and queue renewals of the expiring ones,
+/// exits when auto-renewal is turned off
+fn auto_renew_worker() {
+    loop {
+        let threshold = AUTO_RENEW_THRESHOLD_BLOCKS.load(Ordering::Relaxed);
+        if threshold == 0 {
+            AUTO_RENEW_WORKER.store(false, Ordering::Release);
+            // Turned on again right before we stopped, keep going
+            if AUTO_RENEW_THRESHOLD_BLOCKS.load(Ordering::Relaxed) == 0 || AUTO_RENEW_WORKER.swap(true, Ordering::AcqRel) {
+                return;
+            }
+            continue;
+        }
+        if let Some(context) = running_context() {
+            if let Ok(ctx) = context.lock() {
+                renew_expiring_domains(&ctx, threshold);
+            }
+        }
+        thread::sleep(AUTO_RENEW_CHECK_INTERVAL);
+    }
+}
+
+/// Queue a renewal for every owned domain expiring within `threshold_blocks`,
+/// each one is reported with an "auto_renew" event
+fn renew_expiring_domains(ctx: &Context, threshold_blocks: u64) {
+    let keystore = match ctx.get_keystore() {
+        Some(keystore) => keystore.clone(),
+        None => return,
+    };
+    let block_interval = network_health(&ctx.chain)["avg_block_interval_secs"]
+        .as_u64()
+        .filter(|interval| *interval > 0)
+        .unwrap_or(DEFAULT_BLOCK_INTERVAL_SECS);
+    let window = threshold_blocks.saturating_mul(block_interval).min(i64::MAX as u64) as i64;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    // A renewal waiting to be mined shouldn't be queued again every check
+    let pending: HashSet<String> = match PENDING_BLOCKS.lock() {
+        Ok(pending) => pending.iter().map(|block| block.domain.clone()).collect(),
+        Err(_) => return,
+    };
+
+    let pubkey = keystore.get_public();
+    for (_identity, (domain, timestamp, data)) in ctx.chain.get_my_domains(Some(&keystore)) {
+        let expiry = timestamp + DOMAIN_LIFETIME;
+        if expiry - now > window || pending.contains(&domain) {
+            continue;
+        }
+        let result = check_can_mine(ctx, &domain, &pubkey).and_then(|_| serde_json::to_string(&data).map_err(|e| e.to_string()));
+        let error = match result {
+            Ok(data) => {
+                let transaction = Transaction::from_str(
+                    domain.clone(),
+                    CLASS_DOMAIN.to_owned(),
+                    data,
+                    pubkey.clone(),
+                    keystore.get_encryption_public(),
+                );
+                let block = Block::new(Some(transaction), pubkey.clone(), Bytes::default(), DOMAIN_DIFFICULTY);
+                queue_pending_block(&domain, "renew", block, keystore.clone());
+                add_log_message(format!("Renewal of {} queued, it expires at {}", domain, expiry));
+                None
+            }
+            Err(e) => {
+                add_log_message(format!("Can't renew {}: {}", domain, e));
+                Some(e)
+            }
+        };
+        emit_app_event(serde_json::json!({
+            "event": "auto_renew",
+            "domain": domain,
+            "expiry": expiry,
+            "queued": error.is_none(),
+            "error": error,
+        }));
+    }
+}
+
+/// Domain names are lowercase labels of letters, digits and inner hyphens, with a zone
+fn is_valid_domain_name(name: &str) -> bool {
+    let labels: Vec<&str> = name.split('.').collect();
+    labels.len() >= 2
+        && name.len() <= 253
+        && labels.iter().all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        })
+}
+
+/// Put a block in the queue of blocks waiting to be mined
+fn queue_pending_block(domain: &str, action: &'static str, block: Block, keystore: Keystore) {
+    let submitted = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    if let Ok(mut pending) = PENDING_BLOCKS.lock() {
+        pending.push(PendingBlock { domain: domain.to_string(), action, block, keystore, submitted, mining: false });
+    }
+    feed_miner();
+}
+
+/// Start the core miner with the configured threads and give it all pending blocks
+fn start_miner(context: &Arc<Mutex<Context>>) {
+    let threads = MINING_THREADS.load(Ordering::Relaxed);
+    if threads == 0 || state().miner.is_some() {
+        return;
+    }
+    if let Ok(mut ctx) = context.lock() {
+        ctx.settings.mining.threads = threads;
+        ctx.settings.mining.lower = true;
+    }
+    let mut miner = Miner::new(Arc::clone(context));
+    miner.start_mining_thread();
+    state().miner = Some(Arc::new(Mutex::new(miner)));
+    add_log_message(format!("Mining started with {} threads", threads));
+    feed_miner();
+}
+
+/// Stop the core miner, blocks it hasn't mined stay pending
+fn stop_miner() {
+    let miner = state().miner.take();
+    if let Some(miner) = miner {
+        if let Ok(mut miner) = miner.lock() {
+            miner.stop();
+        }
+        if let Ok(mut reports) = MINER_REPORTS.lock() {
+            reports.started = None;
+        }
+        add_log_message("Mining stopped".to_string());
+    }
+    if let Ok(mut pending) = PENDING_BLOCKS.lock() {
+        pending.iter_mut().for_each(|block| block.mining = false);
+    }
+}
+
+/// Give the pending blocks the miner doesn't have yet to it
+fn feed_miner() {
+    let miner = match state().miner.clone() {
+        Some(miner) => miner,
+        None => return,
+    };
+    let (mut pending, mut miner) = match (PENDING_BLOCKS.lock(), miner.lock()) {
+        (Ok(pending), Ok(miner)) => (pending, miner),
+        _ => return,
+    };
+    for block in pending.iter_mut().filter(|block| !block.mining) {
+        miner.add_block(block.block.clone(), block.keystore.clone());
+        block.mining = true;
+    }
+}
+
+/// Drop the pending block the miner just added to the chain
+fn remove_mined_block(context: &Arc<Mutex<Context>>) {
+    let mined = match context.lock() {
+        Ok(ctx) => ctx.chain.get_block(ctx.chain.get_height()).and_then(|block| block.transaction),
+        Err(_) => return,
+    };
+    let mined = match mined {
+        Some(transaction) => transaction,
+        None => return,
+    };
+    if let Ok(mut pending) = PENDING_BLOCKS.lock() {
+        let position = pending.iter().position(|block| {
+            block.mining
+                && block.block.transaction.as_ref().map_or(false, |t| {
+                    t.identity == mined.identity && t.signing == mined.signing && t.data == mined.data
+                })
+        });
+        if let Some(position) = position {
+            let block = pending.remove(position);
+            add_log_message(format!("Mined the {} block of {}", block.action, block.domain));
+        }
+    }
+}
+
+/// Threads the miner may use: what was asked for, at most half the cores and MAX_MINING_THREADS
+fn mining_thread_cap(threads: usize) -> usize {
+    let cores = thread::available_parallelism().map(|n| n.get()).unwrap_or(2);
+    threads.min((cores / 2).max(1)).min(MAX_MINING_THREADS)
+}
+
+/// Queue a maintenance task and make sure a worker is waiting for the right moment to run it
+fn schedule_maintenance(task: &str, after: Duration) -> bool {
+    if !MAINTENANCE_TASKS.contains(&task) {
+        return false;
+    }
+    let mut state = match MAINTENANCE.lock() {
+        Ok(state) => state,
+        Err(_) => return false,
+    };
+    if state.queue.iter().any(|(queued, _)| queued == task) {
+        return true;
+    }
+    state.queue.push((task.to_string(), Instant::now() + after));
+    if !state.worker_active {
+        let spawned = thread::Builder::new()
+            .name("Maintenance".to_string())
+            .spawn(maintenance_worker);
+        match spawned {
+            Ok(_) => state.worker_active = true,
+            Err(e) => error!("Failed to start maintenance thread: {}", e),
+        }
+    }
+    MAINTENANCE_WAKE.notify_all();
+    true
+}
+
+/// Run due tasks one by one while the device stays idle and charging, exits when the queue is empty
+fn maintenance_worker() {
+    loop {
+        let task = {
+            let mut state = match MAINTENANCE.lock() {
+                Ok(state) => state,
+                Err(_) => return,
+            };
+            loop {
+                if state.queue.is_empty() {
+                    state.worker_active = false;
+                    return;
+                }
+                let now = Instant::now();
+                if state.can_run() {
+                    if let Some(pos) = state.queue.iter().position(|(_, not_before)| *not_before <= now) {
+                        let (task, _) = state.queue.remove(pos);
+                        state.running = Some(task.clone());
+                        break task;
+                    }
+                }
+                state = match MAINTENANCE_WAKE.wait_timeout(state, MAINTENANCE_POLL_INTERVAL) {
+                    Ok((state, _)) => state,
+                    Err(_) => return,
+                };
+            }
+        };
+
+        add_log_message(format!("Running maintenance task '{}'", task));
+        let started = Instant::now();
+        let result = run_maintenance_task(&task);
+        let epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        match &result {
+            Ok(_) => add_log_message(format!("Maintenance task '{}' finished in {} s", task, started.elapsed().as_secs())),
+            Err(e) => add_log_message(format!("Maintenance task '{}' failed: {}", task, e)),
+        }
+        if let Ok(mut state) = MAINTENANCE.lock() {
+            state.running = None;
+            state.last_run = Some(serde_json::json!({
+                "task": task,
+                "finished_epoch": epoch,
+                "duration_ms": started.elapsed().as_millis() as u64,
+                "error": result.err(),
+            }));
+        }
+    }
+}
+
+fn run_maintenance_task(task: &str) -> Result<(), String> {
+    let db_path = chain_db_path().ok_or("Chain database location is not known yet")?;
+    let mut db = sqlite::open(&db_path).map_err(|e| e.to_string())?;
+    // The node keeps its own connection open, wait for it instead of failing at once
+    db.set_busy_timeout(CHAIN_DB_BUSY_TIMEOUT_MS).map_err(|e| e.to_string())?;
+    match task {
+        "vacuum" => db.execute("VACUUM;").map_err(|e| e.to_string()),
+        "integrity_check" => {
+            let problems = integrity_problems(&db, "integrity_check").map_err(|e| e.to_string())?;
+            match problems.is_empty() {
+                true => Ok(()),
+                false => Err(problems.join("; ")),
+            }
+        }
+        _ => Err(format!("Unknown maintenance task '{}'", task)),
+    }
+}
+
+/// Run an integrity pragma (integrity_check or the faster quick_check), empty if the file is fine
+fn integrity_problems(db: &sqlite::Connection, pragma: &str) -> Result<Vec<String>, sqlite::Error> {
+    let mut statement = db.prepare(format!("PRAGMA {}({});", pragma, MAX_INTEGRITY_PROBLEMS))?;
+    let mut problems = Vec::new();
+    while let sqlite::State::Row = statement.next()? {
+        let line: String = statement.read(0)?;
+        if line != "ok" {
+            problems.push(line);
+        }
+    }
+    Ok(problems)
+}
+
+/// Check the database file before Chain opens it, Chain::new panics on a damaged file.
+/// Damaged blocks are cut off, a file that is still broken after that is moved aside,
+/// so the chain syncs again into a new file instead of falling back to memory.
+fn preflight_chain_db(db_path: &str) {
+    if !std::path::Path::new(db_path).exists() {
+        return;
+    }
+    let problems = match sqlite::open(db_path).and_then(|db| integrity_problems(&db, "quick_check")) {
+        Ok(problems) => problems,
+        Err(e) => vec![e.to_string()],
+    };
+    if problems.is_empty() {
+        return;
+    }
+    add_log_message(format!("WARNING: Chain database is damaged: {}", problems.join("; ")));
+    match repair_chain_db(db_path) {
+        Ok((height, discarded)) => add_log_message(format!("Database repaired: {} blocks discarded, chain height is {}", discarded, height)),
+        Err(e) => warn!("Database repair failed: {}", e),
+    }
+    let fixed = sqlite::open(db_path)
+        .and_then(|db| integrity_problems(&db, "quick_check"))
+        .map(|problems| problems.is_empty())
+        .unwrap_or(false);
+    if fixed {
+        return;
+    }
+    let corrupt_path = format!("{}.corrupt", db_path);
+    match std::fs::rename(db_path, &corrupt_path) {
+        Ok(_) => {
+            // The WAL and shared memory files belong to the moved file
+            let _ = std::fs::remove_file(format!("{}-wal", db_path));
+            let _ = std::fs::remove_file(format!("{}-shm", db_path));
+            add_log_message(format!("WARNING: Damaged chain database moved to {}, the chain will sync again", corrupt_path));
+        }
+        Err(e) => warn!("Failed to move damaged database aside: {}", e),
+    }
+}
+
+/// Find the first block that is missing, out of order or not linked to its predecessor
+/// and drop it with everything above. Returns the new height and the number of dropped blocks.
+fn repair_chain_db(db_path: &str) -> Result<(u64, u64), String> {
+    let db = sqlite::open(db_path).map_err(|e| e.to_string())?;
+    let mut first_bad: Option<i64> = None;
+    let mut last_valid: i64 = 0;
+    {
+        let mut statement = db
+            .prepare("SELECT id, prev_block_hash, hash FROM blocks ORDER BY id ASC;")
+            .map_err(|e| e.to_string())?;
+        let mut last_hash: Vec<u8> = Vec::new();
+        loop {
+            match statement.next() {
+                Ok(sqlite::State::Row) => {}
+                Ok(sqlite::State::Done) => break,
+                Err(e) => {
+                    // Unreadable page, everything from here on is suspect
+                    warn!("Failed to read block after {}: {}", last_valid, e);
+                    first_bad = Some(last_valid + 1);
+                    break;
+                }
+            }
+            let row = read_block_link(&statement);
+            let valid = match &row {
+                Ok((id, prev_hash, hash)) => {
+                    *id == last_valid + 1 && !hash.is_empty() && (*id == 1 || *prev_hash == last_hash)
+                }
+                Err(_) => false,
+            };
+            if !valid {
+                first_bad = Some(last_valid + 1);
+                break;
+            }
+            if let Ok((id, _, hash)) = row {
+                last_valid = id;
+                last_hash = hash;
+            }
+        }
+    }
+
+    let first_bad = match first_bad {
+        Some(index) => index,
+        None => return Ok((last_valid as u64, 0)),
+    };
+    if first_bad <= 1 {
+        return Err("No valid blocks found, a full reset is needed".to_string());
+    }
+
+    let mut discarded = 0u64;
+    {
+        let mut statement = db.prepare("SELECT COUNT(*) FROM blocks WHERE id >= ?;").map_err(|e| e.to_string())?;
+        statement.bind((1, first_bad)).map_err(|e| e.to_string())?;
+        if let Ok(sqlite::State::Row) = statement.next() {
+            discarded = statement.read::<i64, _>(0).unwrap_or(0) as u64;
+        }
+    }
+    let truncate = format!(
+        "BEGIN; DELETE FROM blocks WHERE id >= {0}; DELETE FROM domains WHERE id >= {0}; COMMIT;",
+        first_bad
+    );
+    if let Err(e) = db.execute(&truncate) {
+        let _ = db.execute("ROLLBACK;");
+        return Err(format!("Failed to truncate the chain: {}", e));
+    }
+    Ok((last_valid as u64, discarded))
+}
+
+fn read_block_link(statement: &sqlite::Statement) -> Result<(i64, Vec<u8>, Vec<u8>), sqlite::Error> {
+    Ok((statement.read(0)?, statement.read(1)?, statement.read(2)?))
+}
+
+/// Check hashes, signatures and links of every block, reporting progress as app events
+fn verify_chain_full(context: &Arc<Mutex<Context>>) -> serde_json::Value {
+    use alfis::blockchain::hash_utils::{check_block_hash, check_block_signature};
+
+    let height = match context.lock() {
+        Ok(ctx) => ctx.chain.get_height(),
+        Err(_) => return serde_json::json!({"ok": false, "blocks_verified": 0, "first_bad_block": null, "error": "Failed to acquire context lock"}),
+    };
+    let mut previous_hash: Option<Bytes> = None;
+    let mut verified = 0u64;
+    let mut index = 1u64;
+    while index <= height {
+        if VERIFY_CANCELLED.load(Ordering::Relaxed) {
+            return serde_json::json!({"ok": false, "blocks_verified": verified, "first_bad_block": null, "error": "Cancelled"});
+        }
+        let ctx = match context.lock() {
+            Ok(ctx) => ctx,
+            Err(_) => return serde_json::json!({"ok": false, "blocks_verified": verified, "first_bad_block": null, "error": "Failed to acquire context lock"}),
+        };
+        let last = (index + VERIFY_BATCH_BLOCKS - 1).min(height);
+        for i in index..=last {
+            let problem = match ctx.chain.get_block(i) {
+                None => Some("missing"),
+                Some(block) if block.index != i => Some("wrong index"),
+                Some(block) if previous_hash.as_ref().map_or(false, |hash| *hash != block.prev_block_hash) => Some("not linked to the previous block"),
+                Some(block) if !check_block_hash(&block) => Some("wrong hash"),
+                Some(block) if !check_block_signature(&block) => Some("wrong signature"),
+                Some(block) => {
+                    previous_hash = Some(block.hash.clone());
+                    None
+                }
+            };
+            if let Some(problem) = problem {
+                return serde_json::json!({
+                    "ok": false,
+                    "blocks_verified": verified,
+                    "first_bad_block": i,
+                    "error": format!("Block {}: {}", i, problem),
+                });
+            }
+            verified += 1;
+            if verified % VERIFY_PROGRESS_EVERY == 0 {
+                emit_app_event(serde_json::json!({"event": "verify_progress", "verified": verified, "height": height}));
+            }
+        }
+        drop(ctx);
+        index = last + 1;
+    }
+
+    emit_app_event(serde_json::json!({"event": "verify_progress", "verified": verified, "height": height}));
+    serde_json::json!({"ok": true, "blocks_verified": verified, "first_bad_block": null, "error": null})
+}
+
+fn dns_stats() -> serde_json::Value {
+    let (queries, blocks, peers, uptime) = if DNS_RUNNING.load(Ordering::Acquire) {
+        let uptime = uptime_secs(unix_time(), DNS_START_TIME.load(Ordering::Relaxed));
+
+        // Get comprehensive statistics
+        let (server_ctx, alfis_ctx) = {
+            let state = state();
+            (state.server_context.clone(), state.alfis_context.clone())
+        };
+        match (server_ctx, alfis_ctx) {
+            (Some(server_ctx), Some(alfis_ctx)) => {
+                let udp_queries = server_ctx.statistics.get_udp_query_count();
+                let tcp_queries = server_ctx.statistics.get_tcp_query_count();
+                let total_queries = udp_queries + tcp_queries;
+
+                // Get blockchain statistics
+                let (block_count, peer_count) = if let Ok(ctx_guard) = alfis_ctx.lock() {
+                    let blocks = ctx_guard.chain.get_height();
+                    let peers = NETWORK_PEER_COUNT.load(Ordering::Relaxed);
+                    // Add debug logging for block count
+                    if blocks > 0 {
+                        log::debug!("Blockchain height: {}", blocks);
+                    }
+                    (blocks, peers)
+                } else {
+                    log::warn!("Failed to acquire context lock for statistics");
+                    (0, 0)
+                };
+
+                (total_queries, block_count, peer_count, uptime)
+            }
+            _ => (0, 0, 0, uptime)
+        }
+    } else {
+        (0, 0, 0, 0)
+    };
+
+    let mut stats = serde_json::json!({
+        "blocks": blocks,
+        "peers": peers,
+        "queries": queries,
+        "uptime": uptime,
+        "reorg_count": REORG_COUNT.load(Ordering::Relaxed),
+    });
+    let session = session_counters(queries);
+    let total = stats_totals(&session);
+    if let Some(stats) = stats.as_object_mut() {
+        stats.extend(session.clone());
+        stats.insert("workers".to_string(), UDP_POOL.stats());
+        stats.insert("tcp_connections_active".to_string(), TCP_CONNECTIONS_ACTIVE.load(Ordering::Relaxed).into());
+        stats.insert("session".to_string(), serde_json::Value::Object(session));
+        stats.insert("total".to_string(), serde_json::Value::Object(total));
+    }
+    stats
+}
+
+/// Counters of this session, the ones kept as lifetime totals
+fn session_counters(queries: u64) -> serde_json::Map<String, serde_json::Value> {
+    let mut session = DNS_COUNTERS.snapshot();
+    session.insert("queries".to_string(), queries.into());
+    session
+}
+
+/// Previous sessions plus this one
+fn stats_totals(session: &serde_json::Map<String, serde_json::Value>) -> serde_json::Map<String, serde_json::Value> {
+    let mut totals = STATS_TOTALS.lock().map(|totals| totals.clone()).unwrap_or_default();
+    for (name, value) in session {
+        let previous = totals.get(name).and_then(|v| v.as_u64()).unwrap_or(0);
+        if let Some(value) = value.as_u64() {
+            totals.insert(name.clone(), previous.saturating_add(value).into());
+        }
+    }
+    totals
+}
+
+/// Load the totals of previous sessions, a missing or damaged file starts from zero
+fn load_stats_totals(work_dir: &str) {
+    let path = format!("{}/{}", work_dir, STATS_FILE);
+    let totals = match std::fs::read_to_string(&path) {
+        Ok(text) => match serde_json::from_str::<serde_json::Value>(&text) {
+            Ok(serde_json::Value::Object(totals)) => totals.into_iter().filter(|(_, value)| value.is_u64()).collect(),
+            _ => {
+                warn!("Ignoring damaged statistics file {}", path);
+                serde_json::Map::new()
+            }
+        },
+        Err(_) => serde_json::Map::new(),
+    };
+    if let Ok(mut current) = STATS_TOTALS.lock() {
+        *current = totals;
+    }
+}
+
+/// Add this session to the totals and write them to the work directory
+fn save_stats_totals() {
+    let queries = match state().server_context.clone() {
+        Some(server_ctx) => server_ctx.statistics.get_udp_query_count() + server_ctx.statistics.get_tcp_query_count(),
+        None => 0,
+    };
+    let totals = stats_totals(&session_counters(queries));
+    let work_dir = match WORK_DIR.lock().ok().and_then(|dir| dir.clone()) {
+        Some(dir) => dir,
+        None => return,
+    };
+    let path = format!("{}/{}", work_dir, STATS_FILE);
+    let temp_path = format!("{}.tmp", path);
+    let result = std::fs::write(&temp_path, serde_json::Value::Object(totals.clone()).to_string())
+        .and_then(|_| std::fs::rename(&temp_path, &path));
+    match result {
+        Ok(_) => {
+            // The session counters are in the totals now
+            DNS_COUNTERS.reset();
+            if let Ok(mut current) = STATS_TOTALS.lock() {
+                *current = totals;
+            }
+        }
+        Err(e) => warn!("Failed to save statistics to {}: {}", path, e),
+    }
+}
+
+/// Dashboard view: uptime, sync state and heights, queries by protocol, cache and last block time
+fn dns_stats_detailed() -> serde_json::Value {
+    let running = DNS_RUNNING.load(Ordering::Acquire);
+    let uptime = match running {
+        true => uptime_secs(unix_time(), DNS_START_TIME.load(Ordering::Relaxed)),
+        false => 0,
+    };
+    let (udp_queries, tcp_queries) = match running_server_context() {
+        Some(server_ctx) => (server_ctx.statistics.get_udp_query_count(), server_ctx.statistics.get_tcp_query_count()),
+        None => (0, 0),
+    };
+    let height = match running_context() {
+        Some(context) => context.lock().map(|ctx| ctx.chain.get_height()).unwrap_or(0),
+        None => 0,
+    };
+    let sync_state = match SYNC_STATE.load(Ordering::Relaxed) {
+        _ if !running => "idle",
+        SYNC_STATE_SYNCING => "syncing",
+        SYNC_STATE_SYNCED => "synced",
+        _ => "idle",
+    };
+    let last_block = match LAST_BLOCK_RECEIVED.load(Ordering::Relaxed) {
+        0 => serde_json::Value::Null,
+        time => time.into(),
+    };
+
+    serde_json::json!({
+        "uptime_secs": uptime,
+        "network_paused": NETWORK_PAUSED.load(Ordering::Relaxed),
+        "mining": {
+            "active": running && state().miner.is_some(),
+            "threads": MINING_THREADS.load(Ordering::Relaxed),
+            "hash_attempts": MINING_HASHES.load(Ordering::Relaxed),
+            "mined_blocks": MINED_BLOCKS.load(Ordering::Relaxed),
+        },
+        "database": {
+            "in_memory": running && IN_MEMORY_DB.load(Ordering::Relaxed),
+            "wal": running && CHAIN_DB_WAL.load(Ordering::Relaxed),
+        },
+        "sync": {
+            "state": sync_state,
+            "height": height,
+            "network_height": NETWORK_HEIGHT.load(Ordering::Relaxed).max(height),
+        },
+        "peers": NETWORK_PEER_COUNT.load(Ordering::Relaxed),
+        "queries": {
+            "udp": udp_queries,
+            "tcp": tcp_queries,
+            "total": udp_queries + tcp_queries,
+        },
+        "cache": cache_stats(),
+        "last_block_received": last_block,
+    })
+}
+
+/// Resolver cache size and hit rate, hitRate is a percentage with one decimal
+fn cache_stats() -> serde_json::Value {
+    let (entries, bytes) = match RESPONSE_CACHE.lock() {
+        Ok(cache) => (cache.len(), cache.bytes),
+        Err(_) => (0, 0),
+    };
+    let hits = DNS_COUNTERS.cache_hits.load(Ordering::Relaxed);
+    let misses = DNS_COUNTERS.cache_misses.load(Ordering::Relaxed);
+    let hit_rate = match hits + misses {
+        0 => 0.0,
+        total => (hits as f64 * 1000.0 / total as f64).round() / 10.0,
+    };
+
+    serde_json::json!({
+        "entries": entries,
+        "hits": hits,
+        "misses": misses,
+        "hitRate": hit_rate,
+        "bytesApprox": bytes,
+    })
+}
+
+/// Forwarders in configured order, then the system DNS fallback servers
+fn forwarder_stats() -> serde_json::Value {
+    let forwarders = FORWARDERS.read().map(|f| f.clone()).unwrap_or_default();
+    let fallback = SYSTEM_DNS_FALLBACK.read().map(|f| f.clone()).unwrap_or_default();
+    let upstreams = forwarders
+        .iter()
+        .map(|upstream| (upstream, false))
+        .chain(fallback.iter().map(|upstream| (upstream, true)));
+    let stats = match FORWARDER_STATS.lock() {
+        Ok(stats) => upstreams
+            .map(|(upstream, is_fallback)| {
+                let empty = UpstreamStats::default();
+                let s = stats.get(upstream).unwrap_or(&empty);
+                let avg_latency_ms = if s.successes == 0 { 0 } else { s.total_latency_ms / s.successes };
+                serde_json::json!({
+                    "upstream": upstream,
+                    "fallback": is_fallback,
+                    "queries": s.queries,
+                    "successes": s.successes,
+                    "timeouts": s.timeouts,
+                    "avg_latency_ms": avg_latency_ms,
+                })
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+
+    serde_json::Value::Array(stats)
+}
+
+fn health_status() -> serde_json::Value {
+    let (context, network_running) = {
+        let state = state();
+        let network_running = state.network_handle.as_ref().map(|handle| !handle.is_finished()).unwrap_or(false);
+        (state.alfis_context.clone(), network_running)
+    };
+    let height = context.and_then(|context| context.lock().ok().map(|ctx| ctx.chain.get_height()));
+    let dns_running = DNS_RUNNING.load(Ordering::Acquire);
+    let memory_constrained = memory_constrained();
+    let maintenance = match MAINTENANCE.lock() {
+        Ok(state) => state.status(),
+        Err(_) => serde_json::Value::Null,
+    };
+
+    serde_json::json!({
+        "dns_running": dns_running,
+        "network_running": network_running,
+        "height": height,
+        "memory_constrained": memory_constrained,
+        "maintenance": maintenance,
+    })
+}
+
+fn native_memory_stats() -> serde_json::Value {
+    let (rss, peak_rss) = process_memory();
+    let (cache_entries, cache_bytes) = match RESPONSE_CACHE.lock() {
+        Ok(cache) => (cache.len(), cache.bytes),
+        Err(_) => (0, 0),
+    };
+    let log_lines = LOG_BUFFER.lock().map(|buffer| buffer.len()).unwrap_or_default();
+    let pending_blocks = PENDING_BLOCKS.lock().map(|pending| pending.len()).unwrap_or_default();
+
+    serde_json::json!({
+        "rss_bytes": rss,
+        "peak_rss_bytes": peak_rss,
+        "cache_entries": cache_entries,
+        "cache_capacity": cache_capacity(),
+        "cache_approx_bytes": cache_bytes,
+        "log_buffer_lines": log_lines,
+        "log_buffer_bytes": LOG_BUFFER_BYTES.load(Ordering::Relaxed),
+        "log_buffer_max_bytes": LOG_BUFFER_MAX_BYTES.load(Ordering::Relaxed),
+        "udp_pool": UDP_POOL.stats(),
+        "tcp_pool": TCP_POOL.stats(),
+        "pending_blocks": pending_blocks,
+        "memory_constrained": memory_constrained(),
+    })
+}
+
+/// Changed config fields and runtime options, as returned by getConfigDiff
+fn config_diff_report() -> serde_json::Value {
+    let config_path = CONFIG_PATH.lock().ok().and_then(|guard| guard.clone());
+    let config = match config_path {
+        Some(config_path) => match config_diff(&config_path) {
+            Ok(changes) => serde_json::Value::Object(changes),
+            Err(e) => serde_json::json!({"error": e}),
+        },
+        None => serde_json::json!({"error": "Config file is not known yet"}),
+    };
+
+    let mut runtime = runtime_overrides();
+    // Forwarders can be replaced without touching the config file
+    if DNS_RUNNING.load(Ordering::Acquire) {
+        let forwarders = FORWARDERS.read().map(|f| f.clone()).unwrap_or_default();
+        let configured = CONFIG_PATH
+            .lock()
+            .ok()
+            .and_then(|guard| guard.clone())
+            .and_then(|path| Settings::load(&path))
+            .map(|settings| settings.dns.forwarders);
+        if let Some(configured) = configured {
+            if configured != forwarders {
+                runtime.insert("forwarders".to_string(), serde_json::json!({"default": configured, "current": forwarders}));
+            }
+        }
+    }
+
+    serde_json::json!({
+        "config": config,
+        "runtime": runtime,
+    })
+}
+
+/// Everything useful for a bug report in one document. Keys never get here; query names
+/// are left out unless query logging is on. Resolves the configured peers.
+fn diagnostic_bundle() -> serde_json::Value {
+    let generated_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let network_health = match running_context() {
+        Some(context) => match context.lock() {
+            Ok(ctx) => network_health(&ctx.chain),
+            Err(_) => serde_json::Value::Null,
+        },
+        None => serde_json::Value::Null,
+    };
+
+    let query_logging = QUERY_LOGGING.load(Ordering::Relaxed);
+    let logs: Vec<String> = match LOG_BUFFER.lock() {
+        Ok(buffer) => buffer
+            .iter()
+            .filter(|line| query_logging || !reveals_query_names(line))
+            .cloned()
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    let network_errors: Vec<&String> = logs
+        .iter()
+        .filter(|line| {
+            let line = line.to_lowercase();
+            line.contains("error") || line.contains("failed")
+        })
+        .collect();
+    let panics = match RECENT_PANICS.lock() {
+        Ok(panics) => serde_json::Value::Array(panics.iter().cloned().collect()),
+        Err(_) => serde_json::Value::Array(Vec::new()),
+    };
+
+    serde_json::json!({
+        "generated_at": generated_at,
+        "version": native_version(),
+        "health": health_status(),
+        "stats": dns_stats(),
+        "network_health": network_health,
+        "peers": peer_network_info(),
+        "forwarders": forwarder_stats(),
+        "memory": native_memory_stats(),
+        "config_diff": config_diff_report(),
+        "network_errors": network_errors,
+        "panics": panics,
+        "logs": logs,
+        "query_names_redacted": !query_logging,
+    })
+}
+
+/// Core and crate versions, the git commit is only known when built from a git checkout
+fn native_version() -> serde_json::Value {
+    serde_json::json!({
+        "core": ALFIS_CORE_VERSION,
+        "crate": env!("CARGO_PKG_VERSION"),
+        "commit": option_env!("ALFIS_GIT_COMMIT"),
+    })
+}
+
+/// Log lines written per query or mirrored from debug logging, they may name queried domains
+fn reveals_query_names(line: &str) -> bool {
+    let message = line.split_once("] ").map_or(line, |(_, message)| message);
+    ["QUERY ", "TRACE ", "DEBUG ", "Tracing queries"].iter().any(|prefix| message.starts_with(prefix))
+}
+
+/// Call the registered status listener with an event, on the calling thread
+fn notify_status(event: serde_json::Value) {
+    // Cloned so the lock isn't held while Java runs, it may unregister itself
+    let callback = match STATUS_CALLBACK.lock() {
+        Ok(callback) => match callback.as_ref() {
+            Some(callback) => callback.clone(),
+            None => return,
+        },
+        Err(_) => return,
+    };
+
+    with_attached_env(|env| {
+        let result = env.new_string(event.to_string()).and_then(|json| {
+            env.call_method(callback.as_obj(), "onStatus", "(Ljava/lang/String;)V", &[JValue::Object(&json)])
+        });
+        if let Err(e) = result {
+            warn!("Status callback failed: {}", e);
+        }
+    });
+}
+
+/// Run `f` with a JNIEnv of the current thread. A thread that isn't attached to the JVM yet
+/// is attached for the call and detached after it. Exceptions left by `f` are cleared.
+/// None if the JVM is not known yet or the thread couldn't be attached.
+fn with_attached_env<R, F: FnOnce(&mut JNIEnv) -> R>(f: F) -> Option<R> {
+    let vm = JAVA_VM.get()?;
+    let mut env = match vm.attach_current_thread() {
+        Ok(env) => env,
+        Err(e) => {
+            error!("Failed to attach thread to JVM: {}", e);
+            return None;
+        }
+    };
+    let result = f(&mut env);
+    if env.exception_check().unwrap_or(false) {
+        let _ = env.exception_describe();
+        let _ = env.exception_clear();
+    }
+    Some(result)
+}
+
+/// Handle a chain reorganization: count it, forget cached blockchain answers and notify the app
+/// with the fork point. Events may come while the chain is locked, so the chain is looked at
+/// from another thread.
+fn on_reorg(from_height: u64, to_height: u64) {
+    REORG_COUNT.fetch_add(1, Ordering::Relaxed);
+    cache_clear(Some(CacheSource::Blockchain));
+    let context = running_context();
+    let spawned = thread::Builder::new().name("Reorg".to_string()).spawn(move || {
+        let common_ancestor = context.as_ref().and_then(|context| match context.lock() {
+            Ok(ctx) => {
+                let ancestor = fork_point(&ctx.chain, to_height);
+                remember_tip_hashes(&ctx.chain);
+                ancestor
+            }
+            Err(_) => None,
+        });
+        let ancestor = match common_ancestor {
+            Some(height) => height.to_string(),
+            None => format!("below the last {} blocks", MAX_RECENT_BLOCK_HASHES),
+        };
+        add_log_message(format!("Chain reorganization: {} -> {} (common ancestor {})", from_height, to_height, ancestor));
+        warn!("Chain reorganization from height {} to {}, common ancestor {}", from_height, to_height, ancestor);
+        emit_app_event(serde_json::json!({
+            "event": "reorg",
+            "from_height": from_height,
+            "to_height": to_height,
+            "common_ancestor": common_ancestor,
+        }));
+    });
+    if let Err(e) = spawned {
+        error!("Failed to start reorg thread: {}", e);
+    }
+}
+
+/// Find the highest block that is still the one we saw before the reorg, dropping the
+/// hashes of the replaced blocks. None if the fork is older than the hashes we keep.
+fn fork_point(chain: &Chain, to_height: u64) -> Option<u64> {
+    let mut hashes = RECENT_BLOCK_HASHES.lock().ok()?;
+    // The block at to_height has just been replaced, the fork is below it
+    let ancestor = hashes
+        .iter()
+        .rev()
+        .filter(|(index, _)| *index < to_height)
+        .find(|(index, hash)| chain.get_block(*index).map_or(false, |block| block.hash == *hash))
+        .map(|(index, _)| *index);
+    match ancestor {
+        Some(ancestor) => hashes.retain(|(index, _)| *index <= ancestor),
+        None => hashes.clear(),
+    }
+    ancestor
+}
+
+/// Remember the hashes of blocks added since the last call, in a thread as the chain may be
+/// locked by whoever posted the event. Calls while the thread waits are covered by it.
+fn record_block_hashes() {
+    if RECORDING_BLOCK_HASHES.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    let context = match running_context() {
+        Some(context) => context,
+        None => {
+            RECORDING_BLOCK_HASHES.store(false, Ordering::Release);
+            return;
+        }
+    };
+    let spawned = thread::Builder::new().name("Block-Hashes".to_string()).spawn(move || {
+        let ctx = context.lock();
+        // Blocks added from now on are left to the next thread
+        RECORDING_BLOCK_HASHES.store(false, Ordering::Release);
+        if let Ok(ctx) = ctx {
+            remember_tip_hashes(&ctx.chain);
+        }
+    });
+    if let Err(e) = spawned {
+        RECORDING_BLOCK_HASHES.store(false, Ordering::Release);
+        debug!("Failed to start block hashes thread: {}", e);
+    }
+}
+
+/// Add the hashes of the chain above the last remembered block, keeping the last ones only
+fn remember_tip_hashes(chain: &Chain) {
+    let height = chain.get_height();
+    let mut hashes = match RECENT_BLOCK_HASHES.lock() {
+        Ok(hashes) => hashes,
+        Err(_) => return,
+    };
+    let next = hashes.back().map(|(index, _)| index + 1).unwrap_or(1);
+    let first = next.max(height.saturating_sub(MAX_RECENT_BLOCK_HASHES as u64 - 1)).max(1);
+    for index in first..=height {
+        if let Some(block) = chain.get_block(index) {
+            hashes.push_back((index, block.hash));
+        }
+    }
+    while hashes.len() > MAX_RECENT_BLOCK_HASHES {
+        hashes.pop_front();
+    }
+}
+
+/// Record every panic, even ones caught later, so the app can see that a thread crashed
+fn install_panic_hook() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            let message = if let Some(msg) = info.payload().downcast_ref::<&str>() {
+                msg.to_string()
+            } else if let Some(msg) = info.payload().downcast_ref::<String>() {
+                msg.clone()
+            } else {
+                "Unknown panic".to_string()
+            };
+            let location = info.location().map(|l| format!("{}:{}", l.file(), l.line()));
+            let thread = thread::current().name().unwrap_or("unnamed").to_string();
+            let epoch = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+
+            error!("Panic in thread '{}' at {}: {}", thread, location.as_deref().unwrap_or("unknown"), message);
+            add_log_message(format!("Panic in thread '{}': {}", thread, message));
+            if let Ok(mut panics) = RECENT_PANICS.lock() {
+                panics.push_back(serde_json::json!({
+                    "epoch": epoch,
+                    "thread": thread,
+                    "message": message,
+                    "location": location,
+                }));
+                if panics.len() > MAX_RECENT_PANICS {
+                    panics.pop_front();
+                }
+            }
+            default_hook(info);
+        }));
+    });
+}
+
+/// Queue an event for the app, dropping the oldest ones if nobody polls
+fn emit_app_event(event: serde_json::Value) {
+    if let Ok(mut queue) = APP_EVENTS.lock() {
+        queue.push_back(event.to_string());
+        if queue.len() > MAX_APP_EVENTS {
+            queue.pop_front();
+        }
+    }
+}
+
+/// Resolve a request and post-process the response, shared by all server loops.
+/// Also tells where the answer came from, None if it was not resolved at all.
+fn process_query(server_context: &Arc<ServerContext>, request: &DnsPacket) -> (DnsPacket, Option<CacheSource>) {
+    // Queries caught by a stop or restart get a quick SERVFAIL, the contexts are about to go
+    if DNS_SHUTDOWN_FLAG.load(Ordering::Relaxed) {
+        return (error_response(request, ResultCode::SERVFAIL), None);
+    }
+    let (mut response, source) = resolve_request(server_context, request, true);
+    apply_family_preference(server_context, request, &mut response);
+    (response, source)
+}
+
+fn family_name(family: u32) -> &'static str {
+    match family {
+        FAMILY_IPV4 => "ipv4",
+        FAMILY_IPV6 => "ipv6",
+        _ => "any",
+    }
+}
+
+/// Drop answers of the other address family when the name has addresses of the preferred one,
+/// and put the preferred family first in answers with both. Records are never made up.
+fn apply_family_preference(server_context: &Arc<ServerContext>, request: &DnsPacket, response: &mut DnsPacket) {
+    let (preferred, other) = match ADDRESS_FAMILY_PREFERENCE.load(Ordering::Relaxed) {
+        FAMILY_IPV4 => (QueryType::A, QueryType::AAAA),
+        FAMILY_IPV6 => (QueryType::AAAA, QueryType::A),
+        _ => return,
+    };
+    let question = match request.questions.first() {
+        Some(question) => question,
+        None => return,
+    };
+    if !response.answers.iter().any(|record| record.get_querytype() == other) {
+        return;
+    }
+    if question.qtype != other {
+        response.answers.sort_by_key(|record| record.get_querytype() == other);
+        return;
+    }
+
+    // A name with only addresses of the other family keeps them, it would be unreachable otherwise
+    let mut probe = DnsPacket::new();
+    probe.header.id = request.header.id;
+    probe.header.recursion_desired = true;
+    probe.questions.push(DnsQuestion::new(question.name.clone(), preferred));
+    let (preferred_response, _) = resolve_request(server_context, &probe, false);
+    if preferred_response.answers.iter().any(|record| record.get_querytype() == preferred) {
+        response.answers.retain(|record| record.get_querytype() != other);
+    }
+}
+
+/// Resolve through cache, blockchain and upstreams. Internal queries (`from_client` false)
+/// don't trigger prefetch and don't count in cache statistics.
+fn resolve_request(server_context: &Arc<ServerContext>, request: &DnsPacket, from_client: bool) -> (DnsPacket, Option<CacheSource>) {
+    use alfis::dns::context::ResolveStrategy;
+    use alfis::dns::server::execute_query;
+
+    let question = match request.questions.first() {
+        Some(question) => question,
+        None => return (execute_query(Arc::clone(server_context), request), None),
+    };
+    let tracing = is_traced(&question.name);
+    if is_refused_type(question.qtype) {
+        DNS_COUNTERS.refused_by_type.fetch_add(1, Ordering::Relaxed);
+        if tracing {
+            trace_query(question, "type is refused");
+        }
+        return (error_response(request, ResultCode::REFUSED), None);
+    }
+    // Pinned names win over the blocklist, the chain and upstreams
+    if let Some(response) = local_override_response(request, question) {
+        DNS_COUNTERS.local_overrides.fetch_add(1, Ordering::Relaxed);
+        if tracing {
+            trace_query(question, "answered by local override");
+        }
+        return (response, None);
+    }
+    // Before the cache and upstreams, blocked names never leave the device
+    if is_blocked(&question.name) {
+        DNS_COUNTERS.blocked_queries.fetch_add(1, Ordering::Relaxed);
+        if tracing {
+            trace_query(question, "name is on the blocklist");
+        }
+        return (error_response(request, ResultCode::NXDOMAIN), None);
+    }
+    let dnssec_ok = dnssec_ok(request);
+    if let Some((mut response, source)) = cache_lookup(request, question, dnssec_ok) {
+        if tracing {
+            trace_query(question, &format!("cache hit, {:?} with {} answers", response.header.rescode, response.answers.len()));
+        }
+        if from_client {
+            DNS_COUNTERS.cache_hits.fetch_add(1, Ordering::Relaxed);
+            if is_negative_response(&response) {
+                DNS_COUNTERS.negative_cache_hits.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        if take_prefetched(question) {
+            DNS_COUNTERS.prefetch_hits.fetch_add(1, Ordering::Relaxed);
+        }
+        if !dnssec_ok {
+            strip_unrequested_dnssec(&mut response, question.qtype);
+        }
+        minimize_response(&mut response);
+        return (response, Some(source));
+    }
+    if tracing {
+        trace_query(question, &format!("cache miss (dnssec_ok {})", dnssec_ok));
+    }
+    if from_client {
+        DNS_COUNTERS.cache_misses.fetch_add(1, Ordering::Relaxed);
+        if DUAL_STACK_PREFETCH.load(Ordering::Relaxed) {
+            prefetch_pair(server_context, question);
+        }
+    }
+
+    let local = lookup_local(server_context, request, question);
+    if tracing {
+        match &local {
+            Some(response) => trace_query(question, &format!("blockchain filter answered {:?}", response.header.rescode)),
+            None => trace_query(question, "not a blockchain domain, resolving upstream"),
+        }
+    }
+    let (mut response, source) = match local {
+        Some(response) => (response, CacheSource::Blockchain),
+        None => match server_context.resolve_strategy {
+            ResolveStrategy::Forward { .. } => {
+                let response = forward_query(request, question, dnssec_ok).unwrap_or_else(|| error_response(request, ResultCode::SERVFAIL));
+                (response, CacheSource::Forwarded)
+            }
+            _ => (execute_query(Arc::clone(server_context), request), CacheSource::Forwarded),
+        },
+    };
+    if source == CacheSource::Forwarded && rewrite_answers(question, &mut response) {
+        DNS_COUNTERS.rewritten_answers.fetch_add(1, Ordering::Relaxed);
+        if tracing {
+            trace_query(question, "answers rewritten by local rule");
+        }
+    }
+    // Clients validating DNSSEC themselves set DO and always get the records
+    if !dnssec_ok {
+        strip_unrequested_dnssec(&mut response, question.qtype);
+        if STRIP_DNSSEC.load(Ordering::Relaxed) && strip_dnssec_records(&mut response) {
+            DNS_COUNTERS.dnssec_stripped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    order_sections(&mut response);
+    cache_store(question, &response, source, dnssec_ok);
+    minimize_response(&mut response);
+    if tracing {
+        trace_query(question, &format!("answered {:?}, answers {:?}", response.header.rescode, response.answers));
+    }
+    (response, Some(source))
+}
+
+/// Check if resolution of the name should be traced. Patterns are exact names,
+/// or "*.example.com" for the name and all its subdomains.
+fn is_traced(name: &str) -> bool {
+    let pattern = match QUERY_TRACE.read() {
+        Ok(pattern) => match pattern.as_ref() {
+            Some(pattern) => pattern.clone(),
+            None => return false,
+        },
+        Err(_) => return false,
+    };
+    let name = name.trim_end_matches('.').to_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(base) => name == base || name.strip_suffix(base).map_or(false, |sub| sub.ends_with('.')),
+        None => name == pattern,
+    }
+}
+
+fn trace_query(question: &DnsQuestion, stage: &str) {
+    let message = format!("TRACE {} {:?}: {}", question.name, question.qtype, stage);
+    info!("{}", message);
+    add_log_message(message);
+}
+
+/// Resolve AAAA for an A query and vice versa in the background, clients usually ask for both
+fn prefetch_pair(server_context: &Arc<ServerContext>, question: &DnsQuestion) {
+    let qtype = match question.qtype {
+        QueryType::A => QueryType::AAAA,
+        QueryType::AAAA => QueryType::A,
+        _ => return,
+    };
+    let key = (question.name.to_lowercase(), qtype);
+    if RESPONSE_CACHE.lock().map(|cache| cache.contains_key(&key)).unwrap_or(true) {
+        return;
+    }
+    match PREFETCHED.lock() {
+        Ok(mut prefetched) => {
+            // Names that were never asked for again shouldn't pile up
+            if prefetched.len() >= MAX_CACHE_ENTRIES {
+                prefetched.clear();
+            }
+            if !prefetched.insert(key) {
+                return;
+            }
+        }
+        Err(_) => return,
+    }
+
+    let mut request = DnsPacket::new();
+    request.header.recursion_desired = true;
+    request.questions.push(DnsQuestion::new(question.name.clone(), qtype));
+    let server_context = Arc::clone(server_context);
+    UDP_POOL.submit(Box::new(move || {
+        // Only fills the cache, and never prefetches again
+        let _ = resolve_request(&server_context, &request, false);
+    }));
+}
+
+/// Check if the question was prefetched and forget it, so each prefetch counts once
+fn take_prefetched(question: &DnsQuestion) -> bool {
+    match PREFETCHED.lock() {
+        Ok(mut prefetched) => prefetched.remove(&(question.name.to_lowercase(), question.qtype)),
+        Err(_) => false,
+    }
+}
+
+/// In minimal responses mode drop authority and additional records from positive answers.
+/// Negative answers keep their SOA, and OPT stays as it belongs to the transport.
+fn minimize_response(packet: &mut DnsPacket) {
+    if !MINIMAL_RESPONSES.load(Ordering::Relaxed) {
+        return;
+    }
+    if packet.header.rescode != ResultCode::NOERROR || packet.answers.is_empty() {
+        return;
+    }
+    packet.authorities.clear();
+    packet.resources.retain(|record| record.get_querytype() == QueryType::OPT);
+}
+
+/// Make an empty response with the given result code
+fn error_response(request: &DnsPacket, rescode: ResultCode) -> DnsPacket {
+    let mut packet = DnsPacket::new();
+    packet.header.id = request.header.id;
+    packet.header.response = true;
+    packet.header.recursion_desired = request.header.recursion_desired;
+    packet.header.recursion_available = true;
+    packet.header.rescode = rescode;
+    packet.questions = request.questions.clone();
+    packet
+}
+
+/// Answer a pinned name with its address, A or AAAA by the address family.
+/// Other types get an empty answer, so the real addresses of the name don't leak.
+fn local_override_response(request: &DnsPacket, question: &DnsQuestion) -> Option<DnsPacket> {
+    let addr = match LOCAL_OVERRIDES.read() {
+        Ok(overrides) if !overrides.is_empty() => *overrides.get(&question.name.trim_end_matches('.').to_lowercase())?,
+        _ => return None,
+    };
+    let mut response = error_response(request, ResultCode::NOERROR);
+    response.header.authoritative_answer = true;
+    let domain = question.name.clone();
+    let ttl = TransientTtl(LOCAL_OVERRIDE_TTL);
+    match (question.qtype, addr) {
+        (QueryType::A, IpAddr::V4(addr)) => response.answers.push(DnsRecord::A { domain, addr, ttl }),
+        (QueryType::AAAA, IpAddr::V6(addr)) => response.answers.push(DnsRecord::AAAA { domain, addr, ttl }),
+        _ => {}
+    }
+    Some(response)
+}
+
+fn is_blocked(name: &str) -> bool {
+    match BLOCKLIST.read() {
+        Ok(blocklist) => !blocklist.is_empty() && blocklist.contains(&name.trim_end_matches('.').to_lowercase()),
+        Err(_) => false,
+    }
+}
+
+/// Names of a hosts file: "0.0.0.0 name [name...]" lines or bare names, comments after '#'.
+/// Names of the local host itself are skipped.
+fn parse_hosts_list(text: &str) -> HashSet<String> {
+    let mut names = HashSet::new();
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace().peekable();
+        // The address column is optional, lists of bare names are common too
+        if fields.peek().map_or(false, |field| field.parse::<IpAddr>().is_ok()) {
+            fields.next();
+        }
+        for name in fields {
+            let name = name.trim_end_matches('.').to_lowercase();
+            if !matches!(name.as_str(), "localhost" | "localhost.localdomain" | "local" | "broadcasthost" | "ip6-localhost" | "ip6-loopback" | "0.0.0.0") {
+                names.insert(name);
+            }
+        }
+    }
+    names
+}
+
+/// Take a token from the bucket of `source`, false if it is over the rate limit
+fn rate_allowed(source: IpAddr) -> bool {
+    let qps = RATE_LIMIT_QPS.load(Ordering::Relaxed);
+    if qps == 0 {
+        return true;
+    }
+    let mut limiter = match RATE_BUCKETS.lock() {
+        Ok(limiter) => limiter,
+        Err(_) => return true,
+    };
+    let now = Instant::now();
+    if limiter.pruned.map_or(true, |pruned| now.duration_since(pruned) >= RATE_BUCKET_IDLE) {
+        limiter.buckets.retain(|_, (_, updated)| now.duration_since(*updated) < RATE_BUCKET_IDLE);
+        limiter.pruned = Some(now);
+    }
+
+    let rate = qps as f64;
+    let (tokens, updated) = limiter.buckets.entry(source).or_insert((rate, now));
+    *tokens = (*tokens + now.duration_since(*updated).as_secs_f64() * rate).min(rate);
+    *updated = now;
+    if *tokens < 1.0 {
+        DNS_COUNTERS.rate_limited.fetch_add(1, Ordering::Relaxed);
+        return false;
+    }
+    *tokens -= 1.0;
+    true
+}
+
+fn is_refused_type(qtype: QueryType) -> bool {
+    match REFUSED_QUERY_TYPES.read() {
+        Ok(types) => types.contains(&qtype.to_num()),
+        Err(_) => false,
+    }
+}
+
+/// Resolve a query made by the app itself through the running resolver
+fn resolve_for_app(name: &str, qtype: &str) -> Result<DnsPacket, String> {
+    let server_context = running_server_context().ok_or("DNS server is not running")?;
+    let qtype = parse_query_type(qtype).ok_or_else(|| format!("Unknown query type '{}'", qtype))?;
+    let mut request = DnsPacket::new();
+    request.header.id = rand::random::<u16>();
+    request.header.recursion_desired = true;
+    request.questions.push(DnsQuestion::new(name.trim().trim_end_matches('.').to_string(), qtype));
+    let (response, _) = process_query(&server_context, &request);
+    Ok(response)
+}
+
+/// Resolve a chain name and a clearnet name through the running resolver, like a client would
+fn self_test() -> serde_json::Value {
+    let server_context = match running_server_context() {
+        Some(server_context) => server_context,
+        None => {
+            return serde_json::json!({
+                "blockchainResolve": false,
+                "forwardResolve": false,
+                "details": "DNS server is not running",
+            })
+        }
+    };
+    let resolve = |name: &str| {
+        let mut request = DnsPacket::new();
+        request.header.id = rand::random::<u16>();
+        request.header.recursion_desired = true;
+        request.questions.push(DnsQuestion::new(name.to_string(), QueryType::A));
+        resolve_request(&server_context, &request, false)
+    };
+    let mut details = Vec::new();
+
+    let (response, source) = resolve(SELF_TEST_CHAIN_NAME);
+    let blockchain_resolve = source == Some(CacheSource::Blockchain) && !response.answers.is_empty();
+    match (blockchain_resolve, source) {
+        (true, _) => details.push(format!("{} resolved from the blockchain", SELF_TEST_CHAIN_NAME)),
+        (false, Some(CacheSource::Blockchain)) => {
+            let height = running_context().and_then(|context| context.lock().ok().map(|ctx| ctx.chain.get_height())).unwrap_or(0);
+            details.push(format!(
+                "{} not found in the blockchain ({:?}) at height {}, it may not be synced yet",
+                SELF_TEST_CHAIN_NAME, response.header.rescode, height
+            ));
+        }
+        (false, _) => details.push(format!("{} wasn't answered by the blockchain filter ({:?})", SELF_TEST_CHAIN_NAME, response.header.rescode)),
+    }
+
+    let (response, source) = resolve(TEST_QUERY_NAME);
+    let forward_resolve = source == Some(CacheSource::Forwarded) && response.header.rescode == ResultCode::NOERROR && !response.answers.is_empty();
+    match forward_resolve {
+        true => details.push(format!("{} resolved through the upstreams", TEST_QUERY_NAME)),
+        false => details.push(format!("{} failed with {:?}, check upstream connectivity with testForwarders", TEST_QUERY_NAME, response.header.rescode)),
+    }
+
+    serde_json::json!({
+        "blockchainResolve": blockchain_resolve,
+        "forwardResolve": forward_resolve,
+        "details": details.join("; "),
+    })
+}
+
+/// All sections of a response, like dig shows them
+fn packet_to_json(packet: &DnsPacket) -> serde_json::Value {
+    let records = |records: &Vec<DnsRecord>| serde_json::to_value(records).unwrap_or_else(|_| serde_json::Value::Array(Vec::new()));
+    let header = &packet.header;
+    serde_json::json!({
+        "header": {
+            "id": header.id,
+            "rcode": format!("{:?}", header.rescode),
+            "flags": {
+                "qr": header.response,
+                "aa": header.authoritative_answer,
+                "tc": header.truncated_message,
+                "rd": header.recursion_desired,
+                "ra": header.recursion_available,
+                "ad": header.authed_data,
+                "cd": header.checking_disabled,
+            },
+        },
+        "question": packet.questions.iter().map(|question| serde_json::json!({
+            "name": question.name,
+            "qtype": format!("{:?}", question.qtype),
+        })).collect::<Vec<_>>(),
+        "answers": records(&packet.answers),
+        "authority": records(&packet.authorities),
+        "additional": records(&packet.resources),
+    })
+}
+
+/// Parse a record type name like "AAAA", "TYPE65" or a plain number
+fn parse_query_type(name: &str) -> Option<QueryType> {
+    let name = name.trim().to_uppercase();
+    let num = match name.as_str() {
+        "A" => 1,
+        "NS" => 2,
+        "CNAME" => 5,
+        "SOA" => 6,
+        "PTR" => 12,
+        "MX" => 15,
+        "TXT" => 16,
+        "AAAA" => 28,
+        "SRV" => 33,
+        "OPT" => 41,
+        "DS" => 43,
+        "RRSIG" => 46,
+        "NSEC" => 47,
+        "DNSKEY" => 48,
+        "TLSA" => 52,
+        "SVCB" => 64,
+        "HTTPS" => 65,
+        "IXFR" => 251,
+        "AXFR" => 252,
+        "ANY" => 255,
+        other => other.strip_prefix("TYPE").unwrap_or(other).parse::<u16>().ok()?,
+    };
+    Some(QueryType::from_num(num))
+}
+
+/// Ask the filters (blockchain domains) directly, like the core resolver does before anything else
+fn lookup_local(server_context: &ServerContext, request: &DnsPacket, question: &DnsQuestion) -> Option<DnsPacket> {
+    let result = server_context
+        .filters
+        .iter()
+        .find_map(|filter| filter.lookup(&question.name, question.qtype, request.header.recursion_desired))?;
+    Some(build_response(request, result))
+}
+
+/// Make a response to the request from a resolved packet, the same way execute_query does
+fn build_response(request: &DnsPacket, result: DnsPacket) -> DnsPacket {
+    let mut packet = DnsPacket::new();
+    packet.header.id = request.header.id;
+    packet.header.response = true;
+    packet.header.recursion_desired = request.header.recursion_desired;
+    packet.header.recursion_available = true;
+    packet.header.authoritative_answer = result.header.authoritative_answer;
+    packet.header.rescode = result.header.rescode;
+    packet.questions = request.questions.clone();
+    packet.answers = result.answers;
+    packet.authorities = result.authorities;
+    packet.resources = result.resources;
+    packet
+}
+
+/// Resolve a question through the configured forwarders, trying them in order.
+/// With `dnssec_ok` upstreams are asked for DNSSEC records too.
+fn forward_query(request: &DnsPacket, question: &DnsQuestion, dnssec_ok: bool) -> Option<DnsPacket> {
+    let forwarders = FORWARDERS.read().map(|f| f.clone()).unwrap_or_default();
+    if let Some(result) = query_forwarders(&forwarders, question, dnssec_ok) {
+        return Some(build_response(request, result));
+    }
+
+    let fallback = SYSTEM_DNS_FALLBACK.read().map(|f| f.clone()).unwrap_or_default();
+    let result = query_forwarders(&fallback, question, dnssec_ok)?;
+    DNS_COUNTERS.fallback_served.fetch_add(1, Ordering::Relaxed);
+    Some(build_response(request, result))
+}
+
+/// Try the upstreams in order, the first answer wins
+fn query_forwarders(upstreams: &[String], question: &DnsQuestion, dnssec_ok: bool) -> Option<DnsPacket> {
+    for upstream in upstreams {
+        let started = Instant::now();
+        let result = query_upstream(upstream, question, dnssec_ok);
+        record_forwarder_result(upstream, &result, started.elapsed());
+        if is_traced(&question.name) {
+            let outcome = match &result {
+                Ok(result) => format!("{:?} with {} answers", result.header.rescode, result.answers.len()),
+                Err(e) => format!("failed: {}", e),
+            };
+            trace_query(question, &format!("forwarder {} in {} ms: {}", upstream, started.elapsed().as_millis(), outcome));
+        }
+        match result {
+            Ok(result) => return Some(result),
+            Err(e) => debug!("Forwarder {} failed for {}: {}", upstream, question.name, e),
+        }
+    }
+    None
+}
+
+fn record_forwarder_result(upstream: &str, result: &Result<DnsPacket, UpstreamError>, elapsed: Duration) {
+    if let Ok(mut stats) = FORWARDER_STATS.lock() {
+        let stats = stats.entry(upstream.to_string()).or_default();
+        stats.queries += 1;
+        match result {
+            Ok(_) => {
+                stats.successes += 1;
+                stats.total_latency_ms += elapsed.as_millis() as u64;
+            }
+            Err(UpstreamError::Timeout) => stats.timeouts += 1,
+            Err(UpstreamError::Failed(_)) => {}
+        }
+    }
+    if let Err(UpstreamError::Timeout) = result {
+        DNS_COUNTERS.forward_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn forward_timeout() -> Duration {
+    Duration::from_millis(FORWARD_TIMEOUT_MS.load(Ordering::Relaxed))
+}
 
-    // DNS server setup
-    
-    // Start UDP server in controllable thread
-    if server_context.enable_udp {
-        add_log_message("Starting UDP DNS server...".to_string());
-        let server_ctx_clone = Arc::clone(&server_context);
-        let udp_handle = thread::Builder::new()
-            .name("DNS-UDP".to_string())
-            .spawn(move || {
-                run_controllable_udp_server(server_ctx_clone);
-            })?;
-        
-        unsafe {
-            DNS_UDP_HANDLE = Some(udp_handle);
+/// Ask every upstream for TEST_QUERY_NAME at once, each within the forward timeout.
+/// Forwarders of the running server, or of the config file while it is stopped.
+fn test_forwarders() -> serde_json::Value {
+    let mut upstreams = FORWARDERS.read().map(|f| f.clone()).unwrap_or_default();
+    if upstreams.is_empty() {
+        let config_settings = CONFIG_PATH.lock().ok().and_then(|guard| guard.clone()).and_then(|path| Settings::load(&path));
+        if let Some(settings) = config_settings {
+            upstreams = settings.dns.forwarders;
         }
-        add_log_message("UDP DNS server started successfully".to_string());
     }
 
-    // Start TCP server in controllable thread  
-    if server_context.enable_tcp {
-        add_log_message("Starting TCP DNS server...".to_string());
-        let server_ctx_clone = Arc::clone(&server_context);
-        let tcp_handle = thread::Builder::new()
-            .name("DNS-TCP".to_string())
-            .spawn(move || {
-                run_controllable_tcp_server(server_ctx_clone);
-            })?;
-            
-        unsafe {
-            DNS_TCP_HANDLE = Some(tcp_handle);
+    let question = DnsQuestion::new(TEST_QUERY_NAME.to_string(), QueryType::A);
+    let results: Vec<serde_json::Value> = thread::scope(|scope| {
+        let probes: Vec<_> = upstreams
+            .iter()
+            .map(|upstream| {
+                let question = &question;
+                scope.spawn(move || {
+                    let started = Instant::now();
+                    let result = query_upstream(upstream, question, false);
+                    (started.elapsed().as_millis() as u64, result)
+                })
+            })
+            .collect();
+        upstreams
+            .iter()
+            .zip(probes)
+            .map(|(upstream, probe)| {
+                let (ok, latency, error) = match probe.join() {
+                    Ok((latency, Ok(_))) => (true, Some(latency), None),
+                    Ok((_, Err(e))) => (false, None, Some(e.to_string())),
+                    Err(_) => (false, None, Some("Probe failed".to_string())),
+                };
+                serde_json::json!({"upstream": upstream, "ok": ok, "latencyMs": latency, "error": error})
+            })
+            .collect()
+    });
+    serde_json::Value::Array(results)
+}
+
+/// Replace the forwarder list, the stats of old forwarders are dropped
+fn set_forwarders(forwarders: Vec<String>) {
+    if let Ok(mut stats) = FORWARDER_STATS.lock() {
+        stats.clear();
+    }
+    if let Ok(mut current) = FORWARDERS.write() {
+        *current = forwarders;
+    }
+}
+
+/// Ask one upstream, plain DNS for `ip:port` or DoH for `https://` URLs
+fn query_upstream(upstream: &str, question: &DnsQuestion, dnssec_ok: bool) -> Result<DnsPacket, UpstreamError> {
+    let id = rand::random::<u16>();
+    let encrypted = upstream.starts_with("https://");
+    // Padding only hides anything when the transport is encrypted
+    let padding = encrypted && EDNS_PADDING.load(Ordering::Relaxed);
+    let query = build_upstream_query(question, id, padding, dnssec_ok)?;
+    let wire = match encrypted {
+        true => query_doh(upstream, &query)?,
+        false => query_udp(upstream, &query, id)?,
+    };
+
+    if is_traced(&question.name) {
+        let shown = &wire[..wire.len().min(TRACE_MAX_WIRE_BYTES)];
+        let hex: String = shown.iter().map(|byte| format!("{:02x}", byte)).collect();
+        trace_query(question, &format!("raw response from {} ({} bytes): {}", upstream, wire.len(), hex));
+    }
+    let mut result = parse_packet(&wire)?;
+    if result.header.id != id {
+        return Err(format!("Response id {} doesn't match query id {}", result.header.id, id).into());
+    }
+    // OPT is hop-by-hop, the client gets our own
+    result.resources.retain(|record| record.get_querytype() != QueryType::OPT);
+    Ok(result)
+}
+
+/// Encode a recursive query with an EDNS(0) OPT record, padded if asked to
+fn build_upstream_query(question: &DnsQuestion, id: u16, padding: bool, dnssec_ok: bool) -> Result<Vec<u8>, String> {
+    use alfis::dns::buffer::VectorPacketBuffer;
+
+    let mut packet = DnsPacket::new();
+    packet.header.id = id;
+    packet.header.recursion_desired = true;
+    packet.questions.push(question.clone());
+    let mut buffer = VectorPacketBuffer::new();
+    packet.write(&mut buffer, 0xFFFF).map_err(|e| format!("Failed to encode query: {:?}", e))?;
+    let mut wire = buffer.buffer;
+    append_opt_record(&mut wire, padding, dnssec_ok);
+    Ok(wire)
+}
+
+/// Append an OPT record to an encoded message and bump ARCOUNT, with the DO flag if asked to.
+/// Written by hand because alfis doesn't encode EDNS options.
+fn append_opt_record(wire: &mut Vec<u8>, padding: bool, dnssec_ok: bool) {
+    let mut options = Vec::new();
+    if padding {
+        let len = edns_padding_length(wire.len() + OPT_RECORD_HEADER_LEN, EDNS_QUERY_PADDING_BLOCK);
+        options.extend_from_slice(&EDNS_OPTION_PADDING.to_be_bytes());
+        options.extend_from_slice(&(len as u16).to_be_bytes());
+        options.resize(options.len() + len, 0);
+    }
+
+    wire.push(0); // root name
+    wire.extend_from_slice(&QueryType::OPT.to_num().to_be_bytes());
+    let payload_size = if memory_constrained() { LOW_MEMORY_UDP_PAYLOAD_SIZE as u16 } else { EDNS_UDP_PAYLOAD_SIZE };
+    wire.extend_from_slice(&payload_size.to_be_bytes());
+    let flags = if dnssec_ok { EDNS_FLAG_DO } else { 0 };
+    wire.extend_from_slice(&flags.to_be_bytes()); // extended rcode, version and flags
+    wire.extend_from_slice(&(options.len() as u16).to_be_bytes());
+    wire.extend_from_slice(&options);
+
+    let arcount = u16::from_be_bytes([wire[10], wire[11]]).saturating_add(1);
+    wire[10..12].copy_from_slice(&arcount.to_be_bytes());
+}
+
+/// Number of padding bytes that make the message a multiple of `block`.
+/// `unpadded_len` is the message length with an empty OPT record,
+/// the padding option header (code and length, 4 bytes) is accounted for here.
+fn edns_padding_length(unpadded_len: usize, block: usize) -> usize {
+    let len = unpadded_len + 4;
+    (block - len % block) % block
+}
+
+fn parse_packet(wire: &[u8]) -> Result<DnsPacket, String> {
+    use alfis::dns::buffer::VectorPacketBuffer;
+
+    let mut buffer = VectorPacketBuffer::new();
+    buffer.buffer = wire.to_vec();
+    DnsPacket::from_buffer(&mut buffer).map_err(|e| format!("Malformed response: {:?}", e))
+}
+
+/// Upstreams are `ip`, `ip:port` or a DoH `https://` URL with a host
+fn is_valid_upstream(upstream: &str) -> bool {
+    match upstream.strip_prefix("https://") {
+        Some(rest) => !rest.split('/').next().unwrap_or_default().is_empty(),
+        None => parse_upstream_addr(upstream).is_ok(),
+    }
+}
+
+/// Parse an upstream address, the port defaults to 53
+fn parse_upstream_addr(upstream: &str) -> Result<SocketAddr, String> {
+    if let Ok(addr) = upstream.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    match upstream.trim_matches(|c| c == '[' || c == ']').parse::<IpAddr>() {
+        Ok(ip) => Ok(SocketAddr::new(ip, 53)),
+        Err(_) => Err(format!("Invalid upstream address '{}'", upstream)),
+    }
+}
+
+/// Plain DNS over UDP, retried over TCP if the answer is truncated
+fn query_udp(upstream: &str, query: &[u8], id: u16) -> Result<Vec<u8>, UpstreamError> {
+    use std::net::UdpSocket;
+
+    let addr = parse_upstream_addr(upstream)?;
+    let bind = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = UdpSocket::bind(bind)?;
+    let timeout = forward_timeout();
+    socket.set_read_timeout(Some(timeout))?;
+    socket.send_to(query, addr)?;
+
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0u8; 65535];
+    loop {
+        let (len, src) = socket.recv_from(&mut buf)?;
+        // Skip anything that is not the answer to this query
+        if src != addr || len < 12 || u16::from_be_bytes([buf[0], buf[1]]) != id {
+            if Instant::now() >= deadline {
+                return Err(UpstreamError::Timeout);
+            }
+            continue;
         }
-        add_log_message("TCP DNS server started successfully".to_string());
+        let truncated = buf[2] & 0x02 != 0;
+        return match truncated {
+            true => query_tcp(addr, query),
+            false => Ok(buf[..len].to_vec()),
+        };
     }
-    
-    Ok(server_context)
 }
 
-/// Create server context for Android (based on dns_utils.rs create_server_context)
-fn create_android_server_context(context: Arc<Mutex<Context>>, settings: &Settings) -> Arc<ServerContext> {
-    use alfis::dns::context::ResolveStrategy;
-    use alfis::blockchain::filter::BlockchainFilter;
+/// Plain DNS over TCP with the two byte length prefix
+fn query_tcp(addr: SocketAddr, query: &[u8]) -> Result<Vec<u8>, UpstreamError> {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
 
-    let mut server_context = ServerContext::new(settings.dns.listen.clone(), settings.dns.bootstraps.clone());
-    add_log_message(format!("DNS server configured to listen on: {}", settings.dns.listen));
-    server_context.allow_recursive = true;
-    server_context.resolve_strategy = match settings.dns.forwarders.is_empty() {
-        true => ResolveStrategy::Recursive,
-        false => ResolveStrategy::Forward { upstreams: settings.dns.forwarders.clone() }
+    let timeout = forward_timeout();
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let mut message = (query.len() as u16).to_be_bytes().to_vec();
+    message.extend_from_slice(query);
+    stream.write_all(&message)?;
+
+    let mut len_bytes = [0u8; 2];
+    stream.read_exact(&mut len_bytes)?;
+    let mut response = vec![0u8; u16::from_be_bytes(len_bytes) as usize];
+    stream.read_exact(&mut response)?;
+    Ok(response)
+}
+
+/// DNS over HTTPS (RFC 8484), POST with a binary message
+#[cfg(feature = "doh")]
+fn query_doh(url: &str, query: &[u8]) -> Result<Vec<u8>, UpstreamError> {
+    use std::io::Read;
+
+    let response = DOH_AGENT
+        .post(url)
+        .timeout(forward_timeout())
+        .set("Content-Type", "application/dns-message")
+        .set("Accept", "application/dns-message")
+        .send_bytes(query)?;
+    let mut body = Vec::new();
+    response.into_reader().take(65535).read_to_end(&mut body)?;
+    Ok(body)
+}
+
+#[cfg(not(feature = "doh"))]
+fn query_doh(_url: &str, _query: &[u8]) -> Result<Vec<u8>, UpstreamError> {
+    Err(UpstreamError::Failed("DoH support is not compiled in".to_string()))
+}
+
+/// Resolve the host of a DoH forwarder through the bootstrap servers,
+/// the system resolver may well be pointing back at us
+#[cfg(feature = "doh")]
+fn resolve_with_bootstraps(netloc: &str) -> std::io::Result<Vec<SocketAddr>> {
+    use std::io::{Error, ErrorKind};
+
+    let (host, port) = netloc
+        .rsplit_once(':')
+        .and_then(|(host, port)| port.parse::<u16>().ok().map(|port| (host, port)))
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("Bad address '{}'", netloc)))?;
+    let host = host.trim_matches(|c| c == '[' || c == ']');
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+
+    let bootstraps = BOOTSTRAPS.read().map(|b| b.clone()).unwrap_or_default();
+    let mut addrs = Vec::new();
+    for qtype in [QueryType::A, QueryType::AAAA] {
+        let question = DnsQuestion::new(host.to_string(), qtype);
+        for bootstrap in &bootstraps {
+            let answers = match query_upstream(bootstrap, &question, false) {
+                Ok(result) => result.answers,
+                Err(e) => {
+                    debug!("Bootstrap {} failed for {}: {}", bootstrap, host, e);
+                    continue;
+                }
+            };
+            addrs.extend(answers.iter().filter_map(|record| match record {
+                DnsRecord::A { addr, .. } => Some(SocketAddr::new(IpAddr::V4(*addr), port)),
+                DnsRecord::AAAA { addr, .. } => Some(SocketAddr::new(IpAddr::V6(*addr), port)),
+                _ => None,
+            }));
+            break;
+        }
+    }
+    match addrs.is_empty() {
+        true => Err(Error::new(ErrorKind::NotFound, format!("Could not resolve {} with bootstraps", host))),
+        false => Ok(addrs),
+    }
+}
+
+/// Get a cached response for the request, with the TTLs counted down.
+/// A DNSSEC-aware request only takes responses resolved with the DO bit.
+fn cache_lookup(request: &DnsPacket, question: &DnsQuestion, dnssec_ok: bool) -> Option<(DnsPacket, CacheSource)> {
+    let key = (question.name.to_lowercase(), question.qtype);
+    let mut cache = RESPONSE_CACHE.lock().ok()?;
+    let now = Instant::now();
+    if cache.get(&key)?.expires <= now {
+        cache.remove(&key);
+        return None;
+    }
+    let entry = cache.get(&key)?;
+    if dnssec_ok && !entry.dnssec_ok {
+        return None;
+    }
+
+    let remaining = entry.expires.duration_since(now).as_secs() as u32;
+    let mut response = entry.response.clone();
+    response.header.id = request.header.id;
+    response.header.recursion_desired = request.header.recursion_desired;
+    response.questions = request.questions.clone();
+    for record in response.answers.iter_mut().chain(response.authorities.iter_mut()) {
+        set_record_ttl(record, remaining);
+    }
+    Some((response, entry.source))
+}
+
+/// Cache a successful response for the lowest TTL of its answers, and a forwarded
+/// NXDOMAIN/NODATA one for the negative cache TTL or less if its SOA says so
+fn cache_store(question: &DnsQuestion, response: &DnsPacket, source: CacheSource, dnssec_ok: bool) {
+    if response.header.truncated_message {
+        return;
+    }
+    let ttl = if is_negative_response(response) {
+        match source {
+            CacheSource::Forwarded => negative_ttl(response),
+            CacheSource::Blockchain => 0,
+        }
+    } else if response.header.rescode == ResultCode::NOERROR {
+        let max_ttl = match source {
+            CacheSource::Blockchain => MAX_ALFIS_CACHE_TTL,
+            CacheSource::Forwarded => MAX_CACHE_TTL,
+        };
+        response.answers.iter().map(|record| record.get_ttl()).min().unwrap_or(0).min(max_ttl)
+    } else {
+        0
     };
-    
-    // Add blockchain filter for .alfis domains
-    server_context.filters.push(Box::new(BlockchainFilter::new(context)));
-    
-    match server_context.initialize() {
-        Ok(_) => info!("Server context initialized successfully"),
-        Err(e) => error!("Server context initialization failed: {:?}", e)
+    if ttl == 0 {
+        return;
     }
 
-    Arc::new(server_context)
+    let capacity = cache_capacity();
+    if let Ok(mut cache) = RESPONSE_CACHE.lock() {
+        shrink_cache(&mut cache, capacity.saturating_sub(1));
+        let entry = CacheEntry {
+            response: response.clone(),
+            source,
+            expires: Instant::now() + Duration::from_secs(ttl as u64),
+            dnssec_ok,
+        };
+        cache.insert((question.name.to_lowercase(), question.qtype), entry);
+    }
 }
 
-/// Safely create a blockchain with proper error handling for Android
-fn create_chain_safely(settings: &Settings, db_path: &str) -> Result<Chain, Box<dyn std::error::Error>> {
-    info!("Attempting to create blockchain database at: {}", db_path);
+/// Check if the response says the name or the type doesn't exist, errors like SERVFAIL aren't
+fn is_negative_response(response: &DnsPacket) -> bool {
+    match response.header.rescode {
+        ResultCode::NXDOMAIN => true,
+        ResultCode::NOERROR => response.answers.is_empty(),
+        _ => false,
+    }
+}
+
+/// How long to keep a negative answer: the configured TTL, lowered to the SOA TTL and
+/// minimum field from the authority section as RFC 2308 wants
+fn negative_ttl(response: &DnsPacket) -> u32 {
+    let ttl = NEGATIVE_CACHE_TTL.load(Ordering::Relaxed);
+    let soa_ttl = response.authorities.iter().find_map(|record| match record {
+        DnsRecord::SOA { minimum, .. } => Some(record.get_ttl().min(*minimum)),
+        _ => None,
+    });
+    match soa_ttl {
+        Some(soa_ttl) => ttl.min(soa_ttl),
+        None => ttl,
+    }
+}
+
+/// Resident and peak resident size of the process in bytes.
+/// Read from /proc/self/status, which is in kB, unlike statm that counts pages
+/// of a size we can't query without libc (16 KB on some Android devices).
+fn process_memory() -> (Option<u64>, Option<u64>) {
+    let status = match std::fs::read_to_string("/proc/self/status") {
+        Ok(status) => status,
+        Err(e) => {
+            debug!("Can't read process memory: {}", e);
+            return (None, None);
+        }
+    };
+    let field = |name: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .and_then(|value| value.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+            .map(|kb| kb * 1024)
+    };
+    (field("VmRSS:"), field("VmHWM:"))
+}
+
+/// How many responses may be cached, fewer under memory pressure
+fn cache_capacity() -> usize {
+    match memory_constrained() {
+        true => LOW_MEMORY_CACHE_ENTRIES,
+        false => MAX_CACHE_ENTRIES,
+    }
+}
+
+/// Drop expired entries, then the ones that would expire first, until at most `limit` are left
+fn shrink_cache(cache: &mut ResponseCache, limit: usize) {
+    if cache.len() > limit {
+        let now = Instant::now();
+        cache.retain(|_, entry| entry.expires > now);
+    }
+    while cache.len() > limit {
+        // Still full of live entries, drop the one that would expire first
+        let oldest = cache.iter().min_by_key(|(_, entry)| entry.expires).map(|(key, _)| key.clone());
+        match oldest {
+            Some(key) => cache.remove(&key),
+            None => break,
+        }
+    }
+}
+
+/// Check if the app reported low memory recently, limits go back to normal after a while
+fn memory_constrained() -> bool {
+    let mut until = match MEMORY_PRESSURE_UNTIL.lock() {
+        Ok(until) => until,
+        Err(_) => return false,
+    };
+    match *until {
+        Some(deadline) if Instant::now() >= deadline => {
+            *until = None;
+            add_log_message("Memory pressure is over, resolver limits restored".to_string());
+            info!("Memory pressure is over, resolver limits restored");
+            false
+        }
+        Some(_) => true,
+        None => false,
+    }
+}
+
+/// Remove cached responses, all of them or only from one source
+fn cache_clear(source: Option<CacheSource>) {
+    if let Ok(mut cache) = RESPONSE_CACHE.lock() {
+        match source {
+            Some(source) => cache.retain(|_, entry| entry.source != source),
+            None => cache.clear(),
+        }
+    }
+}
+
+fn set_record_ttl(record: &mut DnsRecord, new_ttl: u32) {
+    match record {
+        DnsRecord::A { ttl, .. }
+        | DnsRecord::AAAA { ttl, .. }
+        | DnsRecord::NS { ttl, .. }
+        | DnsRecord::CNAME { ttl, .. }
+        | DnsRecord::MX { ttl, .. }
+        | DnsRecord::TXT { ttl, .. }
+        | DnsRecord::SOA { ttl, .. }
+        | DnsRecord::SRV { ttl, .. } => ttl.0 = new_ttl,
+        _ => {}
+    }
+}
+
+/// Remove DNSSEC material from all sections, returns true if anything was removed
+fn strip_dnssec_records(packet: &mut DnsPacket) -> bool {
+    let is_dnssec = |record: &DnsRecord| DNSSEC_RECORD_TYPES.contains(&record.get_querytype().to_num());
+    let before = packet.answers.len() + packet.authorities.len() + packet.resources.len();
+    packet.answers.retain(|record| !is_dnssec(record));
+    packet.authorities.retain(|record| !is_dnssec(record));
+    packet.resources.retain(|record| !is_dnssec(record));
+    before != packet.answers.len() + packet.authorities.len() + packet.resources.len()
+}
+
+/// Remove DNSSEC records the client didn't ask for, as RFC 3225 wants for queries without DO
+fn strip_unrequested_dnssec(packet: &mut DnsPacket, qtype: QueryType) {
+    let unrequested = |record: &DnsRecord| {
+        let rtype = record.get_querytype();
+        rtype != qtype && DNSSEC_RECORD_TYPES.contains(&rtype.to_num())
+    };
+    packet.answers.retain(|record| !unrequested(record));
+    packet.authorities.retain(|record| !unrequested(record));
+    packet.resources.retain(|record| !unrequested(record));
+}
+
+/// Check if the request has the DO bit set in its OPT record
+fn dnssec_ok(request: &DnsPacket) -> bool {
+    request.resources.iter().any(|record| match record {
+        DnsRecord::OPT { flags, .. } => flags & EDNS_FLAG_DO != 0,
+        _ => false,
+    })
+}
+
+/// Put records in the conventional order: CNAME chain before the final answers,
+/// SOA/NS first in authority and the OPT pseudo-record last in additional
+fn order_sections(packet: &mut DnsPacket) {
+    packet.answers.sort_by_key(|record| record.get_querytype() != QueryType::CNAME);
+    packet.authorities.sort_by_key(|record| !matches!(record.get_querytype(), QueryType::SOA | QueryType::NS));
+    packet.resources.sort_by_key(|record| record.get_querytype() == QueryType::OPT);
+}
+
+/// Replace the answers of a forwarded response by the matching rewrite rule,
+/// an exact name rule wins over suffix rules and the longest suffix over shorter ones
+fn rewrite_answers(question: &DnsQuestion, packet: &mut DnsPacket) -> bool {
+    let rules = match ANSWER_REWRITES.read() {
+        Ok(rules) if !rules.is_empty() => rules,
+        _ => return false,
+    };
+    let name = question.name.trim_end_matches('.').to_lowercase();
+    let rule = rules
+        .iter()
+        .filter(|rule| rule.matches(&name))
+        .max_by_key(|rule| (!rule.suffix, rule.name.len()));
+    let answers = match rule.and_then(|rule| rule.answers(question)) {
+        Some(answers) => answers,
+        None => return false,
+    };
+
+    packet.header.rescode = ResultCode::NOERROR;
+    packet.header.authoritative_answer = false;
+    packet.answers = answers;
+    packet.authorities.clear();
+    packet.resources.retain(|record| record.get_querytype() == QueryType::OPT);
+    true
+}
+
+/// Bind `listen` and run a UDP server on it until the shutdown flag is set
+fn run_udp_listener(server_context: Arc<ServerContext>, listen: &str) {
+    let socket = match listen.parse::<SocketAddr>().map_err(|e| e.to_string()).and_then(|addr| bind_udp(addr).map_err(|e| e.to_string())) {
+        Ok(socket) => socket,
+        Err(e) => {
+            add_log_message(format!("Failed to bind UDP socket: {}", e));
+            error!("Failed to bind UDP socket: {}", e);
+            return;
+        }
+    };
+    run_controllable_udp_server(server_context, socket);
+}
+
+/// Controllable UDP DNS server that respects shutdown flag
+fn run_controllable_udp_server(server_context: Arc<ServerContext>, socket: std::net::UdpSocket) {
+    let socket = Arc::new(socket);
+    // Big enough for any query a client with EDNS may send
+    let mut buf = [0; MAX_UDP_PAYLOAD_SIZE];
     
-    // Pre-check: test if we can create/access the database file
-    match sqlite::open(db_path) {
-        Ok(db) => {
-            match db.execute("SELECT 1") {
-                Ok(_) => {
-                    info!("Database connection test successful for {}", db_path);
-                    // Close the test connection
-                    drop(db);
+    while !DNS_SHUTDOWN_FLAG.load(Ordering::Relaxed) {
+        match socket.recv_from(&mut buf) {
+            Ok((size, src)) => {
+                // Over the limit queries are dropped, the client retries later
+                if !rate_allowed(src.ip()) {
+                    continue;
                 }
-                Err(e) => {
-                    error!("Database functionality test failed: {}", e);
-                    return Err(format!("Database not functional: {}", e).into());
+                // Resolution may block on upstreams, so it runs on the worker pool
+                let data = buf[..size].to_vec();
+                let socket = Arc::clone(&socket);
+                let server_context = Arc::clone(&server_context);
+                UDP_POOL.submit(Box::new(move || {
+                    answer_udp_query(&socket, &data, src, &server_context);
+                }));
+            }
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                // No data available, sleep briefly and check shutdown flag again
+                thread::sleep(Duration::from_millis(10));
+                continue;
+            }
+            Err(e) => {
+                if !DNS_SHUTDOWN_FLAG.load(Ordering::Relaxed) {
+                    error!("UDP socket error: {}", e);
                 }
+                break;
             }
         }
-        Err(e) => {
-            error!("Failed to open SQLite database at {}: {}", db_path, e);
-            return Err(format!("Database creation failed: {}", e).into());
-        }
     }
+    UDP_POOL.wake_all();
     
-    // Now try to create the chain - this might still panic due to internal expects
-    // but at least we know the database itself works
-    let result = std::panic::catch_unwind(|| {
-        Chain::new(settings, db_path)
-    });
+    add_log_message("UDP DNS server thread stopped".to_string());
+    info!("UDP DNS server thread stopped");
+}
+
+/// Resolve one UDP query and send the response back to its source
+fn answer_udp_query(socket: &std::net::UdpSocket, data: &[u8], src: SocketAddr, server_context: &Arc<ServerContext>) {
+    use alfis::dns::buffer::VectorPacketBuffer;
+
+    let mut packet_buffer = VectorPacketBuffer::new();
+    packet_buffer.buffer = data.to_vec();
     
-    match result {
-        Ok(chain) => {
-            info!("Blockchain created successfully");
-            Ok(chain)
+    if let Ok(request) = DnsPacket::from_buffer(&mut packet_buffer) {
+        let (mut response, source) = process_query(server_context, &request);
+        count_response(&response, source);
+        log_query(&request, &response, source);
+        let wire = match encode_udp_response(&request, &mut response) {
+            Some(wire) => wire,
+            None => return,
+        };
+        if response.header.truncated_message || wire[2] & 0x02 != 0 {
+            DNS_COUNTERS.udp_truncated.fetch_add(1, Ordering::Relaxed);
         }
-        Err(panic_err) => {
-            error!("Chain::new panicked during initialization");
-            // Try to extract panic message if possible
-            if let Some(msg) = panic_err.downcast_ref::<String>() {
-                error!("Panic message: {}", msg);
-                Err(format!("Chain creation panicked: {}", msg).into())
-            } else if let Some(msg) = panic_err.downcast_ref::<&str>() {
-                error!("Panic message: {}", msg);
-                Err(format!("Chain creation panicked: {}", msg).into())
-            } else {
-                Err("Chain creation panicked with unknown error".into())
-            }
+        let _ = socket.send_to(&wire, src);
+
+        // Update UDP query statistics
+        server_context.statistics.udp_query_count.fetch_add(1, std::sync::atomic::Ordering::Release);
+    }
+}
+
+/// Encode a response within the UDP payload size the client advertised, 512 bytes without EDNS.
+/// A response that doesn't fit becomes an empty one with TC set. EDNS clients get our OPT record appended.
+fn encode_udp_response(request: &DnsPacket, response: &mut DnsPacket) -> Option<Vec<u8>> {
+    use alfis::dns::buffer::VectorPacketBuffer;
+
+    let client_payload_size = edns_payload_size(request);
+    // Our own OPT is appended after writing, upstream ones are not for this client
+    response.resources.retain(|record| record.get_querytype() != QueryType::OPT);
+    let limit = match client_payload_size {
+        Some(size) => size - OPT_RECORD_HEADER_LEN,
+        None => 512,
+    };
+
+    // The client gets TC and no answers instead of a partial set, and retries over TCP
+    let mut res_buffer = VectorPacketBuffer::new();
+    let mut wire = match response.write(&mut res_buffer, limit) {
+        Ok(_) if !response.header.truncated_message => res_buffer.buffer,
+        // The writer leaves out the records over the limit and sets TC
+        Ok(_) => truncated_response(request)?,
+        Err(e) => {
+            debug!("UDP response doesn't fit in {} bytes ({:?}), sending it truncated", limit, e);
+            truncated_response(request)?
+        }
+    };
+    if wire.len() > limit {
+        wire = truncated_response(request)?;
+    }
+    if client_payload_size.is_some() {
+        append_opt_record(&mut wire, false, dnssec_ok(request));
+    }
+    Some(wire)
+}
+
+/// Count a response sent to a client by result code and by where the answer came from
+fn count_response(response: &DnsPacket, source: Option<CacheSource>) {
+    DNS_COUNTERS.responses.fetch_add(1, Ordering::Relaxed);
+    let counter = match response.header.rescode {
+        ResultCode::NOERROR => Some(&DNS_COUNTERS.responses_noerror),
+        ResultCode::NXDOMAIN => Some(&DNS_COUNTERS.responses_nxdomain),
+        ResultCode::SERVFAIL => Some(&DNS_COUNTERS.responses_servfail),
+        _ => None,
+    };
+    if let Some(counter) = counter {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+    let counter = match source {
+        Some(CacheSource::Blockchain) => &DNS_COUNTERS.answered_local,
+        Some(CacheSource::Forwarded) => &DNS_COUNTERS.answered_forwarded,
+        None => return,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// With query logging on, log every answered query in a stable, parseable format:
+/// `QUERY example.alfis A -> NOERROR (2 ans, local)`
+fn log_query(request: &DnsPacket, response: &DnsPacket, source: Option<CacheSource>) {
+    if !QUERY_LOGGING.load(Ordering::Relaxed) {
+        return;
+    }
+    let question = match request.questions.first() {
+        Some(question) => question,
+        None => return,
+    };
+    let source = match source {
+        Some(CacheSource::Blockchain) => "local",
+        Some(CacheSource::Forwarded) => "forwarded",
+        None => "none",
+    };
+    add_log_message(format!(
+        "QUERY {} {:?} -> {:?} ({} ans, {})",
+        question.name, question.qtype, response.header.rescode, response.answers.len(), source
+    ));
+}
+
+/// An empty response with TC set, it tells the client to ask again over TCP
+fn truncated_response(request: &DnsPacket) -> Option<Vec<u8>> {
+    use alfis::dns::buffer::VectorPacketBuffer;
+
+    let mut packet = error_response(request, ResultCode::NOERROR);
+    packet.header.truncated_message = true;
+    let mut buffer = VectorPacketBuffer::new();
+    packet.write(&mut buffer, 512).ok()?;
+    Some(buffer.buffer)
+}
+
+/// UDP payload size advertised in the OPT record of a request, clamped to sane bounds.
+/// None if the client doesn't use EDNS.
+fn edns_payload_size(request: &DnsPacket) -> Option<usize> {
+    request.resources.iter().find_map(|record| match record {
+        DnsRecord::OPT { packet_len, .. } => {
+            let max = if memory_constrained() { LOW_MEMORY_UDP_PAYLOAD_SIZE } else { MAX_UDP_PAYLOAD_SIZE };
+            Some((*packet_len as usize).clamp(512, max))
+        }
+        _ => None,
+    })
+}
+
+/// Bind `listen` and run a TCP server on it until the shutdown flag is set
+fn run_tcp_listener(server_context: Arc<ServerContext>, listen: &str) {
+    let listener = match listen.parse::<SocketAddr>().map_err(|e| e.to_string()).and_then(|addr| bind_tcp(addr).map_err(|e| e.to_string())) {
+        Ok(listener) => listener,
+        Err(e) => {
+            add_log_message(format!("Failed to bind TCP socket: {}", e));
+            error!("Failed to bind TCP socket: {}", e);
+            return;
         }
+    };
+    run_controllable_tcp_server(server_context, listener);
+}
+
+/// Counts an open TCP connection in TCP_CONNECTIONS_ACTIVE until it is dropped
+struct ActiveTcpConnection;
+
+impl ActiveTcpConnection {
+    fn new() -> Self {
+        TCP_CONNECTIONS_ACTIVE.fetch_add(1, Ordering::Relaxed);
+        ActiveTcpConnection
     }
 }
 
-/// Start the P2P network thread
-fn start_network_with_context(context: Arc<Mutex<Context>>) -> Result<(), Box<dyn std::error::Error>> {
-    // Register event listener to track network status and block updates
-    register(|_uuid, event| {
-        match event {
-            Event::NetworkStatus { blocks, domains: _, keys: _, nodes } => {
-                unsafe {
-                    NETWORK_PEER_COUNT = nodes;
+impl Drop for ActiveTcpConnection {
+    fn drop(&mut self) {
+        TCP_CONNECTIONS_ACTIVE.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Controllable TCP DNS server that respects shutdown flag  
+fn run_controllable_tcp_server(server_context: Arc<ServerContext>, listener: std::net::TcpListener) {
+    while !DNS_SHUTDOWN_FLAG.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, addr)) => {
+                if !rate_allowed(addr.ip()) {
+                    continue;
                 }
-                
-                // Log peer connectivity status periodically
-                static mut LAST_PEER_LOG: u64 = 0;
-                let now = std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs();
-                
-                unsafe {
-                    if now - LAST_PEER_LOG > 60 { // Log every 60 seconds
-                        LAST_PEER_LOG = now;
-                        if nodes == 0 {
-                            add_log_message("Warning: No peer connections active".to_string());
-                            log::warn!("No active peer connections - this will prevent sync");
-                        } else {
-                            add_log_message(format!("Network: {} peers, {} blocks", nodes, blocks));
-                            log::info!("Active peers: {}, known blocks: {}", nodes, blocks);
-                            
-                            // Check if we have a reasonable number of peers for good sync
-                            if nodes < 2 {
-                                add_log_message("Few peers: Consider checking network connectivity".to_string());
-                                log::warn!("Low peer count ({}) may impact sync performance", nodes);
-                            }
-                        }
-                    }
+                let max_connections = MAX_TCP_CONNECTIONS.load(Ordering::Relaxed);
+                if max_connections > 0 && TCP_CONNECTIONS_ACTIVE.load(Ordering::Relaxed) >= max_connections {
+                    // Closed before reading anything, a flood costs no worker
+                    DNS_COUNTERS.tcp_connections_rejected.fetch_add(1, Ordering::Relaxed);
+                    debug!("TCP connection limit of {} reached, closed the one from {}", max_connections, addr);
+                    continue;
+                }
+                let connection = ActiveTcpConnection::new();
+                // Accepted sockets may inherit non-blocking mode, clients are served with timeouts
+                let _ = stream.set_nonblocking(false);
+                let server_ctx = Arc::clone(&server_context);
+                let accepted = TCP_POOL.submit(Box::new(move || {
+                    handle_tcp_client(stream, server_ctx);
+                    drop(connection);
+                }));
+                if !accepted {
+                    // Dropping the job closed the connection
+                    DNS_COUNTERS.tcp_rejected.fetch_add(1, Ordering::Relaxed);
+                    debug!("Too many TCP connections, closed the one from {}", addr);
                 }
             }
-            Event::BlockchainChanged { index: _ } => {
-                // Silent - syncing events will show progress
-            }
-            Event::NewBlockReceived => {
-                // Silent - syncing events will show progress
-            }
-            Event::Syncing { have, height } => {
-                let percent = if height > 0 { (have as f64 / height as f64) * 100.0 } else { 0.0 };
-                add_log_message(format!("Syncing: {}/{} blocks ({:.1}%)", have, height, percent));
-            }
-            Event::SyncFinished => {
-                add_log_message("Blockchain synchronization completed".to_string());
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                // No connection available, sleep briefly and check shutdown flag again
+                thread::sleep(Duration::from_millis(10));
+                continue;
             }
-            _ => {
-                // Other events - silent
+            Err(e) => {
+                if !DNS_SHUTDOWN_FLAG.load(Ordering::Relaxed) {
+                    error!("TCP accept error: {}", e);
+                }
+                break;
             }
         }
-        true // Keep listening
-    });
-
-    let context_clone = Arc::clone(&context);
-    
-    let handle = thread::Builder::new()
-        .name(String::from("Network"))
-        .spawn(move || {
-            // Give the DNS server time to start
-            thread::sleep(Duration::from_millis(1000));
-            
-            add_log_message("Connecting to P2P network...".to_string());
-            info!("Starting P2P network thread");
-            
-            add_log_message("Network thread started".to_string());
-            add_log_message("Attempting to connect to bootstrap peers...".to_string());
-            add_log_message("Looking for peers at peer-v4.alfis.name:4244 and peer-v6.alfis.name:4244".to_string());
-            
-            let mut network = Network::new(context_clone);
-            network.start();
-        })?;
-    
-    unsafe {
-        NETWORK_HANDLE = Some(handle);
     }
+    TCP_POOL.wake_all();
     
-    Ok(())
+    add_log_message("TCP DNS server thread stopped".to_string());
+    info!("TCP DNS server thread stopped");
 }
 
-/// Controllable UDP DNS server that respects shutdown flag
-fn run_controllable_udp_server(server_context: Arc<ServerContext>) {
-    use std::net::UdpSocket;
-    use alfis::dns::server::execute_query;
-    use alfis::dns::buffer::{BytePacketBuffer, PacketBuffer};
-    use alfis::dns::protocol::DnsPacket;
-    
-    let socket = match UdpSocket::bind(&server_context.dns_listen) {
-        Ok(socket) => {
-            add_log_message(format!("UDP server bound to {}", server_context.dns_listen));
-            socket
-        }
+/// Unix domain socket DNS server that respects shutdown flag
+/// Bind the Unix socket and start its server thread, the handle is stored in `unix_handle`
+fn start_unix_listener(
+    server_context: Arc<ServerContext>,
+    socket_path: &str,
+    unix_handle: &mut Option<(String, thread::JoinHandle<()>)>,
+) -> bool {
+    use std::os::unix::net::UnixListener;
+
+    // A socket file left from a previous run would make bind fail
+    let _ = std::fs::remove_file(socket_path);
+    let listener = match UnixListener::bind(socket_path) {
+        Ok(listener) => listener,
         Err(e) => {
-            add_log_message(format!("Failed to bind UDP socket: {}", e));
-            error!("Failed to bind UDP socket: {}", e);
-            return;
+            add_log_message(format!("Failed to bind Unix socket {}: {}", socket_path, e));
+            error!("Failed to bind Unix socket {}: {}", socket_path, e);
+            return false;
         }
     };
+    if let Err(e) = listener.set_nonblocking(true) {
+        error!("Failed to set Unix socket non-blocking: {}", e);
+        return false;
+    }
 
-    // Set socket to non-blocking so we can check shutdown flag
-    if let Err(e) = socket.set_nonblocking(true) {
-        error!("Failed to set socket non-blocking: {}", e);
-        return;
+    let path_clone = socket_path.to_string();
+    match thread::Builder::new()
+        .name("DNS-Unix".to_string())
+        .spawn(move || run_controllable_unix_server(server_context, listener, path_clone))
+    {
+        Ok(handle) => {
+            *unix_handle = Some((socket_path.to_string(), handle));
+            add_log_message(format!("Unix socket DNS server listening on {}", socket_path));
+            info!("Unix socket DNS server listening on {}", socket_path);
+            true
+        }
+        Err(e) => {
+            error!("Failed to spawn Unix socket server thread: {}", e);
+            false
+        }
     }
+}
 
-    let mut buf = [0; 512];
-    
+fn run_controllable_unix_server(server_context: Arc<ServerContext>, listener: std::os::unix::net::UnixListener, socket_path: String) {
     while !DNS_SHUTDOWN_FLAG.load(Ordering::Relaxed) {
-        match socket.recv_from(&mut buf) {
-            Ok((size, src)) => {
-                let mut packet_buffer = BytePacketBuffer::new();
-                packet_buffer.buf[..size].copy_from_slice(&buf[..size]);
-                
-                if let Ok(request) = DnsPacket::from_buffer(&mut packet_buffer) {
-                    let mut response = execute_query(Arc::clone(&server_context), &request);
-                    
-                    let mut res_buffer = BytePacketBuffer::new();
-                    if response.write(&mut res_buffer, 512).is_ok() {
-                        let len = res_buffer.pos();
-                        let _ = socket.send_to(&res_buffer.buf[..len], src);
-                        
-                        // Update UDP query statistics
-                        server_context.statistics.udp_query_count.fetch_add(1, std::sync::atomic::Ordering::Release);
-                    }
-                }
+        match listener.accept() {
+            Ok((stream, _addr)) => {
+                let server_ctx = Arc::clone(&server_context);
+                thread::spawn(move || {
+                    handle_unix_client(stream, server_ctx);
+                });
             }
             Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                // No data available, sleep briefly and check shutdown flag again
+                // No connection available, sleep briefly and check shutdown flag again
                 thread::sleep(Duration::from_millis(10));
                 continue;
             }
             Err(e) => {
                 if !DNS_SHUTDOWN_FLAG.load(Ordering::Relaxed) {
-                    error!("UDP socket error: {}", e);
+                    error!("Unix socket accept error: {}", e);
                 }
                 break;
             }
         }
     }
-    
-    add_log_message("UDP DNS server thread stopped".to_string());
-    info!("UDP DNS server thread stopped");
+
+    let _ = std::fs::remove_file(&socket_path);
+    add_log_message("Unix socket DNS server thread stopped".to_string());
+    info!("Unix socket DNS server thread stopped");
 }
 
-/// Controllable TCP DNS server that respects shutdown flag  
-fn run_controllable_tcp_server(server_context: Arc<ServerContext>) {
+/// Start the DoH listener on DOH_LISTEN unless it is already running, false if it couldn't start
+#[cfg(feature = "doh-server")]
+fn start_doh_listener(server_context: &Arc<ServerContext>) -> bool {
     use std::net::TcpListener;
-    
-    let listener = match TcpListener::bind(&server_context.dns_listen) {
-        Ok(listener) => {
-            add_log_message(format!("TCP server bound to {}", server_context.dns_listen));
-            listener
+
+    let mut doh_handle = match DNS_DOH_HANDLE.lock() {
+        Ok(guard) => guard,
+        Err(_) => return false,
+    };
+    if doh_handle.as_ref().map_or(false, |handle| !handle.is_finished()) {
+        return true;
+    }
+    let config = match doh_tls_config() {
+        Ok(config) => config,
+        Err(e) => {
+            add_log_message(format!("Failed to set up TLS for DoH: {}", e));
+            error!("Failed to set up TLS for DoH: {}", e);
+            return false;
         }
+    };
+    let listener = match TcpListener::bind(DOH_LISTEN) {
+        Ok(listener) => listener,
         Err(e) => {
-            add_log_message(format!("Failed to bind TCP socket: {}", e));
-            error!("Failed to bind TCP socket: {}", e);
-            return;
+            add_log_message(format!("Failed to bind DoH socket {}: {}", DOH_LISTEN, e));
+            error!("Failed to bind DoH socket {}: {}", DOH_LISTEN, e);
+            return false;
         }
     };
-
-    // Set listener to non-blocking
     if let Err(e) = listener.set_nonblocking(true) {
-        error!("Failed to set TCP listener non-blocking: {}", e);
-        return;
+        error!("Failed to set DoH listener non-blocking: {}", e);
+        return false;
     }
 
-    while !DNS_SHUTDOWN_FLAG.load(Ordering::Relaxed) {
+    let server_ctx_clone = Arc::clone(server_context);
+    match thread::Builder::new()
+        .name("DNS-DoH".to_string())
+        .spawn(move || run_doh_server(server_ctx_clone, listener, config))
+    {
+        Ok(handle) => {
+            *doh_handle = Some(handle);
+            add_log_message(format!("DoH server listening on https://{}/dns-query", DOH_LISTEN));
+            true
+        }
+        Err(e) => {
+            error!("Failed to spawn DoH thread: {}", e);
+            false
+        }
+    }
+}
+
+#[cfg(not(feature = "doh-server"))]
+fn start_doh_listener(_server_context: &Arc<ServerContext>) -> bool {
+    add_log_message("DoH server support is not compiled in".to_string());
+    false
+}
+
+/// Join the DoH listener, it quits once the shutdown flag is set or DoH is disabled
+fn stop_doh_listener() {
+    if let Some(handle) = DNS_DOH_HANDLE.lock().ok().and_then(|mut guard| guard.take()) {
+        if let Err(e) = handle.join() {
+            error!("Failed to join DoH thread: {:?}", e);
+        }
+    }
+}
+
+/// TLS config with the self-signed certificate for localhost from the work directory,
+/// the certificate and key are generated when missing
+#[cfg(feature = "doh-server")]
+fn doh_tls_config() -> Result<Arc<rustls::ServerConfig>, Box<dyn std::error::Error>> {
+    use std::io::BufReader;
+    use std::os::unix::fs::PermissionsExt;
+
+    let work_dir = WORK_DIR.lock().ok().and_then(|guard| guard.clone()).ok_or("Work directory is not known")?;
+    let cert_path = format!("{}/{}", work_dir, DOH_CERT_FILE);
+    let key_path = format!("{}/{}", work_dir, DOH_KEY_FILE);
+    if !std::path::Path::new(&cert_path).exists() || !std::path::Path::new(&key_path).exists() {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string(), "127.0.0.1".to_string()])?;
+        std::fs::write(&key_path, certified.key_pair.serialize_pem())?;
+        std::fs::set_permissions(&key_path, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::write(&cert_path, certified.cert.pem())?;
+        add_log_message(format!("Generated self-signed DoH certificate {}", cert_path));
+    }
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(std::fs::File::open(&cert_path)?)).collect::<Result<Vec<_>, _>>()?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(std::fs::File::open(&key_path)?))?.ok_or("No private key in DoH key file")?;
+    let mut config = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+#[cfg(feature = "doh-server")]
+fn run_doh_server(server_context: Arc<ServerContext>, listener: std::net::TcpListener, config: Arc<rustls::ServerConfig>) {
+    while !DNS_SHUTDOWN_FLAG.load(Ordering::Relaxed) && DOH_ENABLED.load(Ordering::Relaxed) {
         match listener.accept() {
-            Ok((stream, _addr)) => {
+            Ok((stream, addr)) => {
+                if !rate_allowed(addr.ip()) {
+                    continue;
+                }
+                let _ = stream.set_nonblocking(false);
                 let server_ctx = Arc::clone(&server_context);
-                thread::spawn(move || {
-                    handle_tcp_client(stream, server_ctx);
-                });
+                let config = Arc::clone(&config);
+                // Connections share the TCP workers, they are alike in cost
+                let accepted = TCP_POOL.submit(Box::new(move || {
+                    handle_doh_client(stream, config, server_ctx);
+                }));
+                if !accepted {
+                    DNS_COUNTERS.tcp_rejected.fetch_add(1, Ordering::Relaxed);
+                    debug!("Too many TCP connections, closed the DoH one from {}", addr);
+                }
             }
             Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                 // No connection available, sleep briefly and check shutdown flag again
@@ -827,46 +7992,523 @@ fn run_controllable_tcp_server(server_context: Arc<ServerContext>) {
             }
             Err(e) => {
                 if !DNS_SHUTDOWN_FLAG.load(Ordering::Relaxed) {
-                    error!("TCP accept error: {}", e);
+                    error!("DoH accept error: {}", e);
                 }
                 break;
             }
         }
     }
-    
-    add_log_message("TCP DNS server thread stopped".to_string());
-    info!("TCP DNS server thread stopped");
+
+    add_log_message("DoH server thread stopped".to_string());
+    info!("DoH server thread stopped");
+}
+
+/// Serve RFC 8484 requests over one TLS connection, HTTP/1.1 with keep-alive
+#[cfg(feature = "doh-server")]
+fn handle_doh_client(stream: std::net::TcpStream, config: Arc<rustls::ServerConfig>, server_context: Arc<ServerContext>) {
+    use std::io::Write;
+
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+    let connection = match rustls::ServerConnection::new(config) {
+        Ok(connection) => connection,
+        Err(e) => {
+            debug!("Failed to create TLS connection: {}", e);
+            return;
+        }
+    };
+    let mut tls = rustls::StreamOwned::new(connection, stream);
+    while let Some((method, target, body)) = read_http_request(&mut tls) {
+        let answer = doh_query_bytes(&method, &target, body).and_then(|query| {
+            answer_doh_query(&query, &server_context).ok_or("400 Bad Request")
+        });
+        let written = match &answer {
+            Ok(response) => {
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/dns-message\r\nContent-Length: {}\r\nCache-Control: no-store\r\n\r\n",
+                    response.len()
+                );
+                tls.write_all(head.as_bytes()).and_then(|_| tls.write_all(response))
+            }
+            Err(status) => tls.write_all(format!("HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status).as_bytes()),
+        };
+        if written.and_then(|_| tls.flush()).is_err() || answer.is_err() {
+            break;
+        }
+    }
+    tls.conn.send_close_notify();
+    let _ = tls.flush();
+}
+
+/// Read one HTTP/1.1 request: method, target and body. None on errors and oversized requests.
+#[cfg(feature = "doh-server")]
+fn read_http_request<S: std::io::Read>(stream: &mut S) -> Option<(String, String, Vec<u8>)> {
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= DOH_MAX_HEAD_SIZE || stream.read(&mut byte).ok()? == 0 {
+            return None;
+        }
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8(head).ok()?;
+    let mut lines = head.lines();
+    let mut request_line = lines.next()?.split_whitespace();
+    let method = request_line.next()?.to_string();
+    let target = request_line.next()?.to_string();
+    let length = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .map_or(Some(0), |(_, value)| value.trim().parse::<usize>().ok())?;
+    if length > TCP_MAX_MESSAGE_SIZE.load(Ordering::Relaxed) {
+        DNS_COUNTERS.tcp_oversized_rejected.fetch_add(1, Ordering::Relaxed);
+        return None;
+    }
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body).ok()?;
+    Some((method, target, body))
+}
+
+/// The DNS message of a request: base64url `dns` parameter for GET, the body for POST.
+/// Errors are HTTP status lines.
+#[cfg(feature = "doh-server")]
+fn doh_query_bytes(method: &str, target: &str, body: Vec<u8>) -> Result<Vec<u8>, &'static str> {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    if path != "/dns-query" {
+        return Err("404 Not Found");
+    }
+    let message = match method {
+        "GET" => query
+            .split('&')
+            .find_map(|param| param.strip_prefix("dns="))
+            .and_then(decode_base64url)
+            .ok_or("400 Bad Request")?,
+        "POST" => body,
+        _ => return Err("405 Method Not Allowed"),
+    };
+    if message.is_empty() || message.len() > TCP_MAX_MESSAGE_SIZE.load(Ordering::Relaxed) {
+        return Err("400 Bad Request");
+    }
+    Ok(message)
+}
+
+/// Resolve a DNS message like the other listeners do, None if it isn't a valid query
+#[cfg(feature = "doh-server")]
+fn answer_doh_query(query: &[u8], server_context: &Arc<ServerContext>) -> Option<Vec<u8>> {
+    use alfis::dns::buffer::VectorPacketBuffer;
+
+    let mut packet_buffer = VectorPacketBuffer::new();
+    packet_buffer.buffer = query.to_vec();
+    let request = DnsPacket::from_buffer(&mut packet_buffer).ok()?;
+    DNS_COUNTERS.doh_queries.fetch_add(1, Ordering::Relaxed);
+    let (mut response, source) = process_query(server_context, &request);
+    count_response(&response, source);
+    log_query(&request, &response, source);
+
+    let mut res_buffer = VectorPacketBuffer::new();
+    response.write(&mut res_buffer, 0xFFFF).ok()?;
+    Some(res_buffer.buffer)
+}
+
+/// Decode unpadded base64url as used by DoH GET requests
+#[cfg(feature = "doh-server")]
+fn decode_base64url(text: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(text.len() * 3 / 4);
+    let mut bits: u32 = 0;
+    let mut count = 0;
+    for c in text.trim_end_matches('=').bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            _ => return None,
+        };
+        bits = (bits << 6) | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            decoded.push((bits >> count) as u8);
+            bits &= (1 << count) - 1;
+        }
+    }
+    Some(decoded)
 }
 
 /// Handle individual TCP client connection (simplified)
 fn handle_tcp_client(mut stream: std::net::TcpStream, server_context: Arc<ServerContext>) {
-    use std::io::{Read, Write};
-    use alfis::dns::server::execute_query;
-    use alfis::dns::buffer::{BytePacketBuffer, PacketBuffer};
-    use alfis::dns::protocol::DnsPacket;
-    
-    let mut buf = [0; 512];
-    if let Ok(size) = stream.read(&mut buf) {
-        if size >= 2 {
-            // Skip the 2-byte length prefix for simplicity
-            let mut packet_buffer = BytePacketBuffer::new();
-            packet_buffer.buf[..size-2].copy_from_slice(&buf[2..size]);
-            
-            if let Ok(request) = DnsPacket::from_buffer(&mut packet_buffer) {
-                let mut response = execute_query(server_context.clone(), &request);
-                
-                let mut res_buffer = BytePacketBuffer::new();
-                if response.write(&mut res_buffer, 512).is_ok() {
-                    let len = res_buffer.pos();
-                    // Write length prefix then data
-                    let len_bytes = (len as u16).to_be_bytes();
-                    let _ = stream.write_all(&len_bytes);
-                    let _ = stream.write_all(&res_buffer.buf[..len]);
-                    
-                    // Update TCP query statistics
-                    server_context.statistics.tcp_query_count.fetch_add(1, std::sync::atomic::Ordering::Release);
-                }
+    // Don't let a client that never sends the message hold the thread forever
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+    // Clients may send several queries over one connection (RFC 7766)
+    while answer_framed_query(&mut stream, &server_context) {
+        // Update TCP query statistics
+        server_context.statistics.tcp_query_count.fetch_add(1, std::sync::atomic::Ordering::Release);
+    }
+}
+
+/// Handle individual Unix socket client connection, framed like TCP
+fn handle_unix_client(mut stream: std::os::unix::net::UnixStream, server_context: Arc<ServerContext>) {
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+    while answer_framed_query(&mut stream, &server_context) {
+        DNS_COUNTERS.unix_queries.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Read one length-prefixed query from a stream and write back the length-prefixed response.
+/// Returns true if a response was sent.
+fn answer_framed_query<S: std::io::Read + std::io::Write>(stream: &mut S, server_context: &Arc<ServerContext>) -> bool {
+    use alfis::dns::buffer::VectorPacketBuffer;
+
+    let mut len_bytes = [0u8; 2];
+    if stream.read_exact(&mut len_bytes).is_err() {
+        return false;
+    }
+    // Check the declared size before allocating anything for the body
+    let len = u16::from_be_bytes(len_bytes) as usize;
+    if len == 0 || len > TCP_MAX_MESSAGE_SIZE.load(Ordering::Relaxed) {
+        DNS_COUNTERS.tcp_oversized_rejected.fetch_add(1, Ordering::Relaxed);
+        debug!("Rejected stream DNS message with declared size {}", len);
+        return false;
+    }
+
+    let mut packet_buffer = VectorPacketBuffer::new();
+    packet_buffer.buffer = vec![0; len];
+    if stream.read_exact(&mut packet_buffer.buffer).is_err() {
+        return false;
+    }
+
+    if let Ok(request) = DnsPacket::from_buffer(&mut packet_buffer) {
+        let (mut response, source) = process_query(server_context, &request);
+        count_response(&response, source);
+        log_query(&request, &response, source);
+
+        // Stream responses are only limited by the 16 bit length prefix
+        let mut res_buffer = VectorPacketBuffer::new();
+        if response.write(&mut res_buffer, 0xFFFF).is_ok() {
+            let len = res_buffer.buffer.len();
+            // Length prefix and data in one write, some clients don't like split messages
+            let mut message = Vec::with_capacity(len + 2);
+            message.extend_from_slice(&(len as u16).to_be_bytes());
+            message.extend_from_slice(&res_buffer.buffer);
+            return stream.write_all(&message).is_ok();
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read, Write};
+
+    /// Stream with canned input that keeps what is written to it
+    struct MemoryStream {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for MemoryStream {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MemoryStream {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Tests that change global options run one at a time
+    static GLOBAL_OPTIONS: Mutex<()> = Mutex::new(());
+
+    fn lock_globals() -> std::sync::MutexGuard<'static, ()> {
+        GLOBAL_OPTIONS.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn test_server_context() -> Arc<ServerContext> {
+        Arc::new(ServerContext::new("127.0.0.1:0".to_string(), Vec::new()))
+    }
+
+    fn query(name: &str, qtype: QueryType) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.id = 4242;
+        packet.header.recursion_desired = true;
+        packet.questions.push(DnsQuestion::new(name.to_string(), qtype));
+        packet
+    }
+
+    /// A query with an OPT record advertising `payload_size`, parsed back like one from a client
+    fn edns_query(name: &str, qtype: QueryType, payload_size: u16, dnssec_ok: bool) -> DnsPacket {
+        let mut wire = encode(&mut query(name, qtype));
+        append_opt_record(&mut wire, false, dnssec_ok);
+        // The payload size is the class of the OPT record, 8 bytes from the end without options
+        let class = wire.len() - 8;
+        wire[class..class + 2].copy_from_slice(&payload_size.to_be_bytes());
+        parse_packet(&wire).expect("query parses")
+    }
+
+    fn a_response(request: &DnsPacket, answers: u8) -> DnsPacket {
+        let mut response = error_response(request, ResultCode::NOERROR);
+        let domain = request.questions[0].name.clone();
+        for i in 0..answers {
+            response.answers.push(DnsRecord::A { domain: domain.clone(), addr: Ipv4Addr::new(198, 51, 100, i), ttl: TransientTtl(300) });
+        }
+        response
+    }
+
+    fn encode(packet: &mut DnsPacket) -> Vec<u8> {
+        use alfis::dns::buffer::VectorPacketBuffer;
+
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer, 0xFFFF).expect("packet encodes");
+        buffer.buffer
+    }
+
+    #[test]
+    fn oversized_length_prefix_closes_stream() {
+        let server_context = test_server_context();
+        let mut input = vec![0xFF, 0xFF];
+        input.extend_from_slice(&[0u8; 64]);
+        let mut stream = MemoryStream { input: Cursor::new(input), output: Vec::new() };
+        let rejected = DNS_COUNTERS.tcp_oversized_rejected.load(Ordering::Relaxed);
+
+        let started = Instant::now();
+        assert!(!answer_framed_query(&mut stream, &server_context));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        // Only the prefix was read, the body was neither read nor answered
+        assert_eq!(stream.input.position(), 2);
+        assert!(stream.output.is_empty());
+        assert!(DNS_COUNTERS.tcp_oversized_rejected.load(Ordering::Relaxed) > rejected);
+    }
+
+    #[test]
+    fn padding_fills_message_to_block() {
+        // The 4 bytes of the padding option header count towards the block
+        assert_eq!(edns_padding_length(120, 128), 4);
+        assert_eq!(edns_padding_length(124, 128), 0);
+        assert_eq!(edns_padding_length(125, 128), 127);
+        assert_eq!(edns_padding_length(0, 128), 124);
+        for len in 0..600 {
+            let padding = edns_padding_length(len, 128);
+            assert!(padding < 128);
+            assert_eq!((len + 4 + padding) % 128, 0);
+        }
+    }
+
+    #[test]
+    fn padded_query_is_multiple_of_block() {
+        for name in ["a.b", "example.com", "a-much-longer-name.of.some.subdomain.example.org"] {
+            let mut wire = encode(&mut query(name, QueryType::A));
+            append_opt_record(&mut wire, true, false);
+            assert_eq!(wire.len() % EDNS_QUERY_PADDING_BLOCK, 0, "{}", name);
+        }
+    }
+
+    #[test]
+    fn minimal_responses_are_smaller() {
+        let _guard = lock_globals();
+        let request = query("www.example.com", QueryType::A);
+        let mut response = error_response(&request, ResultCode::NOERROR);
+        response.answers.push(DnsRecord::A { domain: "www.example.com".to_string(), addr: Ipv4Addr::new(192, 0, 2, 1), ttl: TransientTtl(300) });
+        for i in 1..=4 {
+            let host = format!("ns{}.example.com", i);
+            response.authorities.push(DnsRecord::NS { domain: "example.com".to_string(), host: host.clone(), ttl: TransientTtl(3600) });
+            response.resources.push(DnsRecord::A { domain: host, addr: Ipv4Addr::new(192, 0, 2, 10 + i), ttl: TransientTtl(3600) });
+        }
+        let full = encode(&mut response.clone()).len();
+
+        let mut untouched = response.clone();
+        minimize_response(&mut untouched);
+        MINIMAL_RESPONSES.store(true, Ordering::Relaxed);
+        let mut minimal = response.clone();
+        minimize_response(&mut minimal);
+        MINIMAL_RESPONSES.store(false, Ordering::Relaxed);
+
+        assert_eq!(encode(&mut untouched).len(), full);
+        assert_eq!(minimal.answers.len(), 1);
+        assert!(minimal.authorities.is_empty());
+        assert!(minimal.resources.is_empty());
+        let minimal = encode(&mut minimal).len();
+        assert!(minimal < full, "minimal {} bytes, full {} bytes", minimal, full);
+    }
+
+    #[test]
+    fn edns_query_gets_larger_udp_buffer() {
+        let request = edns_query("big.example.com", QueryType::A, 4096, false);
+        assert_eq!(edns_payload_size(&request), Some(4096));
+        assert_eq!(edns_payload_size(&query("big.example.com", QueryType::A)), None);
+
+        // Too big for 512 bytes, fits in the 4096 bytes the client asked for
+        let mut response = a_response(&request, 60);
+        let wire = encode_udp_response(&request, &mut response).expect("response encodes");
+        assert!(wire.len() > 512, "only {} bytes", wire.len());
+        assert_eq!(wire[2] & 0x02, 0, "TC must not be set");
+        assert_eq!(parse_packet(&wire).expect("response parses").answers.len(), 60);
+    }
+
+    #[test]
+    fn oversized_udp_answer_is_truncated_and_empty() {
+        let request = query("big.example.com", QueryType::A);
+        let mut response = a_response(&request, 60);
+        let wire = encode_udp_response(&request, &mut response).expect("response encodes");
+        assert!(wire.len() <= 512, "{} bytes", wire.len());
+        assert_ne!(wire[2] & 0x02, 0, "TC must be set");
+        let packet = parse_packet(&wire).expect("response parses");
+        assert!(packet.header.truncated_message);
+        assert!(packet.answers.is_empty());
+        assert_eq!(packet.questions.len(), 1);
+    }
+
+    #[test]
+    fn dnssec_ok_lookup_skips_plain_cache_entry() {
+        let _guard = lock_globals();
+        let request = query("do-bit.cache.example.com", QueryType::A);
+        let question = request.questions[0].clone();
+
+        cache_store(&question, &a_response(&request, 1), CacheSource::Forwarded, false);
+        assert!(cache_lookup(&request, &question, true).is_none());
+        let (plain, _) = cache_lookup(&request, &question, false).expect("plain lookup hits");
+        assert_eq!(plain.answers.len(), 1);
+
+        cache_store(&question, &a_response(&request, 2), CacheSource::Forwarded, true);
+        let (signed, source) = cache_lookup(&request, &question, true).expect("DO lookup hits");
+        assert_eq!(signed.answers.len(), 2);
+        assert!(source == CacheSource::Forwarded);
+        let (plain, _) = cache_lookup(&request, &question, false).expect("plain lookup hits");
+        assert_eq!(plain.answers.len(), 2);
+
+        if let Ok(mut cache) = RESPONSE_CACHE.lock() {
+            cache.remove(&(question.name.to_lowercase(), question.qtype));
+        }
+    }
+
+    /// A free port on 127.0.0.1, the listener is dropped right away
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0").and_then(|listener| listener.local_addr()).expect("free port").port()
+    }
+
+    fn wait_until(timeout: Duration, condition: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if condition() {
+                return true;
             }
+            thread::sleep(Duration::from_millis(50));
+        }
+        condition()
+    }
+
+    // Starts the whole node with the P2P thread, and the free ports can be taken in between
+    #[test]
+    #[ignore = "starts the whole node on local ports, run with --ignored"]
+    fn restart_binds_listeners_again() {
+        let _globals = lock_globals();
+        let dir = std::env::temp_dir().join(format!("alfis-restart-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("temp dir");
+        let net_listen = format!("127.0.0.1:{}", free_port());
+        let dns_listen = format!("127.0.0.1:{}", free_port());
+        let mut config = DEFAULT_ANDROID_CONFIG.to_string();
+        config = set_config_line(&config, "net", "peers", &toml::Value::Array(Vec::new()));
+        config = set_config_line(&config, "net", "listen", &toml::Value::String(net_listen.clone()));
+        config = set_config_line(&config, "net", "yggdrasil_only", &toml::Value::Boolean(false));
+        config = set_config_line(&config, "dns", "listen", &toml::Value::String(dns_listen));
+        let config_path = dir.join("alfis.toml").to_string_lossy().to_string();
+        let work_dir = dir.to_string_lossy().to_string();
+        std::fs::write(&config_path, config).expect("config written");
+
+        let started = || DNS_RUNNING.load(Ordering::Acquire);
+        let failed = || !LAST_START_ERROR.lock().map(|e| e.is_empty()).unwrap_or(true);
+        start_node(&config_path, &work_dir, "");
+        assert!(wait_until(Duration::from_secs(10), || started() || failed()));
+        assert!(started(), "first start failed: {}", LAST_START_ERROR.lock().unwrap());
+        assert!(wait_until(Duration::from_secs(5), || std::net::TcpStream::connect(&net_listen).is_ok()));
+        assert!(stop_node());
+
+        start_node(&config_path, &work_dir, "");
+        assert!(wait_until(Duration::from_secs(10), || started() || failed()));
+        let error = LAST_START_ERROR.lock().map(|e| e.clone()).unwrap_or_default();
+        assert!(started() && error.is_empty(), "restart failed: {}", error);
+        assert!(stop_node());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn queries_racing_shutdown_get_servfail() {
+        let _globals = lock_globals();
+        let name = "shutdown-race.example.com";
+        LOCAL_OVERRIDES.write().unwrap().insert(name.to_string(), IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+        let server_context = test_server_context();
+
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let server_context = Arc::clone(&server_context);
+                thread::spawn(move || {
+                    let request = query(name, QueryType::A);
+                    let mut after_shutdown = 0;
+                    while after_shutdown < 100 {
+                        let stopping = DNS_SHUTDOWN_FLAG.load(Ordering::Relaxed);
+                        let (response, source) = process_query(&server_context, &request);
+                        match stopping {
+                            true => {
+                                assert!(response.header.rescode == ResultCode::SERVFAIL && source.is_none());
+                                after_shutdown += 1;
+                            }
+                            // The flag may be set while this query runs, either answer is fine then
+                            false => assert!(response.header.rescode == ResultCode::NOERROR || response.header.rescode == ResultCode::SERVFAIL),
+                        }
+                    }
+                })
+            })
+            .collect();
+        thread::sleep(Duration::from_millis(20));
+        DNS_SHUTDOWN_FLAG.store(true, Ordering::Relaxed);
+        let results: Vec<_> = workers.into_iter().map(|worker| worker.join()).collect();
+
+        DNS_SHUTDOWN_FLAG.store(false, Ordering::Relaxed);
+        LOCAL_OVERRIDES.write().unwrap().remove(name);
+        assert!(results.iter().all(|result| result.is_ok()), "a query thread panicked");
+    }
+
+    #[test]
+    #[ignore = "starts a JVM, needs libjvm, run with --ignored"]
+    fn attached_env_works_from_spawned_thread() {
+        use jni::{InitArgsBuilder, JNIVersion};
+
+        if JAVA_VM.get().is_none() {
+            let args = InitArgsBuilder::new().version(JNIVersion::V8).build().expect("JVM arguments");
+            let _ = JAVA_VM.set(JavaVM::new(args).expect("JVM starts"));
         }
+        let found = thread::spawn(|| with_attached_env(|env| env.find_class("java/lang/String").is_ok()))
+            .join()
+            .expect("thread finishes");
+        assert_eq!(found, Some(true));
+    }
+
+    #[test]
+    fn uptime_is_zero_before_start() {
+        assert_eq!(uptime_secs(100, 200), 0);
+        assert_eq!(uptime_secs(200, 100), 100);
+    }
+
+    #[test]
+    fn exported_keystore_restores_same_key() {
+        let dir = std::env::temp_dir().join(format!("alfis-keystore-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).expect("temp dir");
+        let original_path = dir.join("key.toml").to_string_lossy().to_string();
+        let backup_path = dir.join("backup").join("key.toml").to_string_lossy().to_string();
+
+        let keystore = load_or_create_keystore(&original_path, "pw").expect("keystore created");
+        export_keystore(&keystore, &backup_path, "backup").expect("keystore exported");
+        let restored = load_or_create_keystore(&backup_path, "backup").expect("backup loads");
+        assert!(restored.get_public() == keystore.get_public());
+        assert!(load_or_create_keystore(&backup_path, "pw").is_err());
+
+        let _ = std::fs::remove_dir_all(&dir);
     }
-}
\ No newline at end of file
+}
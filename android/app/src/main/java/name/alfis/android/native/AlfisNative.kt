@@ -15,15 +15,59 @@ object AlfisNative {
      */
     private external fun initLogging()
 
+    /**
+     * Change native log verbosity at runtime, e.g. "debug" while reproducing a bug.
+     * Debug and trace messages go to logcat and to the console output.
+     * @param level "error", "warn", "info" (default), "debug" or "trace"
+     * @return false for unknown levels
+     */
+    external fun setLogLevel(level: String): Boolean
+
     /**
      * Start the DNS server
      * @param configPath Path to the configuration file
      * @param workDir Working directory for blockchain data
      * @param logFile Path to log file
-     * @return true if server started successfully
+     * @return true if server started successfully, see [getLastStartError] otherwise
      */
     external fun startDnsServer(configPath: String, workDir: String, logFile: String): Boolean
 
+    /**
+     * Get why the last [startDnsServer] failed, prefixed with the stage that failed:
+     * "Config error", "Database error", "DNS listener error" or "Network error"
+     * @return The error message, empty if the last start succeeded
+     */
+    external fun getLastStartError(): String?
+
+    /**
+     * Get the port the DNS server listens on. When the configured port is taken by another
+     * app, ports 5354 to 5356 are tried next; if all are taken the start fails and
+     * [getLastStartError] reports "port in use".
+     * @return The bound port, 0 if the server is not running
+     */
+    external fun getActiveDnsPort(): Int
+
+    /**
+     * Get the address the DNS server is actually bound to, e.g. "[::1]:5353". Use it for the
+     * VpnService or resolver config, the port may differ from the config, see [getActiveDnsPort].
+     * @return The bound address, empty if the server is not running
+     */
+    external fun getActiveDnsAddress(): String?
+
+    /**
+     * Check if the running node keeps the chain in memory because the database file
+     * couldn't be used. Synced blocks are lost on restart then.
+     * @return true if the DNS server is running with an in-memory chain
+     */
+    external fun isUsingInMemoryDb(): Boolean
+
+    /**
+     * Allow or forbid falling back to an in-memory chain when the database file fails.
+     * When forbidden, [startDnsServer] fails with a "Database error" instead. Allowed by default,
+     * applies to the next start.
+     */
+    external fun setAllowInMemoryFallback(allow: Boolean)
+
     /**
      * Stop the DNS server
      * @return true if server stopped successfully
@@ -39,7 +83,18 @@ object AlfisNative {
 
     /**
      * Get DNS server statistics as JSON string
-     * @return JSON string with statistics
+     * @return JSON string with statistics; "uptime" is in seconds, "responses" counts responses actually sent,
+     * split by responses_noerror/nxdomain/servfail and answered_local/answered_forwarded;
+     * cache_hits and cache_misses count resolver cache lookups; tcp_rejected counts TCP
+     * connections closed because all TCP workers were busy; rate_limited counts queries and
+     * connections dropped by the rate limiter; doh_queries counts queries over DNS-over-HTTPS;
+     * blocked_queries counts queries answered with NXDOMAIN from the blocklist;
+     * forward_timeouts counts upstream queries that got no answer within the forward timeout;
+     * local_overrides counts queries answered by [setLocalOverride]; tcp_connections_active is the
+     * number of open TCP connections and tcp_connections_rejected counts the ones closed by
+     * [setMaxTcpConnections].
+     * "session" has the counters of this run, "total" the lifetime totals: they are saved to
+     * dns_stats.json in the work directory by [stopDnsServer] and loaded again on start.
      */
     external fun getDnsStats(): String
 
@@ -57,7 +112,687 @@ object AlfisNative {
     external fun getConsoleOutput(): String
 
     /**
-     * Trigger network reconnection after connectivity change
+     * Trigger network reconnection after connectivity change: the P2P network is restarted in
+     * the background, dropping stale connections and dialing the bootstrap peers again.
+     * Peer counts before and after are written to the console. Does nothing while paused.
      */
     external fun triggerNetworkReconnect()
-}
\ No newline at end of file
+
+    /**
+     * Reconnect to the bootstrap peers whenever no peer has been connected for this long,
+     * bounding recovery time after the app returns from the background
+     * @param secs Seconds without peers, 0 turns it off
+     * @return false for negative values
+     */
+    external fun setRebootstrapInterval(secs: Int): Boolean
+
+    /**
+     * Take pending native events, e.g. {"event":"reorg","from_height":..,"to_height":..,"common_ancestor":..}.
+     * common_ancestor is the last block both branches share, null if the fork is below the last 64 blocks.
+     * @return JSON array of events since the last call
+     */
+    external fun pollEvents(): String
+
+    /**
+     * Estimate the data the remaining blockchain sync will use
+     * @return JSON with remaining_blocks, est_bytes, downloaded_bytes and avg_block_bytes
+     */
+    external fun getSyncDataEstimate(): String
+
+    /**
+     * Remove DNSSEC records (RRSIG, NSEC, DNSKEY...) from responses,
+     * for clients that don't validate and are confused by them.
+     * Queries with the DNSSEC OK (DO) bit always get the records.
+     * @param enabled true to strip DNSSEC records
+     */
+    external fun setStripDnssecRecords(enabled: Boolean)
+
+    /**
+     * Check if an address belongs to the Yggdrasil network (200::/7)
+     * @param address IP address, CIDR range or [address]:port
+     * @return true if the address (or the whole range) is in the Yggdrasil space
+     */
+    external fun isYggdrasilAddress(address: String): Boolean
+
+    /**
+     * Also serve DNS on a Unix domain socket, using TCP-style length-prefixed messages.
+     * The DNS server must be running, the socket is closed when it stops.
+     * @param socketPath Filesystem path for the socket
+     * @return true if the socket is listening
+     */
+    external fun startDnsServerUnix(socketPath: String): Boolean
+
+    /**
+     * Limit the memory used by the console log buffer
+     * @param bytes Maximum total size of buffered messages, values below 4 KB are raised to 4 KB
+     * @return false for non-positive values
+     */
+    external fun setLogBufferMaxBytes(bytes: Int): Boolean
+
+    /**
+     * Set how many messages the console log buffer keeps, 100 by default.
+     * The byte limit of setLogBufferMaxBytes still applies.
+     * @param size Number of messages, clamped to 50..5000
+     * @return false for non-positive values
+     */
+    external fun setLogBufferSize(size: Int): Boolean
+
+    /**
+     * Allow or forbid exposing query names (query logging, cache dumps).
+     * While enabled every query is logged as `QUERY <name> <type> -> <rcode> (<n> ans, <local|forwarded|none>)`,
+     * which quickly fills the console buffer.
+     * @param enabled true to allow, off by default for privacy
+     */
+    external fun setQueryLogging(enabled: Boolean)
+
+    /**
+     * Dump resolver cache entries, only works while query logging is enabled
+     * @param limit Maximum number of entries to return
+     * @return JSON array of {name, qtype, ttl_remaining, records, source} or an error object
+     */
+    external fun dumpCache(limit: Int): String
+
+    /**
+     * Set the largest DNS message accepted over TCP, bigger ones are dropped unread
+     * @param bytes Limit between 512 and 65535 (4096 by default)
+     * @return false if the value is out of range
+     */
+    external fun setMaxTcpMessageSize(bytes: Int): Boolean
+
+    /**
+     * Get how regularly blocks arrive in the whole Alfis network
+     * @return JSON with avg_block_interval_secs, blocks_last_hour, tip_age_secs and health ("good", "slow" or "stalled")
+     */
+    external fun getNetworkHealth(): String
+
+    /**
+     * Set the query types answered with REFUSED, AXFR and IXFR by default
+     * @param typesJson JSON array of type names, e.g. ["ANY", "AXFR", "IXFR"]
+     * @return false if the list is malformed or has unknown types
+     */
+    external fun setRefusedQueryTypes(typesJson: String): Boolean
+
+    /**
+     * Get the last panics of native threads, including ones that were caught
+     * @return JSON array of {epoch, thread, message, location}
+     */
+    external fun getRecentPanics(): String
+
+    /**
+     * Forget the recorded panics, e.g. after the user sent a report
+     */
+    external fun clearRecentPanics()
+
+    /**
+     * Set how many worker threads resolve UDP queries. The pool grows with the
+     * queue up to max and lets extra threads go after 30 seconds idle.
+     * @param min Threads kept alive when idle, at least 1
+     * @param max Upper limit under load, at most 64. Without this call it is `dns.threads` from the config
+     * @return false if the range is invalid
+     */
+    external fun setAdaptiveThreads(min: Int, max: Int): Boolean
+
+    /**
+     * Check if a peer accepts connections on its P2P port, without adding it. Only connects:
+     * the handshake is done inside the core, so the peer's version and height aren't known.
+     * @param address Peer address as host:port
+     * @return JSON with reachable, latency_ms and error
+     */
+    external fun probePeer(address: String): String
+
+    /**
+     * Get the history of a domain across blocks, oldest first
+     * @param name Domain name, e.g. "example.alfis"
+     * @return JSON array of {block_height, timestamp, action, owner, records}, empty for unknown names
+     */
+    external fun getDomainHistory(name: String): String
+
+    /**
+     * Create a new key and queue transfers of all owned domains to it.
+     * Transfers take effect only after they are mined and propagated, the old key
+     * stays active until then.
+     * @param newPassword Password for the new keystore file
+     * @return JSON with new_pubkey, keystore_path, transfers_queued and errors
+     */
+    external fun rotateKeystore(newPassword: String): String
+
+    /**
+     * Pad queries sent to DoH forwarders to 128 byte blocks (EDNS padding),
+     * so their size doesn't reveal the queried names
+     * @param enabled true to pad queries
+     */
+    external fun setEdnsPadding(enabled: Boolean)
+
+    /**
+     * Get statistics of every forwarder, in configured order.
+     * Reset when the forwarder list changes.
+     * System DNS fallback servers come last with fallback = true.
+     * @return JSON array of {upstream, fallback, queries, successes, timeouts, avg_latency_ms}
+     */
+    external fun getForwarderStats(): String
+
+    /**
+     * Queue a heavy database task to run when the device is idle and charging
+     * @param task "vacuum" or "integrity_check"
+     * @param afterSecs Don't run before this many seconds from now
+     * @return true if the task is known and queued
+     */
+    external fun scheduleMaintenance(task: String, afterSecs: Int): Boolean
+
+    /**
+     * Report device power state, call it from screen/charging receivers.
+     * Maintenance tasks run only while idle and charging.
+     */
+    external fun setDeviceState(idle: Boolean, charging: Boolean)
+
+    /**
+     * Get node health overview
+     * @return JSON with dns_running, network_running, height, memory_constrained and maintenance
+     * ({pending, running, device_idle, charging, last_run})
+     */
+    external fun healthCheck(): String
+
+    /**
+     * Drop damaged blocks at the top of the chain instead of resetting everything.
+     * The node must be stopped, sync continues from the last valid block on next start.
+     * @return JSON with repaired_to_height, blocks_discarded, error and recommend_reset
+     */
+    external fun repairDatabase(): String
+
+    /**
+     * Run SQLite's integrity check on a chain database file without changing it.
+     * On start a damaged database is repaired, or moved aside as alfis.db.corrupt if that fails.
+     * @param path Path to the database, usually alfis.db in the work directory
+     * @return JSON with ok, problems (at most 100 messages) and error
+     */
+    external fun verifyDatabase(path: String): String?
+
+    /**
+     * Send only the answer section for positive responses, dropping authority
+     * and additional records. Negative responses keep their SOA.
+     * @param enabled true to enable minimal responses
+     */
+    external fun setMinimalResponses(enabled: Boolean)
+
+    /**
+     * Set the device DNS servers (from LinkProperties) as a last resort tier,
+     * used only when all configured forwarders fail
+     * @param serversJson JSON array of IP addresses, empty array to remove
+     * @return true if the list was accepted
+     */
+    external fun setSystemDnsFallback(serversJson: String): Boolean
+
+    /**
+     * Resolve AAAA in the background when A is asked for and vice versa, so the
+     * second query of a dual-stack client is a cache hit. Hits are counted
+     * as prefetch_hits in getDnsStats.
+     * @param enabled true to enable prefetch
+     */
+    external fun setDualStackPrefetch(enabled: Boolean)
+
+    /**
+     * Prefer an address family for names that have both: "ipv4", "ipv6" or "any" (default).
+     * A query for the other family gets an empty answer when the preferred one exists, and
+     * ANY answers list the preferred family first. Records are only filtered or reordered,
+     * never synthesized. Returns false for an unknown value.
+     */
+    external fun setAddressFamilyPreference(pref: String): Boolean
+
+    /**
+     * Get what differs from the shipped defaults, handy for bug reports
+     * @return JSON with "config" (changed config file fields by dotted path) and
+     * "runtime" (options changed through native calls), each entry is {default, current}
+     */
+    external fun getConfigDiff(): String
+
+    /**
+     * Sign a message with the active key, proving control of the identity
+     * @param message Text to sign, its UTF-8 bytes are signed
+     * @return JSON with hex signature and pubkey, or error if the node isn't running or no
+     * keystore is loaded
+     */
+    external fun signMessage(message: String): String
+
+    /**
+     * Check a signature made by signMessage
+     * @param pubkey Public key as hex
+     * @param message Signed text
+     * @param signature Signature as hex
+     * @return true if the signature is valid
+     */
+    external fun verifySignature(pubkey: String, message: String, signature: String): Boolean
+
+    /**
+     * Get what the resolver cache holds, by category
+     * @return JSON with entries, alfis, forwarded, negative, stale and approx_bytes
+     */
+    external fun getCacheBreakdown(): String
+
+    /**
+     * Test lookup of a name through the running resolver, no device DNS setup needed
+     * @param name Domain name, e.g. "example.alfis"
+     * @param qtype Record type, e.g. "A", "AAAA", "CNAME", "MX", "TXT"
+     * @return JSON array of answer records, or JSON object with error
+     */
+    external fun resolveDomain(name: String, qtype: String): String
+
+    /**
+     * Load the keystore from a file, or create and save a new one, so the identity
+     * survives restarts. Used from the next DNS server start on.
+     * @param path Keystore file path
+     * @param password Keystore password
+     * @return false for a wrong password or unreadable file
+     */
+    external fun loadOrCreateKeystore(path: String, password: String): Boolean
+
+    /**
+     * Call from onLowMemory/onTrimMemory. Shrinks the DNS cache and uses smaller
+     * UDP buffers and fewer workers; limits are restored 5 minutes after the last call.
+     */
+    external fun onLowMemory()
+
+    /**
+     * Register a new domain with the loaded key. The block is queued for mining
+     * and propagation, the name is not taken until it is mined.
+     * @param name Domain name, e.g. "example.ygg"
+     * @param recordsJson JSON array of DNS records in the Alfis format
+     * @return JSON with name, tx_hash, identity and queued, or error
+     */
+    external fun registerDomain(name: String, recordsJson: String): String
+
+    /**
+     * Verify the whole chain: hashes, signatures and continuity. Blocks until done,
+     * call it from a background thread. Progress is reported as "verify_progress" events.
+     * @return JSON with ok, blocks_verified, first_bad_block and error
+     */
+    external fun verifyChainFull(): String
+
+    /**
+     * Cancel a running verifyChainFull, it then returns with error "Cancelled"
+     */
+    external fun cancelChainVerification()
+
+    /**
+     * Get the domains owned by the loaded key
+     * @return JSON array of {name, records, expiry, blockHeight}; expiry is a Unix time,
+     * Alfis domains expire one year after their last block
+     */
+    external fun getOwnedDomains(): String
+
+    /**
+     * Override forwarded answers locally, e.g. to send a CDN name to a mirror.
+     * Applied after forwarding, Alfis domains are never rewritten.
+     * Rewritten answers are counted as rewritten_answers in getDnsStats.
+     * @param rulesJson JSON array of {name, records, ttl}, where name is "cdn.example.com"
+     * or "*.example.com" for the name and its subdomains, records is a list of
+     * {type: "A"|"AAAA"|"CNAME", value} (a CNAME must be alone) and ttl defaults to 300
+     * @return false if any rule is invalid, the old rules stay then
+     */
+    external fun setAnswerRewrites(rulesJson: String): Boolean
+
+    /**
+     * Get approximate memory use of the native layer, to tune cache and log limits
+     * @return JSON with rss_bytes and peak_rss_bytes (null if unavailable), cache_entries,
+     * cache_capacity, cache_approx_bytes, log_buffer_lines, log_buffer_bytes,
+     * log_buffer_max_bytes, udp_pool, pending_blocks and memory_constrained
+     */
+    external fun getNativeMemoryStats(): String
+
+    /**
+     * Replace the config file and apply it, restarting only what the changes need:
+     * [dns] changes restart the DNS listeners without disturbing the P2P network.
+     * @param configToml Complete new config file contents
+     * @return JSON with changed (dotted paths, e.g. "dns.forwarders"), restarted (e.g. ["dns"]),
+     * restart_required (["network"] when the service has to be restarted for the rest) and error
+     */
+    external fun applyConfig(configToml: String): String
+
+    /**
+     * Renew owned domains automatically when they get close to expiry. Checked hourly while
+     * the node runs, renewals are mined and propagated like registrations, so the node must
+     * keep running and stay connected. Each renewal attempt is reported as an "auto_renew"
+     * event with domain, expiry, queued and error.
+     * @param enabled true to turn renewal on
+     * @param thresholdBlocks Renew domains expiring within this many blocks; Alfis expiry is
+     * time-based, blocks are converted with the network's average block interval
+     * @return false if enabled with a non-positive threshold
+     */
+    external fun setAutoRenew(enabled: Boolean, thresholdBlocks: Int): Boolean
+
+    /**
+     * Check the Yggdrasil isolation set with yggdrasil_only. The p2p layer only reports the
+     * number of connected peers, so the configured peers are checked. Resolves them, so call
+     * it off the main thread.
+     * @return JSON {yggdrasilOnly, configuredPeersYggdrasilOnly, connectedPeers,
+     * bootstrapPeers: [{address, resolved, yggdrasil}]}. configuredPeersYggdrasilOnly is false
+     * if a configured peer is outside 200::/7, which is also logged. It doesn't cover the peers
+     * connected right now.
+     */
+    external fun getPeerNetworkInfo(): String?
+
+    /**
+     * Add a bootstrap peer, e.g. your own Alfis node. Saved to the config and
+     * used by the running node right away.
+     * @param address Peer as host:port or [ipv6]:port
+     * @return false if the address is malformed or the config can't be saved
+     */
+    external fun addPeer(address: String): Boolean
+
+    /**
+     * Remove a bootstrap peer from the config and the running node
+     * @param address Peer exactly as it was added
+     * @return false if the peer wasn't in the list
+     */
+    external fun removePeer(address: String): Boolean
+
+    /**
+     * Resolve a name like resolveDomain, returning the complete response for a dig-like view
+     * @param name Domain name, e.g. "example.alfis"
+     * @param qtype Record type, e.g. "A", "AAAA", "CNAME", "MX", "TXT"
+     * @return JSON with header ({id, rcode, flags: {qr, aa, tc, rd, ra, ad, cd}}), question,
+     * answers, authority and additional, or JSON object with error
+     */
+    external fun resolveDomainFull(name: String, qtype: String): String
+
+    /**
+     * Drop all cached DNS responses, e.g. after switching networks.
+     * Safe to call whether or not the server is running.
+     * @return true when the cache was cleared
+     */
+    external fun flushDnsCache(): Boolean
+
+    /**
+     * Get resolver cache statistics, to see whether caching saves upstream traffic
+     * @return JSON with entries, hits, misses, hitRate (percent, one decimal) and bytesApprox
+     */
+    external fun getCacheStats(): String
+
+    /**
+     * Log every resolution stage (cache, blockchain filter, each forwarder, raw upstream
+     * response) for names matching a pattern, lines start with "TRACE"
+     * @param namePattern Exact name or "*.example.com" for a name and its subdomains,
+     * empty to turn tracing off
+     */
+    external fun setQueryTrace(namePattern: String)
+
+    /**
+     * Write a diagnostic bundle for bug reports to [outPath] as pretty JSON: health, DNS and
+     * forwarder stats, network health, peers as in [getPeerNetworkInfo], memory, config diff,
+     * recent errors, panics and logs. Keys are never included, and when query logging is off
+     * the lines naming queried domains are dropped from the logs. Resolves the configured
+     * peers, so call it off the main thread.
+     * @return true if the file was written
+     */
+    external fun exportDiagnosticBundle(outPath: String): Boolean
+
+    /**
+     * Switch resolving of non-.alfis names at runtime: "recursive" or "forward".
+     * [forwardersJson] is a JSON array of upstreams ("ip", "ip:port" or "https://" DoH URLs)
+     * used with "forward"; pass an empty string to keep the current forwarders.
+     * The DNS listeners are restarted, the P2P network keeps running and the config file is not changed.
+     * @return false if the node is not running, the mode is unknown or "forward" has no valid upstreams
+     */
+    external fun setResolveStrategy(mode: String, forwardersJson: String): Boolean
+
+    /**
+     * Listen on both 127.0.0.1 and [::1] with the configured port, for apps that only use one of them.
+     * Needs a loopback listen address and takes effect on the next start of the DNS server.
+     * Saved to the config as `dns.dual_stack` when the config file is already known.
+     */
+    external fun setDualStack(enabled: Boolean)
+
+    /**
+     * Get everything the dashboard shows in one call, as JSON:
+     * {uptime_secs, network_paused, mining: {active, threads, hash_attempts, mined_blocks},
+     * database: {in_memory, wal}, sync: {state, height, network_height},
+     * peers, queries: {udp, tcp, total}, cache: {entries, hits, misses, hitRate, bytesApprox},
+     * last_block_received}. state is "syncing", "synced" or "idle"; last_block_received is Unix time
+     * in seconds or null; wal tells if the chain database runs in SQLite WAL mode.
+     */
+    external fun getDnsStatsDetailed(): String?
+
+    /**
+     * Listener for status events pushed by the native side, an alternative to polling getDnsStats
+     */
+    fun interface StatusListener {
+        /**
+         * Called from a native thread with a JSON object, "event" is one of
+         * "syncing" {have, height}, "sync_finished" or "network_status" {blocks, peers}
+         */
+        fun onStatus(eventJson: String)
+    }
+
+    /**
+     * Register [listener] for sync and network status events, replacing the previous one.
+     * The listener is called on native threads, switch to the main thread before touching UI.
+     * @return false if the listener couldn't be registered
+     */
+    external fun registerStatusCallback(listener: StatusListener): Boolean
+
+    /**
+     * Drop the status listener registered with [registerStatusCallback]
+     */
+    external fun unregisterStatusCallback()
+
+    /**
+     * Get the height of the local blockchain without parsing stats
+     * @return the height, or -1 if the node is not running
+     */
+    external fun getBlockchainHeight(): Long
+
+    /**
+     * Get sync progress from the last sync event as JSON: {have, height, percent}.
+     * percent has one decimal; once synced have equals height and percent is 100.
+     */
+    external fun getSyncProgress(): String?
+
+    /**
+     * Write the whole console buffer to [path], one timestamped message per line.
+     * Missing parent directories are created.
+     * @return false on IO errors
+     */
+    external fun exportLogs(path: String): Boolean
+
+    /**
+     * Clear the console output buffer, to start a fresh capture before reproducing a problem
+     */
+    external fun clearLogs()
+
+    /**
+     * Get the version of the native library for the About screen, as JSON:
+     * {core, crate, commit}. commit is null when it wasn't built from a git checkout.
+     */
+    external fun getNativeVersion(): String?
+
+    /**
+     * Generate the default configuration for another Alfis network, e.g. a private or test one
+     * @param configPath Path where to write the configuration
+     * @param origin Hash of the first block of the network, 64 hex characters
+     * @param checkBlocks Number of blocks to check on start, not negative
+     * @param peersJson JSON array of "host:port" bootstrap peers, empty string for the default peers
+     * @return false if a parameter is invalid or the file couldn't be written
+     */
+    external fun generateConfigWithParams(configPath: String, origin: String, checkBlocks: Int, peersJson: String): Boolean
+
+    /**
+     * Restart only the DNS listeners with the DNS settings from the config file, e.g. after
+     * changing the listen address or forwarders. The P2P network keeps running and syncing.
+     * @return false if the node is not running or the listeners couldn't be started, in which
+     * case the node is stopped and getLastStartError tells why
+     */
+    external fun restartDnsListeners(): Boolean
+
+    /**
+     * Limit how many queries per second one source address may send, with bursts up to the
+     * same number. UDP queries over the limit are dropped and TCP connections closed.
+     * @param qps Queries per second, 0 turns the limiter off
+     * @return false for negative values
+     */
+    external fun setRateLimit(qps: Int): Boolean
+
+    /**
+     * Cap the open DNS-over-TCP connections to protect the device from connection floods.
+     * Connections over the cap are closed at once.
+     * @param n Maximum open connections, 0 for unlimited (the default)
+     * @return false for negative values
+     */
+    external fun setMaxTcpConnections(n: Int): Boolean
+
+    /**
+     * Cache forwarded NXDOMAIN/NODATA answers for up to [secs] seconds (default 60, max 10800,
+     * 0 disables), so names apps probe over and over aren't forwarded each time. A lower SOA
+     * minimum in the answer wins. Hits are counted in getDnsStats as negative_cache_hits.
+     * Returns false for an out of range value.
+     */
+    external fun setNegativeCacheTtl(secs: Int): Boolean
+
+    /**
+     * Set how long each upstream gets to answer a forwarded query. Clients get SERVFAIL
+     * when no upstream answers in time.
+     * @param ms Timeout in milliseconds, 100 to 30000, 5000 by default
+     * @return false if [ms] is out of range
+     */
+    external fun setForwardTimeout(ms: Int): Boolean
+
+    /**
+     * Check whether the configured forwarders work on the current network: each one is asked
+     * for the A record of example.com, all at once and each within the forward timeout.
+     * Works while the server is stopped too, with the forwarders from the config file.
+     * @return JSON array of {upstream, ok, latencyMs, error}
+     */
+    external fun testForwarders(): String?
+
+    /**
+     * One-tap check of the running server: resolves alfis.ygg from the blockchain and
+     * example.com through the upstreams. details tells "blockchain not synced" apart from
+     * "no upstream connectivity".
+     * @return JSON {blockchainResolve, forwardResolve, details}; both false with details
+     * "DNS server is not running" when it is stopped
+     */
+    external fun selfTest(): String?
+
+    /**
+     * Look up any registered .alfis domain, no keystore needed.
+     * @param name Domain name, e.g. "example.alfis"
+     * @return JSON {name, owner_pubkey, records, blockHeight, timestamp, expiry}, "null" if the
+     * domain isn't registered, or {error} if the node is not running. blockHeight, timestamp
+     * and expiry are null when the chain is kept in memory.
+     */
+    external fun getDomainInfo(name: String): String?
+
+    /**
+     * Read the records of one type a domain publishes, straight from the chain.
+     * @param name Domain name, e.g. "example.alfis"
+     * @param qtype Record type like "TXT", "AAAA" or "TYPE65"; "ANY" returns all records
+     * @return JSON array of records as in [getDomainInfo], empty if there are none of that type,
+     * "null" if the domain isn't registered, or {error} for an unknown type or a stopped node
+     */
+    external fun lookupRecords(name: String, qtype: String): String?
+
+    /**
+     * Get domain blocks submitted from this device and still waiting to be mined, as JSON:
+     * {pendingCount, transactions: [{domain, action, pubkey, submitted}]}.
+     * action is "register", "renew" or "transfer"; submitted is Unix time in seconds.
+     */
+    external fun getMempoolInfo(): String?
+
+    /**
+     * Mine the domain blocks waiting in [getMempoolInfo] on this device, so registrations
+     * don't depend on other miners. Off by default; threads are capped at half the CPU cores
+     * and 4 to keep the phone cool, and the miner runs at low priority.
+     * @param threads Number of miner threads, at least 1
+     * @return false if [threads] is not positive or the node is not running
+     */
+    external fun startMining(threads: Int): Boolean
+
+    /**
+     * Get the progress of the block being mined, for a progress indicator while registering:
+     * {active, target_difficulty, best_difficulty, attempts, hash_rate, elapsed_secs, domains}.
+     * attempts is estimated from the reported hash rate (hashes per second); domains lists the
+     * blocks the miner has, as it doesn't tell which one it works on.
+     * @return JSON, {active: false, status: "no active job"} when nothing is being mined
+     */
+    external fun getMiningProgress(): String?
+
+    /**
+     * Stop mining, blocks that weren't mined yet stay pending
+     * @return false if mining was not on
+     */
+    external fun stopMining(): Boolean
+
+    /**
+     * Get how long ago the last block was received or added to the chain, to warn when
+     * the chain stops progressing even though peers are connected
+     * @return seconds since the last block, or -1 if no block arrived since start
+     */
+    external fun getLastBlockAge(): Long
+
+    /**
+     * Stop P2P connections and sync, e.g. on metered data or low battery. The DNS server
+     * keeps running and answers .alfis names from the local chain.
+     * @return false if the node is not running
+     */
+    external fun pauseNetwork(): Boolean
+
+    /**
+     * Connect to the P2P network and sync again after [pauseNetwork]
+     * @return false if the node is not running or the network couldn't be started
+     */
+    external fun resumeNetwork(): Boolean
+
+    /**
+     * Replace the forwarders at runtime, e.g. with the DNS servers of the active network after
+     * a connectivity change; call [triggerNetworkReconnect] as well so P2P follows the switch.
+     * Switches from recursion to forwarding if needed. Not saved to the config.
+     * @param forwardersJson JSON array of "ip:port" or "https://" DoH URLs
+     * @return false if the node is not running, the list is empty or any entry is malformed
+     */
+    external fun setForwarders(forwardersJson: String): Boolean
+
+    /**
+     * Enable or disable the DNS-over-HTTPS server at https://127.0.0.1:8443/dns-query for apps
+     * that refuse plain DNS. It uses a self-signed certificate for localhost, saved as
+     * doh-cert.pem in the work directory on first use; apps have to trust it.
+     * Takes effect on the next start when the DNS server is not running.
+     * @return false if the DoH server couldn't be started
+     */
+    external fun setDohEnabled(enabled: Boolean): Boolean
+
+    /**
+     * Load a blocklist of ad and tracker domains in hosts file format ("0.0.0.0 name" or bare
+     * names per line). Listed names are answered with NXDOMAIN and never sent upstream.
+     * Replaces the previous list; an empty file clears it.
+     * @return the number of names loaded, or -1 if the file couldn't be read
+     */
+    external fun loadBlocklist(path: String): Long
+
+    /**
+     * Pin a name to an address on this device, e.g. "myapp.alfis" to a LAN dev box.
+     * Answered before the blocklist, the chain and upstreams: as A for an IPv4 address,
+     * as AAAA for an IPv6 one, other types get an empty answer. Not kept across app restarts.
+     * @param name Domain name with at least two labels
+     * @param ip IPv4 or IPv6 address
+     * @return false for an invalid name or address
+     */
+    external fun setLocalOverride(name: String, ip: String): Boolean
+
+    /**
+     * Remove the override of [name] set with [setLocalOverride]
+     * @return false if the name had no override
+     */
+    external fun clearLocalOverride(name: String): Boolean
+
+    /**
+     * Get the identity of the keystore loaded with [loadOrCreateKeystore], as JSON:
+     * {publicKeyHex, hashHex, encryptionKeyHex, path, hasPrivateKey}, or "null" if none is loaded
+     */
+    external fun getKeystoreInfo(): String?
+
+    /**
+     * Back up the keystore loaded with [loadOrCreateKeystore] to [path], encrypted with
+     * [password] in the desktop Alfis format. Restore it on another device with
+     * loadOrCreateKeystore and the same password. The file is read back before it is kept.
+     * @return false if no keystore is loaded or the file couldn't be written
+     */
+    external fun exportKeystore(path: String, password: String): Boolean
+}